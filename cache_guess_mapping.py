@@ -1,6 +1,6 @@
 #!/usr/bin/env python3
 
-import hashlib, heapq, mmap, os, struct, sys
+import ddrescue_mapfile, hashlib, heapq, mmap, os, struct, sys
 from argparse import ArgumentParser
 from contextlib import contextmanager
 
@@ -11,6 +11,7 @@ STRUCT_FORMAT = f"={HASH_BYTES}sQ"
 ENTRY_SIZE = struct.calcsize(STRUCT_FORMAT)
 ENTRIES_PER_BLOCK = BLOCK_SIZE // ENTRY_SIZE
 MMAP_BLOCK_SIZE = 1024 * 1024 * 128
+UNRECOVERED_OFFSET = 2**64 - 1
 
 
 def main():
@@ -23,6 +24,8 @@ def main():
     subparser = subparsers.add_parser("collect")
     subparser.add_argument("index")
     subparser.add_argument("device")
+    subparser.add_argument("--mapfile",
+            help="ddrescue mapfile of an imaged device; unrecovered blocks are not indexed")
     subparser.set_defaults(func=collect)
 
     subparser = subparsers.add_parser("sort")
@@ -32,7 +35,7 @@ def main():
     subparser = subparsers.add_parser("lookup")
     subparser.add_argument("index")
     subparser.add_argument("cache_device")
-    subparser.add_argument("block", required=False)
+    subparser.add_argument("block", nargs="?")
     subparser.set_defaults(func=lookup)
 
     args = argparser.parse_args()
@@ -40,6 +43,8 @@ def main():
 
 
 def collect(args):
+    mapfile = None if args.mapfile is None else ddrescue_mapfile.Mapfile.read(args.mapfile)
+    unrecovered_blocks = 0
     with mmap_open(args.device) as device:
         device_size = device.size()
         block_count = (device_size + BLOCK_SIZE - 1) // BLOCK_SIZE
@@ -52,19 +57,27 @@ def collect(args):
                 if offset % (BLOCK_SIZE * 10240) == 0:
                     log_status(offset, device_size, "bytes")
 
-                block = device[offset:offset + BLOCK_SIZE]
-                #assert len(block) == BLOCK_SIZE, f"len(block): {len(block)}; BLOCK_SIZE: {BLOCK_SIZE}"
-                m = hashlib.sha1(usedforsecurity=False)
-                m.update(block)
-                digest = m.digest()
-                assert len(digest) <= HASH_BYTES, f"len(digest): {len(digest)}; HASH_BYTES: {HASH_BYTES}"
                 index_offset = index_block * BLOCK_SIZE + index_entry * ENTRY_SIZE
-                index_file[index_offset:index_offset + ENTRY_SIZE] = struct.pack(STRUCT_FORMAT, digest, offset)
+                if mapfile is not None and mapfile.unfinished(offset, offset + BLOCK_SIZE):
+                    # The content is unknown, so it must not match anything
+                    index_file[index_offset:index_offset + ENTRY_SIZE] = \
+                            struct.pack(STRUCT_FORMAT, bytes(HASH_BYTES), UNRECOVERED_OFFSET)
+                    unrecovered_blocks += 1
+                else:
+                    block = device[offset:offset + BLOCK_SIZE]
+                    #assert len(block) == BLOCK_SIZE, f"len(block): {len(block)}; BLOCK_SIZE: {BLOCK_SIZE}"
+                    m = hashlib.sha1(usedforsecurity=False)
+                    m.update(block)
+                    digest = m.digest()
+                    assert len(digest) <= HASH_BYTES, f"len(digest): {len(digest)}; HASH_BYTES: {HASH_BYTES}"
+                    index_file[index_offset:index_offset + ENTRY_SIZE] = struct.pack(STRUCT_FORMAT, digest, offset)
                 index_entry += 1
                 if index_entry >= ENTRIES_PER_BLOCK:
                     index_block += 1
                     index_entry = 0
             log_complete(device_size, "bytes")
+    if unrecovered_blocks:
+        print(f"\n{unrecovered_blocks} unrecovered blocks not indexed", file=sys.stderr)
 
 
 def sort(args):
@@ -74,7 +87,7 @@ def sort(args):
         def status_callback(sorted_blocks):
             if sorted_blocks % 10 == 0:
                 log_status(sorted_blocks, total_blocks, "blocks")
-        heap.sort(status_callback):
+        heap.sort(status_callback)
         log_complete(total_blocks, "blocks")
 
 
@@ -0,0 +1,2156 @@
+#!/usr/bin/env python3
+# -*- coding: utf-8 -*-
+"""Reads the metadata of dm-cache.
+
+The metadata can be read from two kinds of sources.
+The first one is an XML file as created by ``cache_dump``
+from thin-provisioning-tools. ::
+
+    $ cache_dump -o metadata.xml /dev/mapper/cachemeta
+
+XML files compressed with gzip, xz or zstd are decompressed on the fly,
+and ``-`` reads the XML file from stdin.
+
+The second one is the metadata device itself, or an image of it.
+In this case, the superblock, the mapping array, the dirty bitset,
+the discard bitset and the hint array of the policy
+are decoded directly [1]_, so ``cache_dump`` is not required.
+This is also useful if ``cache_dump`` refuses to dump damaged metadata.
+
+When reading a metadata device, every block is checked against its checksum.
+Checksumming is much faster if the module ``google_crc32c`` or ``crc32c`` is installed.
+The command ``check`` lists all damaged blocks
+together with the cache blocks whose mappings depend on them. ::
+
+    $ ./cache_metadata.py check /dev/mapper/cachemeta
+
+If the superblock or the root of the mapping array is lost,
+the command ``salvage`` scans the whole metadata device
+for blocks which still look like parts of the mapping array.
+The recovered mappings are printed in the format of ``cache_dump``,
+each followed by a comment saying that it is salvaged,
+and whether it is only assumed to be dirty because its dirty bit is lost. ::
+
+    $ ./cache_metadata.py salvage --block-size 128 /dev/mapper/cachemeta > metadata.xml
+
+Policy hints are only displayed, e.g. by ``cache_verify.py --hints``.
+They do not help to resolve conflicts between salvaged copies of a mapping,
+see ``salvage_metadata``.
+
+The command ``restore`` writes mappings to a fresh metadata device,
+similar to ``cache_restore`` from thin-provisioning-tools.
+The mappings can be read from any source mentioned above.
+The output must already exist, as its size determines the size of the space map. ::
+
+    $ ./cache_metadata.py restore metadata.xml /dev/mapper/cachemeta
+
+The command ``diff`` compares two metadata sources,
+for example dumps from before and after an incident.
+It lists added, removed and remapped cache blocks, changed dirty flags,
+and changes of the superblock, either as text or as JSON. ::
+
+    $ ./cache_metadata.py diff --format json before.xml /dev/mapper/cachemeta
+
+The command ``validate`` checks the metadata strictly.
+Instead of stopping at the first problem, it reports all issues
+together with the line of the XML file or the block of the metadata device,
+either as text or as JSON. ::
+
+    $ ./cache_metadata.py validate --origin /dev/mapper/data --format json metadata.xml
+
+Because blocks are shadowed on every commit,
+the metadata device often still contains older generations
+of the mapping array and of the bitsets.
+The command ``history`` lists the roots of all generations which are still intact.
+A root can then be passed to ``cache_verify.py`` by ``--mapping-root``, ``--dirty-root``
+and ``--discard-root``. ::
+
+    $ ./cache_metadata.py history /dev/mapper/cachemeta
+
+The command ``stats`` reports the occupancy of the cache, the fraction of dirty blocks,
+the largest runs of dirty blocks and the number of segments of the table
+created by ``cache_verify.py --emulate``.
+A histogram shows the distribution of mapped and dirty blocks over the origin device,
+and ``--heatmap`` renders it as PNG or SVG image. ::
+
+    $ ./cache_metadata.py stats --origin /dev/mapper/data --heatmap heatmap.svg /dev/mapper/cachemeta
+
+.. [1] Implementation of the on-disk format within the Linux kernel:
+   https://github.com/torvalds/linux/blob/master/drivers/md/dm-cache-metadata.c
+"""
+
+import gzip, json, lzma, os, shutil, struct, sys, threading, zlib
+from array import array
+from argparse import ArgumentParser
+from contextlib import contextmanager, nullcontext
+from base64 import b64decode
+from subprocess import Popen, PIPE
+from dataclasses import asdict, dataclass, replace
+from pathlib import Path
+from xml.sax import parse, SAXParseException
+from xml.sax.handler import ContentHandler
+
+
+METADATA_BLOCK_SIZE = 4096
+SUPERBLOCK_LOCATION = 0
+SUPERBLOCK_MAGIC = 0o6142003
+SUPERBLOCK_FORMAT = "<IIQ16sQI16sI128sQQQQQIIIIIIIIII3IQ"
+SUPERBLOCK_CLEAN_SHUTDOWN = 1
+SUPERBLOCK_NEEDS_CHECK = 2
+
+SUPERBLOCK_CSUM_XOR = 9031977
+BTREE_CSUM_XOR = 121107
+ARRAY_CSUM_XOR = 595846735
+INDEX_CSUM_XOR = 160478
+BITMAP_CSUM_XOR = 240779
+
+BTREE_INTERNAL_NODE = 1
+BTREE_LEAF_NODE = 2
+BTREE_NODE_HEADER_FORMAT = "<IIQIIII"
+BTREE_NODE_HEADER_SIZE = struct.calcsize(BTREE_NODE_HEADER_FORMAT)
+
+ARRAY_BLOCK_HEADER_FORMAT = "<IIIIQ"
+ARRAY_BLOCK_HEADER_SIZE = struct.calcsize(ARRAY_BLOCK_HEADER_FORMAT)
+
+SPACE_MAP_ROOT_FORMAT = "<QQQQ"
+SPACE_MAP_INDEX_ENTRY_FORMAT = "<QII"
+SPACE_MAP_MAX_BITMAPS = 255
+SPACE_MAP_ENTRIES_PER_BITMAP = (METADATA_BLOCK_SIZE - 16) * 4
+
+MAPPING_VALID = 1
+MAPPING_DIRTY = 2
+
+XML_ELEMENTS = {"superblock", "mappings", "mapping", "hints", "hint", "discards", "discard"}
+
+
+def main():
+    argparser = ArgumentParser()
+    subparsers = argparser.add_subparsers(required=True)
+
+    subparser = subparsers.add_parser("check")
+    subparser.add_argument("metadata", type=Path)
+    subparser.set_defaults(func=check)
+
+    subparser = subparsers.add_parser("salvage",
+            help="Prints the mappings recovered from a damaged metadata device as XML")
+    subparser.add_argument("metadata", type=Path)
+    subparser.add_argument("--block-size", type=int,
+            help="Size of cache blocks in sectors, required if the superblock is damaged")
+    subparser.set_defaults(func=salvage)
+
+    subparser = subparsers.add_parser("history",
+            help="Lists older generations of the mapping array and the bitsets")
+    subparser.add_argument("metadata", type=Path)
+    subparser.set_defaults(func=history)
+
+    subparser = subparsers.add_parser("restore",
+            help="Writes the given mappings to a fresh metadata device")
+    subparser.add_argument("input", type=Path)
+    subparser.add_argument("output", type=Path)
+    subparser.add_argument("--block-size", type=int,
+            help="Size of cache blocks in sectors, taken from the input by default")
+    subparser.add_argument("--nr-cache-blocks", type=int,
+            help="Number of cache blocks, taken from the input by default")
+    subparser.add_argument("--policy", help="Name of the policy, taken from the input by default")
+    subparser.add_argument("--policy-version", default="2.0.0")
+    subparser.add_argument("--hint-size", type=int, help="Size of policy hints in bytes")
+    subparser.add_argument("--metadata-version", type=int, choices=[1, 2], default=2)
+    subparser.set_defaults(func=restore)
+
+    subparser = subparsers.add_parser("diff",
+            help="Compares the mappings and the superblock of two metadata sources")
+    subparser.add_argument("old", type=Path)
+    subparser.add_argument("new", type=Path)
+    subparser.add_argument("--format", choices=["text", "json"], default="text")
+    subparser.set_defaults(func=diff)
+
+    subparser = subparsers.add_parser("validate",
+            help="Validates the metadata strictly and reports all issues")
+    subparser.add_argument("metadata", type=Path)
+    subparser.add_argument("--origin", type=Path, help="Origin device used to check the range of origin blocks")
+    subparser.add_argument("--origin-size", type=int, help="Size of the origin device in sectors")
+    subparser.add_argument("--format", choices=["text", "json"], default="text")
+    subparser.set_defaults(func=validate)
+
+    subparser = subparsers.add_parser("stats",
+            help="Reports the occupancy of the cache and the layout of mapped and dirty blocks")
+    subparser.add_argument("metadata", type=Path)
+    subparser.add_argument("--origin", type=Path, help="Origin device, whose size is used for the distribution")
+    subparser.add_argument("--origin-size", type=int, help="Size of the origin device in sectors")
+    subparser.add_argument("--buckets", type=int, default=32,
+            help="Number of equal parts of the origin device in the histogram")
+    subparser.add_argument("--top", type=int, default=10, help="Number of the largest dirty runs to list")
+    subparser.add_argument("--heatmap", type=Path, metavar="FILE",
+            help="Render a heatmap of the origin device as PNG or SVG, chosen by the suffix")
+    subparser.add_argument("--heatmap-columns", type=int, default=256)
+    subparser.add_argument("--heatmap-rows", type=int, default=128)
+    subparser.add_argument("--heatmap-scale", type=int, default=4, metavar="PIXELS",
+            help="Size of the cells of the heatmap")
+    subparser.add_argument("--format", choices=["text", "json"], default="text")
+    subparser.set_defaults(func=stats)
+
+    args = argparser.parse_args()
+    args.func(args)
+
+
+def check(args):
+    corruptions = []
+    with dev_open(args.metadata) as fd:
+        device = MetadataDevice(fd, on_corruption=corruptions.append)
+        superblock = device.read_superblock()
+        report_superblock_flags(superblock)
+        device.check_space_map(superblock)
+        for _ in device.iter_array(superblock.mapping_root, 8, "mapping array"):
+            pass
+        if superblock.version >= 2:
+            device.read_bitset(superblock.dirty_root, superblock.cache_blocks, "dirty bitset")
+        if superblock.policy_hint_size > 0 and superblock.hint_root != 0:
+            for _ in device.iter_array(superblock.hint_root, superblock.policy_hint_size, "hint array", raw=True):
+                pass
+        if superblock.discard_root != 0:
+            for _ in device.iter_array(superblock.discard_root, 8, "discard bitset", "bitset block", None):
+                pass
+
+    for corruption in corruptions:
+        print(format_corruption(corruption))
+    damaged_blocks = {corruption.block for corruption in corruptions}
+    affected = merge_ranges(c.cache_blocks for c in corruptions if c.cache_blocks)
+    print(f"{len(damaged_blocks)} damaged metadata blocks", file=sys.stderr)
+    if affected:
+        print(f"Affected cache blocks: {', '.join(map(format_range, affected))}", file=sys.stderr)
+    if corruptions:
+        sys.exit(1)
+
+
+def salvage(args):
+    entries = []
+    salvage_metadata(args.metadata, entries.append, args.block_size)
+    with dev_open(args.metadata) as fd:
+        superblock = MetadataDevice(fd).read_intact_superblock()
+    if superblock is None:
+        block_size = args.block_size
+        nr_cache_blocks = max((entry.cache_block + 1 for entry in entries), default=0)
+        policy = "smq"
+        hint_width = 4
+    else:
+        block_size = superblock.data_block_size
+        nr_cache_blocks = superblock.cache_blocks
+        policy = superblock.policy_name
+        hint_width = superblock.policy_hint_size
+    print(f'<superblock uuid="" block_size="{block_size}" nr_cache_blocks="{nr_cache_blocks}"'
+            f' policy="{policy}" hint_width="{hint_width}">')
+    print("  <mappings>")
+    for entry in entries:
+        dirty = "true" if entry.dirty else "false"
+        # cache_restore rejects unknown attributes, so the origin of the mapping is kept in a comment
+        comment = "salvaged, assumed dirty" if entry.assumed_dirty else "salvaged"
+        print(f'    <mapping cache_block="{entry.cache_block}" origin_block="{entry.origin_block}" dirty="{dirty}"/>'
+                f' <!-- {comment} -->')
+    print("  </mappings>")
+    print("</superblock>")
+    assumed_dirty = sum(1 for entry in entries if entry.assumed_dirty)
+    print(f"Salvaged {len(entries)} mappings, {assumed_dirty} of them assumed dirty", file=sys.stderr)
+
+
+def history(args):
+    with dev_open(args.metadata) as fd:
+        superblock = MetadataDevice(fd).read_superblock()
+        generations = find_generations(fd, superblock)
+    for generation in generations:
+        structure = generation.structure
+        details = []
+        if structure == "mapping array":
+            details.append(f"{generation.mappings} mappings")
+            details.append(f"{generation.differences} differences to current")
+        elif structure.endswith("bitset"):
+            details.append(f"{64 * generation.entries} bits")
+        else:
+            details.append(f"{generation.entries} entries")
+        if generation.damaged_blocks:
+            details.append(f"{generation.damaged_blocks} damaged blocks")
+        if generation.partial:
+            details.append("partial")
+        if generation.current:
+            details.append("current")
+        elif generation.allocated:
+            details.append("allocated")
+        print(f"{structure} at block {generation.root}: {', '.join(details)}")
+
+
+def restore(args):
+    parameters = read_parameters(args.input)
+    entries = []
+    read_metadata(args.input, entries.append, hints=True)
+    if args.hint_size is not None and args.hint_size != parameters.hint_size:
+        entries = [replace(entry, hint=None) for entry in entries]
+    write_metadata(
+        args.output,
+        entries,
+        block_size=args.block_size or parameters.block_size,
+        nr_cache_blocks=args.nr_cache_blocks or parameters.nr_cache_blocks,
+        policy=args.policy or parameters.policy,
+        policy_version=tuple(int(part) for part in args.policy_version.split(".")),
+        hint_size=parameters.hint_size if args.hint_size is None else args.hint_size,
+        version=args.metadata_version,
+    )
+    print(f"Wrote {len(entries)} mappings to {args.output}", file=sys.stderr)
+
+
+def diff(args):
+    old_superblock = read_superblock_fields(args.old)
+    new_superblock = read_superblock_fields(args.new)
+    old_entries = {}
+    new_entries = {}
+    read_metadata(args.old, lambda entry: old_entries.__setitem__(entry.cache_block, entry))
+    read_metadata(args.new, lambda entry: new_entries.__setitem__(entry.cache_block, entry))
+
+    superblock_changes = {
+        name: {"old": old_superblock[name], "new": new_superblock[name]}
+        for name in old_superblock
+        if name in new_superblock and old_superblock[name] != new_superblock[name]
+    }
+    added = []
+    removed = []
+    remapped = []
+    dirty_changed = []
+    for cache_block in sorted(old_entries.keys() | new_entries.keys()):
+        old = old_entries.get(cache_block)
+        new = new_entries.get(cache_block)
+        if old is None:
+            added.append({"cache_block": cache_block, "origin_block": new.origin_block, "dirty": new.dirty})
+        elif new is None:
+            removed.append({"cache_block": cache_block, "origin_block": old.origin_block, "dirty": old.dirty})
+        elif old.origin_block != new.origin_block:
+            remapped.append({"cache_block": cache_block, "old_origin_block": old.origin_block,
+                    "new_origin_block": new.origin_block, "old_dirty": old.dirty, "new_dirty": new.dirty})
+        elif old.dirty != new.dirty:
+            dirty_changed.append({"cache_block": cache_block, "origin_block": new.origin_block,
+                    "old_dirty": old.dirty, "new_dirty": new.dirty})
+    summary = {
+        "superblock_changes": len(superblock_changes),
+        "added": len(added),
+        "removed": len(removed),
+        "remapped": len(remapped),
+        "dirty_changed": len(dirty_changed),
+    }
+
+    if args.format == "json":
+        json.dump({
+            "superblock": superblock_changes,
+            "added": added,
+            "removed": removed,
+            "remapped": remapped,
+            "dirty_changed": dirty_changed,
+            "summary": summary,
+        }, sys.stdout, indent=2)
+        print()
+    else:
+        for name, change in superblock_changes.items():
+            print(f"superblock {name}: {change['old']} -> {change['new']}")
+        for item in added:
+            print(f"added cache block {item['cache_block']} -> origin block {item['origin_block']}"
+                  f" (dirty={item['dirty']})")
+        for item in removed:
+            print(f"removed cache block {item['cache_block']} -> origin block {item['origin_block']}"
+                  f" (dirty={item['dirty']})")
+        for item in remapped:
+            print(f"remapped cache block {item['cache_block']}: origin block {item['old_origin_block']}"
+                  f" -> {item['new_origin_block']} (dirty={item['old_dirty']} -> {item['new_dirty']})")
+        for item in dirty_changed:
+            print(f"dirty flag of cache block {item['cache_block']} -> origin block {item['origin_block']}:"
+                  f" {item['old_dirty']} -> {item['new_dirty']}")
+        print(", ".join(f"{count} {name.replace('_', ' ')}" for name, count in summary.items()), file=sys.stderr)
+    if any(summary.values()):
+        sys.exit(1)
+
+
+def read_superblock_fields(path):
+    """Returns the fields of the superblock which are relevant for comparisons.
+    Binary metadata provides more fields than XML files.
+    """
+    if not is_binary_metadata(path):
+        parameters = read_parameters(path)
+        return {
+            "block_size": parameters.block_size,
+            "nr_cache_blocks": parameters.nr_cache_blocks,
+            "policy": parameters.policy,
+            "hint_size": parameters.hint_size,
+        }
+    with dev_open(path) as fd:
+        superblock = MetadataDevice(fd).read_superblock()
+    return {
+        "block_size": superblock.data_block_size,
+        "nr_cache_blocks": superblock.cache_blocks,
+        "policy": superblock.policy_name,
+        "hint_size": superblock.policy_hint_size,
+        "uuid": superblock.uuid.hex(),
+        "version": superblock.version,
+        "clean_shutdown": bool(superblock.flags & SUPERBLOCK_CLEAN_SHUTDOWN),
+        "needs_check": bool(superblock.flags & SUPERBLOCK_NEEDS_CHECK),
+        "policy_version": ".".join(map(str, superblock.policy_version)),
+        "discard_block_size": superblock.discard_block_size,
+        "discard_nr_blocks": superblock.discard_nr_blocks,
+        "compat_flags": superblock.compat_flags,
+        "compat_ro_flags": superblock.compat_ro_flags,
+        "incompat_flags": superblock.incompat_flags,
+    }
+
+
+def validate(args):
+    origin_sectors = args.origin_size
+    if args.origin is not None:
+        with dev_open(args.origin) as fd:
+            origin_sectors = os.lseek(fd, 0, os.SEEK_END) // 512
+    issues = []
+    read_metadata(args.metadata, lambda entry: None, on_issue=issues.append, origin_sectors=origin_sectors)
+
+    summary = {}
+    for issue in issues:
+        summary[issue.kind] = summary.get(issue.kind, 0) + 1
+    errors = sum(1 for issue in issues if issue.severity == "error")
+    if args.format == "json":
+        json.dump({
+            "issues": [asdict(issue) for issue in issues],
+            "summary": {"errors": errors, "warnings": len(issues) - errors, "kinds": summary},
+        }, sys.stdout, indent=2)
+        print()
+    else:
+        for issue in issues:
+            print(f"{issue.severity}: {format_issue(issue)} [{issue.kind}]")
+        print(f"{errors} errors, {len(issues) - errors} warnings", file=sys.stderr)
+    if errors:
+        sys.exit(1)
+
+
+def stats(args):
+    if args.buckets < 1 or args.heatmap_columns < 1 or args.heatmap_rows < 1 or args.heatmap_scale < 1:
+        sys.exit("The number of buckets and the size of the heatmap must be positive")
+    if args.heatmap is not None and args.heatmap.suffix.lower() not in (".png", ".svg"):
+        sys.exit("The heatmap must be a .png or .svg file")
+    parameters = read_parameters(args.metadata)
+    entries = []
+    read_metadata(args.metadata, entries.append)
+    entries.sort(key=lambda entry: (entry.origin_block, entry.cache_block))
+
+    origin_sectors = args.origin_size
+    if args.origin is not None:
+        with dev_open(args.origin) as fd:
+            origin_sectors = os.lseek(fd, 0, os.SEEK_END) // 512
+    if origin_sectors is not None:
+        origin_blocks = origin_sectors // parameters.block_size
+    else:
+        # Without the origin device, the distribution ends at the last mapped block
+        origin_blocks = max((entry.origin_block + 1 for entry in entries), default=0)
+    origin_blocks = max(origin_blocks, 1)
+
+    # As in cache_verify.py, entries only assumed to be dirty may turn out to be clean
+    dirty_entries = [entry for entry in entries if entry.dirty]
+    assumed_dirty = sum(1 for entry in entries if entry.assumed_dirty)
+    dirty_runs = block_runs(dirty_entries)
+    largest_runs = sorted(dirty_runs, key=lambda run: (-len(run[0]), run[0].start))[:args.top]
+
+    # The emulated table maps every dirty block to the cache, and the gaps between them to the origin
+    cache_segments = len(dirty_entries)
+    merged_cache_segments = sum(len(cache_runs) for _, cache_runs in dirty_runs)
+    origin_segments = 0
+    next_block = 0
+    for origin_run, _ in dirty_runs:
+        origin_segments += next_block < origin_run.start
+        next_block = origin_run.stop
+    origin_segments += next_block < origin_blocks
+
+    bucket_size = -(-origin_blocks // args.buckets)
+    buckets = [[0, 0] for _ in range(-(-origin_blocks // bucket_size))]
+    for entry in entries:
+        bucket = buckets[min(entry.origin_block // bucket_size, len(buckets) - 1)]
+        bucket[0] += 1
+        bucket[1] += entry.dirty
+
+    result = {
+        "block_size": parameters.block_size,
+        "nr_cache_blocks": parameters.nr_cache_blocks,
+        "mappings": len(entries),
+        "occupancy": round(len(entries) / parameters.nr_cache_blocks, 6) if parameters.nr_cache_blocks else None,
+        "dirty": len(dirty_entries),
+        "assumed_dirty": assumed_dirty,
+        "dirty_fraction": round(len(dirty_entries) / len(entries), 6) if entries else None,
+        "origin_blocks": origin_blocks,
+        "origin_size_known": origin_sectors is not None,
+        "dirty_runs": len(dirty_runs),
+        "largest_dirty_runs": [{"first": run.start, "last": run.stop - 1, "blocks": len(run)}
+                               for run, _ in largest_runs],
+        "table": {
+            "cache_segments": cache_segments,
+            "origin_segments": origin_segments,
+            "segments": cache_segments + origin_segments,
+            "segments_if_merged": merged_cache_segments + origin_segments,
+        },
+        "histogram": [{"first": index * bucket_size, "last": min((index + 1) * bucket_size, origin_blocks) - 1,
+                       "mapped": mapped, "dirty": dirty} for index, (mapped, dirty) in enumerate(buckets)],
+    }
+    if args.heatmap is not None:
+        write_heatmap(args.heatmap, entries, origin_blocks, args.heatmap_columns, args.heatmap_rows,
+                      args.heatmap_scale)
+
+    if args.format == "json":
+        json.dump(result, sys.stdout, indent=2)
+        print()
+        return
+    block_bytes = 512 * parameters.block_size
+    print(f"block size: {parameters.block_size} sectors")
+    print(f"cache blocks: {parameters.nr_cache_blocks}, mapped: {len(entries)}"
+          f" ({format_fraction(result['occupancy'])} occupancy)")
+    print(f"dirty blocks: {len(dirty_entries)} ({format_fraction(result['dirty_fraction'])} of mapped,"
+          f" {format_size(len(dirty_entries) * block_bytes)})")
+    if assumed_dirty:
+        print(f"  {assumed_dirty} blocks are only assumed to be dirty after an unclean shutdown")
+    estimated = "" if origin_sectors is not None else ", up to the last mapped block"
+    print(f"origin blocks: {origin_blocks}{estimated}")
+    print(f"dirty runs: {len(dirty_runs)}")
+    for run, _ in largest_runs:
+        print(f"  origin blocks {format_range(run)}: {len(run)} blocks, {format_size(len(run) * block_bytes)}")
+    print(f"emulated table: {cache_segments + origin_segments} segments"
+          f" ({cache_segments} cache, {origin_segments} origin),"
+          f" {merged_cache_segments + origin_segments} if adjacent cache blocks were merged")
+    print("distribution over the origin device (+ mapped, # dirty):")
+    width = 50
+    largest = max((mapped for mapped, _ in buckets), default=0) or 1
+    for index, (mapped, dirty) in enumerate(buckets):
+        dirty_width = -(-dirty * width // largest)
+        clean_width = -(-mapped * width // largest) - dirty_width
+        first = index * bucket_size
+        last = min(first + bucket_size, origin_blocks) - 1
+        print(f"  {first:>12}-{last:<12} {mapped:>10} {dirty:>10} {'#' * dirty_width}{'+' * clean_width}")
+
+
+def block_runs(entries):
+    """Returns the runs of consecutive origin blocks of the sorted entries,
+    each with the runs of their cache blocks which are consecutive too.
+    """
+    runs = []
+    for entry in entries:
+        if runs and entry.origin_block == runs[-1][0].stop:
+            origin_run, cache_runs = runs[-1]
+            if entry.cache_block == cache_runs[-1].stop:
+                cache_runs[-1] = range(cache_runs[-1].start, entry.cache_block + 1)
+            else:
+                cache_runs.append(range(entry.cache_block, entry.cache_block + 1))
+            runs[-1] = (range(origin_run.start, entry.origin_block + 1), cache_runs)
+        elif not runs or entry.origin_block > runs[-1][0].stop:
+            runs.append((range(entry.origin_block, entry.origin_block + 1),
+                         [range(entry.cache_block, entry.cache_block + 1)]))
+    return runs
+
+
+def format_fraction(value):
+    return "n/a" if value is None else f"{100 * value:.1f} %"
+
+
+def format_size(size):
+    for unit in ("B", "KiB", "MiB", "GiB"):
+        if size < 1024:
+            break
+        size /= 1024
+    else:
+        unit = "TiB"
+    return f"{size:.1f} {unit}" if unit != "B" else f"{size} B"
+
+
+HEATMAP_EMPTY = (240, 240, 240)
+HEATMAP_OUTSIDE = (255, 255, 255)
+HEATMAP_CLEAN = ((198, 219, 239), (8, 48, 107))
+HEATMAP_DIRTY = ((252, 187, 161), (103, 0, 13))
+
+
+def write_heatmap(path, entries, origin_blocks, columns, rows, scale):
+    """Renders the origin device as a grid of cells, row by row.
+    Cells with dirty blocks are red, cells with only clean mapped blocks are blue,
+    both darker with the fraction of the blocks of the cell.
+    """
+    blocks_per_cell = -(-origin_blocks // (columns * rows))
+    cells = -(-origin_blocks // blocks_per_cell)
+    rows = -(-cells // columns)
+    mapped = [0] * cells
+    dirty = [0] * cells
+    for entry in entries:
+        cell = min(entry.origin_block // blocks_per_cell, cells - 1)
+        mapped[cell] += 1
+        dirty[cell] += entry.dirty
+
+    def colour(cell):
+        if cell >= cells:
+            return HEATMAP_OUTSIDE
+        if dirty[cell]:
+            light, dark = HEATMAP_DIRTY
+            fraction = dirty[cell] / blocks_per_cell
+        elif mapped[cell]:
+            light, dark = HEATMAP_CLEAN
+            fraction = mapped[cell] / blocks_per_cell
+        else:
+            return HEATMAP_EMPTY
+        return tuple(round(l + (d - l) * min(fraction, 1)) for l, d in zip(light, dark))
+
+    grid = [[colour(row * columns + column) for column in range(columns)] for row in range(rows)]
+    if path.suffix.lower() == ".svg":
+        write_svg_heatmap(path, grid, scale, blocks_per_cell, mapped, dirty)
+    else:
+        write_png_heatmap(path, grid, scale)
+    print(f"heatmap: {columns}x{rows} cells of {blocks_per_cell} origin blocks each", file=sys.stderr)
+
+
+def write_png_heatmap(path, grid, scale):
+    def chunk(kind, data):
+        return struct.pack(">I", len(data)) + kind + data + struct.pack(">I", zlib.crc32(kind + data))
+
+    width = len(grid[0]) * scale
+    lines = []
+    for row in grid:
+        # Filter type 0 followed by the RGB values of the row
+        line = b"\0" + b"".join(bytes(pixel) * scale for pixel in row)
+        lines.extend([line] * scale)
+    with open(path, "wb") as file:
+        file.write(b"\x89PNG\r\n\x1a\n")
+        file.write(chunk(b"IHDR", struct.pack(">IIBBBBB", width, len(grid) * scale, 8, 2, 0, 0, 0)))
+        file.write(chunk(b"IDAT", zlib.compress(b"".join(lines), 9)))
+        file.write(chunk(b"IEND", b""))
+
+
+def write_svg_heatmap(path, grid, scale, blocks_per_cell, mapped, dirty):
+    columns = len(grid[0])
+    width = columns * scale
+    height = len(grid) * scale
+    legend = 16
+    with open(path, "w") as file:
+        file.write(f'<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="{height + legend}"'
+                   f' shape-rendering="crispEdges">\n')
+        file.write(f'<rect width="{width}" height="{height}" fill="rgb{HEATMAP_EMPTY}"/>\n')
+        for row_index, row in enumerate(grid):
+            for column, pixel in enumerate(row):
+                if pixel == HEATMAP_EMPTY:
+                    continue
+                cell = row_index * columns + column
+                title = ""
+                if cell < len(mapped):
+                    first = cell * blocks_per_cell
+                    title = (f"<title>origin blocks {first}-{first + blocks_per_cell - 1}:"
+                             f" {mapped[cell]} mapped, {dirty[cell]} dirty</title>")
+                file.write(f'<rect x="{column * scale}" y="{row_index * scale}" width="{scale}" height="{scale}"'
+                           f' fill="rgb{pixel}">{title}</rect>\n')
+        file.write(f'<text x="0" y="{height + legend - 4}" font-family="sans-serif" font-size="12">'
+                   f"{blocks_per_cell} origin blocks per cell; blue: mapped, red: dirty</text>\n")
+        file.write("</svg>\n")
+
+
+def merge_ranges(ranges):
+    """Merges overlapping or adjacent ranges and returns them in ascending order."""
+    result = []
+    for r in sorted(ranges, key=lambda r: r.start):
+        if result and r.start <= result[-1].stop:
+            result[-1] = range(result[-1].start, max(result[-1].stop, r.stop))
+        else:
+            result.append(r)
+    return result
+
+
+def read_metadata(path, callback, mapping_root=None, dirty_root=None, hints=False,
+        on_issue=None, origin_sectors=None, on_discards=None, discard_root=None):
+    """Calls ``callback`` for every mapping found in the given metadata.
+    The metadata can either be an XML file or a binary metadata device.
+    XML files may be compressed with gzip, xz or zstd, and ``-`` reads from stdin.
+    For binary metadata, ``mapping_root`` and ``dirty_root`` can be used
+    to read an older generation of the metadata instead of the current one.
+    If ``hints`` is true, the policy hints are attached to the entries.
+    As ``cache_dump`` writes the hints after the mappings,
+    all entries of an XML file are kept in memory in this case.
+    If ``on_discards`` is given, it is called with the discarded ranges, as returned by ``read_discards``,
+    before the first mapping. If ``mapping_root`` or ``dirty_root`` is given,
+    the discards of the current generation do not belong to the mappings,
+    so they are only read from ``discard_root``, and no ranges are discarded without it.
+    They are read in the same pass as the mappings, which also works for stdin;
+    as ``cache_dump`` writes the discards after the mappings,
+    the mappings of an XML file are kept in memory in a compact form in this case.
+
+    If ``on_issue`` is given, the metadata is validated strictly,
+    and problems are passed to ``on_issue`` instead of terminating the program.
+    ``origin_sectors`` is the size of the origin device used for the validation.
+    """
+    if is_binary_metadata(path):
+        if on_discards is not None:
+            if discard_root is None and (mapping_root is not None or dirty_root is not None):
+                on_discards([])
+            else:
+                on_discards(read_discards(path, discard_root))
+        read_binary_metadata(path, callback, mapping_root, dirty_root, hints, on_issue, origin_sectors)
+    elif mapping_root is not None or dirty_root is not None or discard_root is not None:
+        sys.exit("Older generations can only be read from binary metadata")
+    elif on_issue is None:
+        with open_metadata(path) as file:
+            parse(file, MetadataReader(callback, hints, on_discards=on_discards))
+    else:
+        try:
+            with open_metadata(path) as file:
+                parse(file, MetadataReader(callback, hints, on_issue, origin_sectors, on_discards))
+        except SAXParseException as e:
+            on_issue(Issue(severity="error", kind="malformed-xml", message=e.getMessage(), line=e.getLineNumber()))
+
+
+def read_discards(path, discard_root=None):
+    """Returns the ranges of the origin device which have been discarded,
+    as sorted list of tuples containing the first and the end sector.
+    If an XML file does not specify the size of discard blocks,
+    the size of cache blocks is used, as the kernel does.
+    For binary metadata, ``discard_root`` can be used to read an older generation of the discard bitset.
+    """
+    if is_binary_metadata(path):
+        with dev_open(path) as fd:
+            device = MetadataDevice(fd)
+            superblock = device.read_superblock()
+            if discard_root is None:
+                discard_root = superblock.discard_root
+            if discard_root == 0 or superblock.discard_nr_blocks == 0:
+                return []
+            words = device.read_bitset(discard_root, superblock.discard_nr_blocks,
+                    "discard bitset", cache_blocks=False)
+        block_size = superblock.discard_block_size
+        return [(block_size * first, block_size * end)
+                for first, end in bitset_ranges(words, superblock.discard_nr_blocks)]
+    if discard_root is not None:
+        sys.exit("Older generations can only be read from binary metadata")
+    if is_stdin(path):
+        sys.exit("Discards cannot be read from stdin on their own, use read_metadata with on_discards")
+    handler = DiscardReader()
+    with open_metadata(path) as file:
+        parse(file, handler)
+    return handler.merged_ranges()
+
+
+def cachevol_regions(path):
+    """Returns the sizes of the metadata and the data region of a cachevol in sectors.
+    The size of the metadata region is recorded in the metadata space map,
+    the size of the data region follows from the number and the size of the cache blocks.
+    """
+    with dev_open(path) as fd:
+        superblock = MetadataDevice(fd).read_superblock()
+    nr_blocks = struct.unpack_from(SPACE_MAP_ROOT_FORMAT, superblock.metadata_space_map_root)[0]
+    return nr_blocks * superblock.metadata_block_size, superblock.cache_blocks * superblock.data_block_size
+
+
+def bitset_ranges(words, nr_bits):
+    """Returns the ranges of set bits as list of tuples containing the first and the end bit."""
+    ranges = []
+    def add(first, end):
+        end = min(end, nr_bits)
+        if first >= end:
+            return
+        if ranges and ranges[-1][1] == first:
+            ranges[-1] = (ranges[-1][0], end)
+        else:
+            ranges.append((first, end))
+    for index, word in enumerate(words):
+        if word == 0:
+            continue
+        if word == (1 << 64) - 1:
+            add(64 * index, 64 * index + 64)
+            continue
+        for bit in range(64):
+            if word >> bit & 1:
+                add(64 * index + bit, 64 * index + bit + 1)
+    return ranges
+
+
+def is_binary_metadata(path):
+    if is_stdin(path):
+        return False
+    with open(path, "rb") as file:
+        header = file.read(struct.calcsize(SUPERBLOCK_FORMAT))
+    if len(header) < struct.calcsize(SUPERBLOCK_FORMAT):
+        return False
+    return struct.unpack_from("<Q", header, 32)[0] == SUPERBLOCK_MAGIC
+
+
+def is_stdin(path):
+    return str(path) == "-"
+
+
+@contextmanager
+def open_metadata(path):
+    """Opens an XML file for reading, decompressing it on the fly if necessary.
+    The compression is detected by the magic bytes, not by the file name.
+    ``-`` stands for stdin, which can be opened again
+    as long as only its beginning has been read, e.g. by ``read_parameters``.
+    """
+    with (nullcontext(rewind_stdin()) if is_stdin(path) else open(path, "rb")) as file:
+        magic = file.peek(6)[:6]
+        if magic.startswith(b"\x1f\x8b"):
+            with gzip.GzipFile(fileobj=file) as decompressed:
+                yield decompressed
+        elif magic.startswith(b"\xfd7zXZ\x00"):
+            with lzma.LZMAFile(file) as decompressed:
+                yield decompressed
+        elif magic.startswith(b"\x28\xb5\x2f\xfd"):
+            with zstd_decompress(file) as decompressed:
+                yield decompressed
+        else:
+            yield file
+
+
+@contextmanager
+def zstd_decompress(file):
+    """Decompresses a stream with the ``zstd`` command, as Python lacks zstd support."""
+    if shutil.which("zstd") is None:
+        sys.exit("Reading zstd compressed metadata requires the zstd command")
+    process = Popen(["zstd", "--decompress", "--stdout", "--quiet"], stdin=PIPE, stdout=PIPE)
+    def feed():
+        try:
+            shutil.copyfileobj(file, process.stdin)
+        except BrokenPipeError:
+            pass
+        finally:
+            process.stdin.close()
+    feeder = threading.Thread(target=feed, daemon=True)
+    feeder.start()
+    try:
+        yield process.stdout
+    finally:
+        process.stdout.close()
+        process.wait()
+        feeder.join()
+    if process.returncode not in (0, -13):
+        sys.exit(f"zstd failed with exit code {process.returncode}")
+
+
+class RewindableInput:
+    """Wraps stdin, so that it can be read again from the beginning.
+    Everything read is kept in memory up to ``limit`` bytes,
+    which suffices to read the superblock of an XML file first.
+    """
+
+    def __init__(self, file, limit=1 << 20):
+        self.__file = file
+        self.__limit = limit
+        self.__buffer = bytearray()
+        self.__position = 0
+        self.__overflowed = False
+
+    def rewind(self):
+        if self.__overflowed:
+            sys.exit("Metadata from stdin has already been read")
+        self.__position = 0
+        return self
+
+    def peek(self, size):
+        self.__fill(size)
+        return bytes(self.__buffer[self.__position:self.__position + size])
+
+    def read(self, size=-1):
+        if self.__position < len(self.__buffer):
+            if size < 0:
+                size = len(self.__buffer) - self.__position
+            data = bytes(self.__buffer[self.__position:self.__position + size])
+            self.__position += len(data)
+            return data
+        data = self.__file.read(size) if size >= 0 else self.__file.read()
+        if not self.__overflowed:
+            self.__buffer += data
+            self.__position = len(self.__buffer)
+            if len(self.__buffer) > self.__limit:
+                self.__buffer = bytearray()
+                self.__position = 0
+                self.__overflowed = True
+        return data
+
+    def read1(self, size=-1):
+        return self.read(size if size >= 0 else 1 << 16)
+
+    def readinto(self, buffer):
+        data = self.read(len(buffer))
+        buffer[:len(data)] = data
+        return len(data)
+
+    def readable(self):
+        return True
+
+    def close(self):
+        pass
+
+    def __fill(self, size):
+        while len(self.__buffer) < self.__position + size:
+            data = self.__file.read(self.__position + size - len(self.__buffer))
+            if not data:
+                break
+            self.__buffer += data
+
+
+STDIN = None
+
+
+def rewind_stdin():
+    """Returns stdin wrapped by ``RewindableInput`` and rewound to the beginning.
+    The wrapper is only created when needed, as stdin may be closed otherwise.
+    """
+    global STDIN
+    if STDIN is None:
+        if sys.stdin is None:
+            sys.exit("Cannot read metadata from stdin, as stdin is closed")
+        STDIN = RewindableInput(sys.stdin.buffer)
+    return STDIN.rewind()
+
+
+def read_binary_metadata(path, callback, mapping_root=None, dirty_root=None, hints=False,
+        on_issue=None, origin_sectors=None):
+    with dev_open(path) as fd:
+        if on_issue is None:
+            device = MetadataDevice(fd)
+        else:
+            device = MetadataDevice(fd, on_corruption=lambda corruption: on_issue(Issue(
+                severity="error",
+                kind="corrupted-block",
+                message=describe_corruption(corruption),
+                block=corruption.block,
+            )))
+        if on_issue is None:
+            superblock = device.read_superblock()
+        else:
+            superblock = device.read_superblock(on_invalid=lambda kind, message: on_issue(Issue(
+                severity="error",
+                kind=kind,
+                message=message,
+                block=SUPERBLOCK_LOCATION,
+            )))
+            if superblock is None:
+                return
+        block_size = superblock.data_block_size
+        checker = None
+        if on_issue is not None:
+            checker = MappingChecker(on_issue, block_size, superblock.cache_blocks, origin_sectors,
+                    block=SUPERBLOCK_LOCATION)
+
+        hint_values = {}
+        if hints and superblock.hint_root != 0 and superblock.policy_hint_size > 0:
+            hint_values = dict(device.iter_array(superblock.hint_root, superblock.policy_hint_size, "hint array", raw=True))
+
+        if mapping_root is None:
+            mapping_root = superblock.mapping_root
+            if dirty_root is None:
+                dirty_root = superblock.dirty_root
+        dirty_known = report_superblock_flags(superblock)
+        dirty_bits = None
+        if superblock.version >= 2 and dirty_known:
+            if dirty_root is None:
+                print("No dirty bitset given for the selected generation, considering all blocks dirty",
+                        file=sys.stderr)
+                dirty_known = False
+            else:
+                dirty_bits = device.read_bitset(dirty_root, superblock.cache_blocks, "dirty bitset")
+
+        for cache_block, value, array_block in device.iter_array(mapping_root, 8, "mapping array", with_blocks=True):
+            origin_block, flags = unpack_mapping(value)
+            if not flags & MAPPING_VALID:
+                continue
+            if checker is not None:
+                checker.check(cache_block, origin_block, block=array_block)
+                if cache_block >= superblock.cache_blocks:
+                    continue
+            elif cache_block >= superblock.cache_blocks:
+                sys.exit(f"Invalid metadata: cache block {cache_block} beyond {superblock.cache_blocks} cache blocks")
+            hint = hint_values.get(cache_block)
+            if not dirty_known:
+                callback(make_entry(block_size, origin_block, cache_block, True, assumed_dirty=True, hint=hint))
+                continue
+            if dirty_bits is None:
+                dirty = bool(flags & MAPPING_DIRTY)
+            else:
+                dirty = test_bit(dirty_bits, cache_block)
+            callback(make_entry(block_size, origin_block, cache_block, dirty, hint=hint))
+
+
+def report_superblock_flags(superblock):
+    """Prints warnings about the flags of the superblock
+    and returns whether the cache was shut down cleanly.
+    Like the kernel, all mapped blocks must be considered dirty otherwise.
+    """
+    clean_shutdown = bool(superblock.flags & SUPERBLOCK_CLEAN_SHUTDOWN)
+    if not clean_shutdown:
+        print("Cache was not shut down cleanly, considering all mapped blocks dirty", file=sys.stderr)
+    if superblock.flags & SUPERBLOCK_NEEDS_CHECK:
+        print("Metadata is flagged as needing a check", file=sys.stderr)
+    return clean_shutdown
+
+
+def salvage_metadata(path, callback, block_size=None):
+    """Calls ``callback`` for every mapping which can be recovered
+    by scanning the whole metadata device for blocks of the mapping array.
+    Blocks of the mapping array do not know their position within the array,
+    so only blocks referenced by a leaf of some btree can be used.
+    Blocks of the bitsets are told apart by the length of the array,
+    and by their location if the superblock is intact.
+    If multiple copies of the same part of the array are found,
+    copies which are still referenced by the superblock are preferred,
+    followed by copies which are still allocated according to the space map,
+    and copies with a valid checksum.
+    Remaining conflicts are resolved by majority.
+    Policy hints are not used for this: there is one hint per cache block, not per copy,
+    and the hint array is only written on a clean shutdown,
+    so it cannot tell which of the copies of a mapping is newer.
+
+    The dirty bitset is only used for mappings taken from the intact
+    mapping array of the superblock, and if the bitset itself is intact.
+    Otherwise, mappings are considered dirty, unless the metadata uses version 1.
+    Without a clean shutdown, all mappings are considered dirty.
+    """
+    with dev_open(path) as fd:
+        device = MetadataDevice(fd, on_corruption=lambda corruption: None)
+        superblock = device.read_intact_superblock()
+        if block_size is None:
+            if superblock is None:
+                sys.exit("Superblock is damaged, the block size must be specified")
+            block_size = superblock.data_block_size
+
+        current = set()
+        bitmaps = None
+        dirty_bits = None
+        bitset_blocks = set()
+        clean_shutdown = superblock is not None and report_superblock_flags(superblock)
+        if superblock is not None:
+            for key, value in device.iter_btree(superblock.mapping_root, "mapping array"):
+                current.add((key, struct.unpack("<Q", value)[0]))
+            bitmaps = device.read_space_map(superblock)
+            # Blocks of the bitsets look like blocks of the mapping array, too
+            for root in (superblock.dirty_root if superblock.version >= 2 else 0, superblock.discard_root):
+                if root != 0:
+                    bitset_blocks.update(block for _, _, block in device.iter_array(root, 8, "bitset", with_blocks=True))
+            if superblock.version >= 2:
+                damaged = []
+                checked_device = MetadataDevice(fd, on_corruption=damaged.append)
+                checked_device.read_superblock()
+                dirty_bits = checked_device.read_bitset(superblock.dirty_root, superblock.cache_blocks, "dirty bitset")
+                damaged_dirty_bits = merge_ranges(c.cache_blocks for c in damaged if c.cache_blocks)
+
+        leaves = []
+        mapping_blocks = {}
+        for block, data in device.iter_blocks():
+            leaf = decode_salvaged_leaf(block, data)
+            if leaf is not None:
+                leaves.append(leaf)
+                continue
+            values = decode_salvaged_mapping_block(block, data)
+            if values is not None and block not in bitset_blocks:
+                mapping_blocks[block] = values
+
+        # The mapping array has one entry per cache block, a bitset only one per 64 cache blocks.
+        # Without the superblock, the array is assumed to end with the last entry found.
+        max_entries = (METADATA_BLOCK_SIZE - ARRAY_BLOCK_HEADER_SIZE) // 8
+        pairs = {pair for leaf in leaves for pair in leaf if pair[1] in mapping_blocks}
+        if superblock is not None:
+            nr_cache_blocks = superblock.cache_blocks
+        else:
+            nr_cache_blocks = max((key * max_entries + len(mapping_blocks[array_block][1])
+                                   for key, array_block in pairs), default=0)
+        candidates = {}
+        for key, array_block in pairs:
+            checksum_valid, values = mapping_blocks[array_block]
+            if len(values) != min(max_entries, nr_cache_blocks - key * max_entries):
+                continue
+            priority = (
+                (key, array_block) in current,
+                bitmaps is not None and space_map_allocated(bitmaps, array_block),
+                checksum_valid,
+            )
+            for i, value in enumerate(values):
+                cache_block = key * max_entries + i
+                candidates.setdefault(cache_block, []).append((priority, value))
+
+        referenced = {array_block for leaf in leaves for _, array_block in leaf}
+        orphans = len(mapping_blocks.keys() - referenced)
+        if orphans:
+            print(f"Salvage: {orphans} blocks of the mapping array are not referenced by any btree leaf",
+                    file=sys.stderr)
+
+        version = 2 if superblock is None else superblock.version
+        for cache_block in sorted(candidates):
+            resolved = resolve_salvaged_mapping(cache_block, candidates[cache_block])
+            if resolved is None:
+                continue
+            priority, value = resolved
+            origin_block, flags = unpack_mapping(value)
+            if not flags & MAPPING_VALID:
+                continue
+            if version == 1 and clean_shutdown:
+                dirty = bool(flags & MAPPING_DIRTY)
+            elif clean_shutdown and dirty_bits is not None and priority[0] \
+                    and not any(cache_block in r for r in damaged_dirty_bits):
+                dirty = test_bit(dirty_bits, cache_block)
+            else:
+                callback(make_entry(block_size, origin_block, cache_block, True,
+                        salvaged=True, assumed_dirty=True))
+                continue
+            callback(make_entry(block_size, origin_block, cache_block, dirty, salvaged=True))
+
+
+@dataclass(frozen=True, kw_only=True, slots=True)
+class Generation:
+    root: int
+    structure: str
+    entries: int
+    mappings: int
+    damaged_blocks: int
+    partial: bool
+    current: bool
+    allocated: bool
+    differences: int | None
+
+
+def find_generations(fd, superblock):
+    """Returns the roots of all arrays which are still found on the metadata device.
+    Shadowing leaves older copies of the mapping array and of the bitsets behind,
+    until their blocks are reused.
+    Roots are btree nodes of arrays which are not referenced by any other node.
+    The current roots are classified by the superblock, older ones by the number of entries:
+    the mapping array has one per cache block, the bitsets one per 64 blocks.
+    """
+    device = MetadataDevice(fd, on_corruption=lambda corruption: None)
+    device.read_superblock()
+    nodes = set()
+    referenced = set()
+    array_blocks = {}
+    for block, data in device.iter_blocks():
+        children = decode_array_btree_node(block, data)
+        if children is not None:
+            nodes.add(block)
+            referenced.update(children)
+            continue
+        values = decode_array_block(block, data)
+        if values is not None:
+            array_blocks[block] = values
+
+    current_mappings = {}
+    for cache_block, value in device.iter_array(superblock.mapping_root, 8, "mapping array"):
+        current_mappings[cache_block] = value
+    current_roots = {superblock.mapping_root: "mapping array", superblock.discard_root: "discard bitset"}
+    if superblock.version >= 2:
+        current_roots[superblock.dirty_root] = "dirty bitset"
+    if superblock.policy_hint_size == 8:
+        current_roots[superblock.hint_root] = "hint array"
+    dirty_words = (superblock.cache_blocks + 63) // 64
+    discard_words = (superblock.discard_nr_blocks + 63) // 64
+    bitmaps = device.read_space_map(superblock)
+
+    generations = []
+    for root in sorted(nodes - referenced):
+        leaves = [(key, struct.unpack("<Q", value)[0])
+                  for key, value in device.iter_btree(root, "array")]
+        if not leaves:
+            continue
+        if not all(block in array_blocks for _, block in leaves):
+            continue
+        values = [value for _, block in leaves for value in array_blocks[block]]
+        if root in current_roots:
+            structure = current_roots[root]
+        elif len(values) == superblock.cache_blocks and all(is_mapping_value(value) for value in values):
+            structure = "mapping array"
+        elif len(values) == dirty_words and superblock.version >= 2:
+            structure = "dirty bitset"
+        elif len(values) == discard_words:
+            structure = "discard bitset"
+        else:
+            structure = "array"
+
+        damaged = set()
+        checked_device = MetadataDevice(fd, on_corruption=lambda corruption: damaged.add(corruption.block))
+        checked_device.read_superblock()
+        entries = 0
+        mappings = 0
+        differences = 0 if structure == "mapping array" else None
+        seen = set()
+        for index, value in checked_device.iter_array(root, 8, structure):
+            entries += 1
+            if structure == "mapping array":
+                seen.add(index)
+                if value & MAPPING_VALID:
+                    mappings += 1
+                if current_mappings.get(index, 0) != value:
+                    differences += 1
+        if differences is not None:
+            differences += sum(1 for index in current_mappings.keys() - seen if current_mappings[index])
+        generations.append(Generation(
+            root=root,
+            structure=structure,
+            entries=entries,
+            mappings=mappings,
+            damaged_blocks=len(damaged),
+            partial=leaves[0][0] != 0,
+            current=root in current_roots,
+            allocated=space_map_allocated(bitmaps, root),
+            differences=differences,
+        ))
+    return generations
+
+
+def decode_array_btree_node(block, data):
+    """Returns the children of a block looking like an internal node of the btree of an array,
+    an empty list for leaves, or ``None`` otherwise.
+    """
+    _, flags, blocknr, nr_entries, max_entries, value_size, _ = \
+        struct.unpack_from(BTREE_NODE_HEADER_FORMAT, data)
+    if flags not in (BTREE_INTERNAL_NODE, BTREE_LEAF_NODE) or value_size != 8 or blocknr != block \
+            or max_entries != btree_max_entries(8) or nr_entries > max_entries:
+        return None
+    if block_checksum(data, BTREE_CSUM_XOR) != struct.unpack_from("<I", data)[0]:
+        return None
+    if flags == BTREE_LEAF_NODE:
+        return []
+    return list(struct.unpack_from(f"<{nr_entries}Q", data, BTREE_NODE_HEADER_SIZE + 8 * max_entries))
+
+
+def decode_array_block(block, data):
+    """Returns the values of a block looking like an array block with 64-bit values,
+    or ``None`` otherwise.
+    """
+    _, max_entries, nr_entries, value_size, blocknr = \
+        struct.unpack_from(ARRAY_BLOCK_HEADER_FORMAT, data)
+    if value_size != 8 or blocknr != block or nr_entries > max_entries \
+            or max_entries != (METADATA_BLOCK_SIZE - ARRAY_BLOCK_HEADER_SIZE) // 8:
+        return None
+    if block_checksum(data, ARRAY_CSUM_XOR) != struct.unpack_from("<I", data)[0]:
+        return None
+    return struct.unpack_from(f"<{nr_entries}Q", data, ARRAY_BLOCK_HEADER_SIZE)
+
+
+def decode_salvaged_leaf(block, data):
+    """Returns the key-value pairs of a block looking like a leaf of an array,
+    or ``None`` otherwise.
+    """
+    _, flags, blocknr, nr_entries, max_entries, value_size, _ = \
+        struct.unpack_from(BTREE_NODE_HEADER_FORMAT, data)
+    if flags != BTREE_LEAF_NODE or value_size != 8 or blocknr != block \
+            or max_entries != btree_max_entries(8) or nr_entries > max_entries:
+        return None
+    if block_checksum(data, BTREE_CSUM_XOR) != struct.unpack_from("<I", data)[0]:
+        return None
+    keys = struct.unpack_from(f"<{nr_entries}Q", data, BTREE_NODE_HEADER_SIZE)
+    values = struct.unpack_from(f"<{nr_entries}Q", data, BTREE_NODE_HEADER_SIZE + 8 * max_entries)
+    return list(zip(keys, values))
+
+
+def decode_salvaged_mapping_block(block, data):
+    """Returns whether the checksum is valid and the values of a block
+    looking like a block of the mapping array, or ``None`` otherwise.
+    """
+    _, max_entries, nr_entries, value_size, blocknr = \
+        struct.unpack_from(ARRAY_BLOCK_HEADER_FORMAT, data)
+    if value_size != 8 or blocknr != block or nr_entries > max_entries \
+            or max_entries != (METADATA_BLOCK_SIZE - ARRAY_BLOCK_HEADER_SIZE) // 8:
+        return None
+    values = struct.unpack_from(f"<{nr_entries}Q", data, ARRAY_BLOCK_HEADER_SIZE)
+    if not all(is_mapping_value(value) for value in values) \
+            or not any(value & MAPPING_VALID for value in values):
+        return None
+    return block_checksum(data, ARRAY_CSUM_XOR) == struct.unpack_from("<I", data)[0], values
+
+
+def resolve_salvaged_mapping(cache_block, candidates):
+    """Returns the priority and the value of the best candidate,
+    or ``None`` on an unresolvable conflict.
+    """
+    best_priority = max(priority for priority, _ in candidates)
+    if len({value for _, value in candidates}) == 1:
+        return best_priority, candidates[0][1]
+    votes = {}
+    for priority, value in candidates:
+        if priority == best_priority:
+            votes[value] = votes.get(value, 0) + 1
+    ranking = sorted(votes.items(), key=lambda item: item[1], reverse=True)
+    if len(ranking) > 1 and ranking[0][1] == ranking[1][1]:
+        print(f"Salvage: conflicting copies of cache block {cache_block} cannot be resolved",
+                file=sys.stderr)
+        return None
+    print(f"Salvage: resolved conflicting copies of cache block {cache_block}", file=sys.stderr)
+    return best_priority, ranking[0][0]
+
+
+def btree_max_entries(value_size):
+    """Returns the number of entries in a btree node like ``calc_max_entries``."""
+    total = (METADATA_BLOCK_SIZE - BTREE_NODE_HEADER_SIZE) // (8 + value_size)
+    return 3 * (total // 3)
+
+
+@dataclass(frozen=True, kw_only=True, slots=True)
+class Entry:
+    origin_block: int
+    origin_sector: int
+    origin_offset: int
+    cache_block: int
+    cache_sector: int
+    cache_offset: int
+    dirty: bool
+    block_bytes: int
+    block_sectors: int
+    salvaged: bool = False
+    assumed_dirty: bool = False
+    hint: bytes | None = None
+
+
+class DiscardReader(ContentHandler):
+
+    def __init__(self):
+        super().__init__()
+        self.ranges = []
+        self.__discard_block_size = None
+
+    def startElement(self, name, attrs):
+        if name == "superblock":
+            self.__discard_block_size = int(attrs.get("discard_block_size", attrs["block_size"]))
+        elif name == "discard":
+            block_size = self.__discard_block_size
+            self.ranges.append((block_size * int(attrs["dbegin"]), block_size * int(attrs["dend"])))
+
+    def merged_ranges(self):
+        return [(r.start, r.stop) for r in merge_ranges(range(first, end) for first, end in self.ranges)]
+
+
+def make_entry(block_size, origin_block, cache_block, dirty, salvaged=False, assumed_dirty=False, hint=None):
+    """Creates an entry, where ``block_size`` is counted in sectors.
+    ``assumed_dirty`` marks entries which are only considered dirty
+    because their actual state is unknown.
+    """
+    return Entry(
+        origin_block=origin_block,
+        origin_sector=block_size * origin_block,
+        origin_offset=512 * block_size * origin_block,
+        cache_block=cache_block,
+        cache_sector=block_size * cache_block,
+        cache_offset=512 * block_size * cache_block,
+        dirty=dirty,
+        block_bytes=512 * block_size,
+        block_sectors=block_size,
+        salvaged=salvaged,
+        assumed_dirty=assumed_dirty,
+        hint=hint,
+    )
+
+
+class MetadataReader(ContentHandler):
+    """Reads the mappings of an XML file created by ``cache_dump``.
+    Without ``on_issue``, the first error terminates the program.
+    Otherwise, all issues are passed to ``on_issue``,
+    and the mappings are additionally checked by ``MappingChecker``.
+    With ``on_discards``, the discards are collected as well,
+    and the mappings are kept as triples of integers until they are known.
+    """
+
+    def __init__(self, callback, hints=False, on_issue=None, origin_sectors=None, on_discards=None):
+        super().__init__()
+        self.__callback = callback
+        self.__superblock = False
+        self.__missing_superblock = False
+        self.__block_size = None
+        self.__entries = [] if hints else None
+        self.__pending = array("Q") if on_discards is not None and not hints else None
+        self.__hints = {}
+        self.__on_discards = on_discards
+        self.__discards = DiscardReader()
+        self.__on_issue = exit_on_error if on_issue is None else on_issue
+        self.__strict = on_issue is not None
+        self.__origin_sectors = origin_sectors
+        self.__checker = None
+        self.__locator = None
+
+    def setDocumentLocator(self, locator):
+        self.__locator = locator
+
+    def startElement(self, name, attrs):
+        if name == "superblock":
+            if self.__superblock:
+                self.__issue("error", "second-superblock", "second superblock")
+                return
+            # Malformed fields of the superblock are unknown, but the superblock is still there
+            self.__superblock = True
+            self.__block_size = self.__int(attrs, "block_size")
+            if self.__on_discards is not None and self.__block_size is not None:
+                self.__discards.startElement(name, attrs)
+            if self.__strict:
+                nr_cache_blocks = self.__int(attrs, "nr_cache_blocks")
+                self.__checker = MappingChecker(self.__on_issue, self.__block_size, nr_cache_blocks,
+                        self.__origin_sectors, line=self.__line())
+            return
+        if not self.__superblock and not self.__missing_superblock:
+            self.__issue("error", "missing-superblock", "No superblock")
+            self.__missing_superblock = True
+        if name == "mapping":
+            origin_block = self.__int(attrs, "origin_block")
+            cache_block = self.__int(attrs, "cache_block")
+            dirty = self.__bool(attrs, "dirty")
+            if origin_block is None or cache_block is None or dirty is None:
+                return
+            if self.__checker is not None:
+                self.__checker.check(cache_block, origin_block, line=self.__line())
+            if self.__block_size is None:
+                return
+            if self.__pending is not None:
+                self.__pending.extend((origin_block, cache_block, dirty))
+                return
+            entry = make_entry(self.__block_size, origin_block, cache_block, dirty)
+            if self.__entries is None:
+                self.__callback(entry)
+            else:
+                self.__entries.append(entry)
+        elif name == "hint":
+            cache_block = self.__int(attrs, "cache_block")
+            if self.__entries is not None and cache_block is not None and "data" in attrs:
+                self.__hints[cache_block] = b64decode(attrs["data"])
+        elif name == "discard":
+            if self.__on_discards is not None and self.__block_size is not None \
+                    and self.__int(attrs, "dbegin") is not None and self.__int(attrs, "dend") is not None:
+                self.__discards.startElement(name, attrs)
+        elif name not in XML_ELEMENTS:
+            self.__issue("warning", "unknown-element", f"unknown element <{name}>")
+
+    def endDocument(self):
+        if self.__on_discards is not None:
+            self.__on_discards(self.__discards.merged_ranges())
+        if self.__pending is not None:
+            for index in range(0, len(self.__pending), 3):
+                origin_block, cache_block, dirty = self.__pending[index:index + 3]
+                self.__callback(make_entry(self.__block_size, origin_block, cache_block, bool(dirty)))
+        if self.__entries is not None:
+            for entry in self.__entries:
+                self.__callback(replace(entry, hint=self.__hints.get(entry.cache_block)))
+
+    def __int(self, attrs, name):
+        if name not in attrs:
+            self.__issue("error", "missing-attribute", f"missing attribute {name}")
+            return None
+        try:
+            return int(attrs[name])
+        except ValueError:
+            self.__issue("error", "malformed-integer", f"malformed integer in {name}: {attrs[name]!r}")
+            return None
+
+    def __bool(self, attrs, name):
+        if name not in attrs:
+            self.__issue("error", "missing-attribute", f"missing attribute {name}")
+            return None
+        try:
+            return parse_bool(attrs[name])
+        except ValueError:
+            self.__issue("error", "malformed-boolean", f"malformed boolean in {name}: {attrs[name]!r}")
+            return None
+
+    def __line(self):
+        return None if self.__locator is None else self.__locator.getLineNumber()
+
+    def __issue(self, severity, kind, message):
+        self.__on_issue(Issue(severity=severity, kind=kind, message=message, line=self.__line()))
+
+
+@dataclass(frozen=True, kw_only=True, slots=True)
+class Issue:
+    severity: str
+    kind: str
+    message: str
+    line: int | None = None
+    block: int | None = None
+
+
+def exit_on_error(issue):
+    if issue.severity == "error":
+        sys.exit(f"Invalid metadata: {format_issue(issue)}")
+
+
+def format_issue(issue):
+    location = format_location(issue.line, issue.block)
+    return issue.message if location is None else f"{location}: {issue.message}"
+
+
+def format_location(line, block):
+    """Formats the line of an XML file or the block of a metadata device, or returns ``None`` without both."""
+    if line is not None:
+        return f"line {line}"
+    if block is not None:
+        return f"block {block}"
+    return None
+
+
+class MappingChecker:
+    """Checks mappings for duplicates and for blocks beyond the end of the devices.
+    The size of the devices is optional.
+    Without a valid block size, origin blocks are not checked against the size of the origin device.
+    """
+
+    def __init__(self, on_issue, block_size, nr_cache_blocks, origin_sectors, *, line=None, block=None):
+        self.__on_issue = on_issue
+        self.__nr_cache_blocks = nr_cache_blocks
+        self.__nr_origin_blocks = None
+        if block_size is not None and block_size <= 0:
+            on_issue(Issue(severity="error", kind="invalid-block-size", message=f"invalid block size {block_size}",
+                    line=line, block=block))
+        elif block_size is not None and origin_sectors is not None:
+            self.__nr_origin_blocks = origin_sectors // block_size
+        self.__cache_blocks = {}
+        self.__origin_blocks = {}
+
+    def check(self, cache_block, origin_block, *, line=None, block=None):
+        def issue(kind, message):
+            self.__on_issue(Issue(severity="error", kind=kind, message=message, line=line, block=block))
+        def first_at(location):
+            return "" if location is None else f", first at {location}"
+        location = format_location(line, block)
+        if cache_block in self.__cache_blocks:
+            issue("duplicate-cache-block",
+                    f"cache block {cache_block} is mapped twice{first_at(self.__cache_blocks[cache_block])}")
+        else:
+            self.__cache_blocks[cache_block] = location
+        if origin_block in self.__origin_blocks:
+            issue("duplicate-origin-block",
+                    f"origin block {origin_block} is mapped twice{first_at(self.__origin_blocks[origin_block])}")
+        else:
+            self.__origin_blocks[origin_block] = location
+        if self.__nr_cache_blocks is not None and cache_block >= self.__nr_cache_blocks:
+            issue("cache-block-out-of-range",
+                    f"cache block {cache_block} beyond {self.__nr_cache_blocks} cache blocks")
+        if self.__nr_origin_blocks is not None and origin_block >= self.__nr_origin_blocks:
+            issue("origin-block-out-of-range",
+                    f"origin block {origin_block} beyond {self.__nr_origin_blocks} origin blocks")
+
+
+@dataclass(frozen=True, kw_only=True, slots=True)
+class Parameters:
+    block_size: int
+    nr_cache_blocks: int
+    policy: str
+    hint_size: int
+
+
+def read_parameters(path):
+    """Returns the parameters stored in the superblock of the given metadata."""
+    if is_binary_metadata(path):
+        with dev_open(path) as fd:
+            superblock = MetadataDevice(fd).read_superblock()
+        return Parameters(
+            block_size=superblock.data_block_size,
+            nr_cache_blocks=superblock.cache_blocks,
+            policy=superblock.policy_name,
+            hint_size=superblock.policy_hint_size,
+        )
+    handler = ParametersReader()
+    try:
+        with open_metadata(path) as file:
+            parse(file, handler)
+    except StopParsing:
+        pass
+    if handler.parameters is None:
+        sys.exit("Invalid metadata: No superblock")
+    return handler.parameters
+
+
+class StopParsing(Exception):
+    pass
+
+
+class ParametersReader(ContentHandler):
+
+    def __init__(self):
+        super().__init__()
+        self.parameters = None
+
+    def startElement(self, name, attrs):
+        if name == "superblock":
+            self.parameters = Parameters(
+                block_size=int(attrs["block_size"]),
+                nr_cache_blocks=int(attrs["nr_cache_blocks"]),
+                policy=attrs.get("policy", "smq"),
+                hint_size=int(attrs.get("hint_width", 4)),
+            )
+        raise StopParsing()
+
+
+@dataclass(frozen=True, kw_only=True, slots=True)
+class Superblock:
+    csum: int
+    flags: int
+    blocknr: int
+    uuid: bytes
+    magic: int
+    version: int
+    policy_name: str
+    policy_hint_size: int
+    metadata_space_map_root: bytes
+    mapping_root: int
+    hint_root: int
+    discard_root: int
+    discard_block_size: int
+    discard_nr_blocks: int
+    data_block_size: int
+    metadata_block_size: int
+    cache_blocks: int
+    compat_flags: int
+    compat_ro_flags: int
+    incompat_flags: int
+    read_hits: int
+    read_misses: int
+    write_hits: int
+    write_misses: int
+    policy_version: tuple
+    dirty_root: int
+
+
+def unpack_superblock(data):
+    fields = struct.unpack_from(SUPERBLOCK_FORMAT, data)
+    return Superblock(
+        csum=fields[0],
+        flags=fields[1],
+        blocknr=fields[2],
+        uuid=fields[3],
+        magic=fields[4],
+        version=fields[5],
+        policy_name=fields[6].rstrip(b"\0").decode("ascii", "replace"),
+        policy_hint_size=fields[7],
+        metadata_space_map_root=fields[8],
+        mapping_root=fields[9],
+        hint_root=fields[10],
+        discard_root=fields[11],
+        discard_block_size=fields[12],
+        discard_nr_blocks=fields[13],
+        data_block_size=fields[14],
+        metadata_block_size=fields[15],
+        cache_blocks=fields[16],
+        compat_flags=fields[17],
+        compat_ro_flags=fields[18],
+        incompat_flags=fields[19],
+        read_hits=fields[20],
+        read_misses=fields[21],
+        write_hits=fields[22],
+        write_misses=fields[23],
+        policy_version=fields[24:27],
+        dirty_root=fields[27],
+    )
+
+
+@dataclass(frozen=True, kw_only=True, slots=True)
+class Corruption:
+    block: int
+    kind: str
+    structure: str
+    reason: str
+    cache_blocks: range | None
+
+
+def report_corruption(corruption):
+    print(f"Corrupted metadata: {format_corruption(corruption)}", file=sys.stderr)
+
+
+def format_corruption(corruption):
+    return f"block {corruption.block}: {describe_corruption(corruption)}"
+
+
+def describe_corruption(corruption):
+    text = f"{corruption.kind} of {corruption.structure}: {corruption.reason}"
+    if corruption.cache_blocks:
+        text += f" (cache blocks {format_range(corruption.cache_blocks)})"
+    return text
+
+
+def format_range(r):
+    return f"{r.start}" if len(r) == 1 else f"{r.start}-{r.stop - 1}"
+
+
+class MetadataDevice:
+    """Provides access to the structures stored on a binary metadata device.
+    Every block is checked against its checksum and block number.
+    Problems are passed to ``on_corruption``,
+    and blocks which cannot be decoded are skipped.
+    """
+
+    def __init__(self, fd, on_corruption=report_corruption):
+        self.__fd = fd
+        self.__on_corruption = on_corruption
+        self.__nr_cache_blocks = None
+
+    def read_block(self, block):
+        data = os.pread(self.__fd, METADATA_BLOCK_SIZE, block * METADATA_BLOCK_SIZE)
+        if len(data) != METADATA_BLOCK_SIZE:
+            sys.exit(f"Incomplete metadata block: {block}")
+        return data
+
+    def iter_blocks(self):
+        """Yields all blocks of the device together with their location."""
+        chunk_blocks = 256
+        block = 0
+        while True:
+            data = os.pread(self.__fd, chunk_blocks * METADATA_BLOCK_SIZE, block * METADATA_BLOCK_SIZE)
+            for offset in range(0, len(data) - METADATA_BLOCK_SIZE + 1, METADATA_BLOCK_SIZE):
+                yield block, data[offset:offset + METADATA_BLOCK_SIZE]
+                block += 1
+            if len(data) < chunk_blocks * METADATA_BLOCK_SIZE:
+                return
+
+    def read_intact_superblock(self):
+        """Returns the superblock, or ``None`` if it is damaged."""
+        data = self.read_block(SUPERBLOCK_LOCATION)
+        superblock = unpack_superblock(data)
+        if superblock.magic != SUPERBLOCK_MAGIC or superblock.version not in (1, 2) \
+                or superblock.blocknr != SUPERBLOCK_LOCATION \
+                or block_checksum(data, SUPERBLOCK_CSUM_XOR) != superblock.csum:
+            return None
+        self.__nr_cache_blocks = superblock.cache_blocks
+        return superblock
+
+    def read_superblock(self, on_invalid=None):
+        """Returns the superblock.
+        A superblock which cannot be used at all terminates the program,
+        unless ``on_invalid`` is given, which is called with the kind of problem and a message instead.
+        ``None`` is returned in this case.
+        """
+        def invalid(kind, message):
+            if on_invalid is None:
+                sys.exit(f"Invalid metadata: {message}")
+            on_invalid(kind, message)
+
+        data = os.pread(self.__fd, METADATA_BLOCK_SIZE, SUPERBLOCK_LOCATION * METADATA_BLOCK_SIZE)
+        if len(data) != METADATA_BLOCK_SIZE:
+            invalid("incomplete-superblock", "superblock beyond end of device")
+            return None
+        superblock = unpack_superblock(data)
+        if superblock.magic != SUPERBLOCK_MAGIC:
+            invalid("invalid-superblock", "superblock magic does not match")
+            return None
+        self.__nr_cache_blocks = superblock.cache_blocks
+        self.__verify(data, SUPERBLOCK_LOCATION, superblock.blocknr, SUPERBLOCK_CSUM_XOR,
+                "superblock", "metadata", range(superblock.cache_blocks))
+        if superblock.version not in (1, 2):
+            invalid("unsupported-version", f"unsupported version {superblock.version}")
+            return None
+        return superblock
+
+    def iter_btree(self, root, structure, covers=None):
+        """Yields all key-value pairs of the btree at ``root`` in ascending key order.
+        ``covers`` maps a range of keys to the range of cache blocks depending on them.
+        """
+        yield from self.__iter_btree(root, structure, covers, set(), 0, None)
+
+    def __iter_btree(self, block, structure, covers, visited, first_key, end_key):
+        cache_blocks = self.__cache_blocks(covers, first_key, end_key)
+        if block in visited:
+            self.__corrupted(block, "btree node", structure, "referenced more than once", cache_blocks)
+            return
+        visited.add(block)
+        data = self.__read(block, "btree node", structure, cache_blocks)
+        if data is None:
+            return
+        _, flags, blocknr, nr_entries, max_entries, value_size, _ = \
+            struct.unpack_from(BTREE_NODE_HEADER_FORMAT, data)
+        self.__verify(data, block, blocknr, BTREE_CSUM_XOR, "btree node", structure, cache_blocks)
+        if BTREE_NODE_HEADER_SIZE + max_entries * (8 + value_size) > METADATA_BLOCK_SIZE \
+                or nr_entries > max_entries:
+            self.__corrupted(block, "btree node", structure, "malformed header", cache_blocks)
+            return
+        keys = struct.unpack_from(f"<{nr_entries}Q", data, BTREE_NODE_HEADER_SIZE)
+        if any(a >= b for a, b in zip(keys, keys[1:])):
+            self.__corrupted(block, "btree node", structure, "keys out of order", cache_blocks)
+        values_offset = BTREE_NODE_HEADER_SIZE + 8 * max_entries
+        if flags & BTREE_INTERNAL_NODE:
+            if value_size != 8:
+                self.__corrupted(block, "btree node", structure, "malformed header", cache_blocks)
+                return
+            children = struct.unpack_from(f"<{nr_entries}Q", data, values_offset)
+            for i, child in enumerate(children):
+                child_end_key = keys[i + 1] if i + 1 < nr_entries else end_key
+                yield from self.__iter_btree(child, structure, covers, visited, keys[i], child_end_key)
+        elif flags & BTREE_LEAF_NODE:
+            for i, key in enumerate(keys):
+                offset = values_offset + i * value_size
+                yield key, data[offset:offset + value_size]
+        else:
+            self.__corrupted(block, "btree node", structure, "unknown node type", cache_blocks)
+
+    def iter_array(self, root, value_size, structure, kind="array block", bits_per_entry=1, with_blocks=False,
+                   raw=False):
+        """Yields all index-value pairs of the array at ``root``.
+        Values with a size of 8 bytes are returned as integers, unless ``raw`` is true.
+        Each entry is considered to cover ``bits_per_entry`` cache blocks,
+        or none if ``bits_per_entry`` is ``None``.
+        If ``with_blocks`` is true, the location of the array block is yielded as third value.
+        """
+        max_entries = (METADATA_BLOCK_SIZE - ARRAY_BLOCK_HEADER_SIZE) // value_size
+        if bits_per_entry is None:
+            covers = None
+        else:
+            def covers(first_key, end_key):
+                entry_bits = max_entries * bits_per_entry
+                return first_key * entry_bits, None if end_key is None else end_key * entry_bits
+        visited = set()
+        for array_index, value in self.iter_btree(root, structure, covers):
+            block = struct.unpack("<Q", value)[0]
+            cache_blocks = self.__cache_blocks(covers, array_index, array_index + 1)
+            if block in visited:
+                self.__corrupted(block, kind, structure, "referenced more than once", cache_blocks)
+                continue
+            visited.add(block)
+            data = self.__read(block, kind, structure, cache_blocks)
+            if data is None:
+                continue
+            _, block_max_entries, nr_entries, block_value_size, blocknr = \
+                struct.unpack_from(ARRAY_BLOCK_HEADER_FORMAT, data)
+            self.__verify(data, block, blocknr, ARRAY_CSUM_XOR, kind, structure, cache_blocks)
+            if block_value_size != value_size or block_max_entries != max_entries \
+                    or nr_entries > max_entries:
+                self.__corrupted(block, kind, structure, "malformed header", cache_blocks)
+                continue
+            index = array_index * max_entries
+            for i in range(nr_entries):
+                offset = ARRAY_BLOCK_HEADER_SIZE + i * value_size
+                value = data[offset:offset + value_size]
+                if value_size == 8 and not raw:
+                    value = struct.unpack("<Q", value)[0]
+                yield (index + i, value, block) if with_blocks else (index + i, value)
+
+    def read_bitset(self, root, nr_bits, structure, cache_blocks=True):
+        """Returns the words of the bitset at ``root`` as a list of integers.
+        ``cache_blocks`` specifies whether the bits correspond to cache blocks.
+        """
+        words = [0] * ((nr_bits + 63) // 64)
+        for index, word in self.iter_array(root, 8, structure, "bitset block", 64 if cache_blocks else None):
+            if index < len(words):
+                words[index] = word
+        return words
+
+    def check_space_map(self, superblock):
+        """Checks the blocks of the metadata space map."""
+        structure = "metadata space map"
+        nr_blocks, _, bitmap_root, ref_count_root = \
+            struct.unpack_from(SPACE_MAP_ROOT_FORMAT, superblock.metadata_space_map_root)
+        data = self.__read(bitmap_root, "index block", structure, None)
+        if data is None:
+            return
+        blocknr = struct.unpack_from("<Q", data, 8)[0]
+        self.__verify(data, bitmap_root, blocknr, INDEX_CSUM_XOR, "index block", structure, None)
+        nr_bitmaps = (nr_blocks + SPACE_MAP_ENTRIES_PER_BITMAP - 1) // SPACE_MAP_ENTRIES_PER_BITMAP
+        if nr_bitmaps > SPACE_MAP_MAX_BITMAPS:
+            self.__corrupted(bitmap_root, "index block", structure, "too many bitmaps", None)
+            return
+        for i in range(nr_bitmaps):
+            bitmap, _, _ = struct.unpack_from(SPACE_MAP_INDEX_ENTRY_FORMAT, data, 16 + 16 * i)
+            bitmap_data = self.__read(bitmap, "bitmap block", structure, None)
+            if bitmap_data is not None:
+                blocknr = struct.unpack_from("<Q", bitmap_data, 8)[0]
+                self.__verify(bitmap_data, bitmap, blocknr, BITMAP_CSUM_XOR, "bitmap block", structure, None)
+        for _ in self.iter_btree(ref_count_root, structure):
+            pass
+
+    def read_space_map(self, superblock):
+        """Returns the bitmap blocks of the metadata space map.
+        Unreadable bitmaps are returned as ``None``.
+        """
+        structure = "metadata space map"
+        nr_blocks, _, bitmap_root, _ = \
+            struct.unpack_from(SPACE_MAP_ROOT_FORMAT, superblock.metadata_space_map_root)
+        data = self.__read(bitmap_root, "index block", structure, None)
+        nr_bitmaps = (nr_blocks + SPACE_MAP_ENTRIES_PER_BITMAP - 1) // SPACE_MAP_ENTRIES_PER_BITMAP
+        if data is None or nr_bitmaps > SPACE_MAP_MAX_BITMAPS:
+            return []
+        bitmaps = []
+        for i in range(nr_bitmaps):
+            bitmap, _, _ = struct.unpack_from(SPACE_MAP_INDEX_ENTRY_FORMAT, data, 16 + 16 * i)
+            bitmaps.append(self.__read(bitmap, "bitmap block", structure, None))
+        return bitmaps
+
+    def __read(self, block, kind, structure, cache_blocks):
+        data = os.pread(self.__fd, METADATA_BLOCK_SIZE, block * METADATA_BLOCK_SIZE)
+        if len(data) != METADATA_BLOCK_SIZE:
+            self.__corrupted(block, kind, structure, "beyond end of device", cache_blocks)
+            return None
+        return data
+
+    def __verify(self, data, block, blocknr, csum_xor, kind, structure, cache_blocks):
+        if block_checksum(data, csum_xor) != struct.unpack_from("<I", data)[0]:
+            self.__corrupted(block, kind, structure, "checksum mismatch", cache_blocks)
+        if blocknr != block:
+            self.__corrupted(block, kind, structure, f"claims to be block {blocknr}", cache_blocks)
+
+    def __corrupted(self, block, kind, structure, reason, cache_blocks):
+        self.__on_corruption(Corruption(
+            block=block,
+            kind=kind,
+            structure=structure,
+            reason=reason,
+            cache_blocks=cache_blocks,
+        ))
+
+    def __cache_blocks(self, covers, first_key, end_key):
+        if covers is None:
+            return None
+        first, end = covers(first_key, end_key)
+        limit = self.__nr_cache_blocks
+        if limit is None:
+            return range(first, first if end is None else end)
+        if end is None or end > limit:
+            end = limit
+        return range(min(first, limit), end)
+
+
+class MetadataWriter:
+    """Creates the structures of a binary metadata device in memory.
+    Blocks are allocated sequentially after the superblock.
+    """
+
+    def __init__(self, nr_blocks):
+        self.__nr_blocks = nr_blocks
+        self.__blocks = {}
+        self.__next_block = SUPERBLOCK_LOCATION + 1
+
+    def allocate(self):
+        block = self.__next_block
+        if block >= self.__nr_blocks:
+            sys.exit(f"Metadata device is too small: more than {self.__nr_blocks} blocks required")
+        self.__next_block += 1
+        return block
+
+    def write_block(self, block, data, csum_xor):
+        data = data.ljust(METADATA_BLOCK_SIZE, b"\0")
+        self.__blocks[block] = struct.pack("<I", block_checksum(data, csum_xor)) + data[4:]
+
+    def write_btree(self, items, value_size):
+        """Writes a btree of the given key-value pairs, where the values are already packed,
+        and returns the location of its root.
+        """
+        level = self.__write_btree_nodes(items, BTREE_LEAF_NODE, value_size)
+        while len(level) > 1:
+            items = [(key, struct.pack("<Q", block)) for key, block in level]
+            level = self.__write_btree_nodes(items, BTREE_INTERNAL_NODE, 8)
+        return level[0][1]
+
+    def __write_btree_nodes(self, items, flags, value_size):
+        max_entries = btree_max_entries(value_size)
+        nodes = []
+        for start in range(0, max(len(items), 1), max_entries):
+            chunk = items[start:start + max_entries]
+            block = self.allocate()
+            keys = [key for key, _ in chunk]
+            header = struct.pack(BTREE_NODE_HEADER_FORMAT, 0, flags, block, len(chunk), max_entries, value_size, 0)
+            data = header + struct.pack(f"<{max_entries}Q", *keys, *[0] * (max_entries - len(keys))) \
+                + b"".join(value for _, value in chunk)
+            self.write_block(block, data, BTREE_CSUM_XOR)
+            nodes.append((keys[0] if keys else 0, block))
+        return nodes
+
+    def write_array(self, values, value_size):
+        """Writes an array of the given packed values and returns the location of its root."""
+        max_entries = (METADATA_BLOCK_SIZE - ARRAY_BLOCK_HEADER_SIZE) // value_size
+        items = []
+        for index, start in enumerate(range(0, len(values), max_entries)):
+            chunk = values[start:start + max_entries]
+            block = self.allocate()
+            header = struct.pack(ARRAY_BLOCK_HEADER_FORMAT, 0, max_entries, len(chunk), value_size, block)
+            self.write_block(block, header + b"".join(chunk), ARRAY_CSUM_XOR)
+            items.append((index, struct.pack("<Q", block)))
+        return self.write_btree(items, 8)
+
+    def write_bitset(self, bits, nr_bits):
+        """Writes a bitset containing the given bits and returns the location of its root."""
+        words = [0] * ((nr_bits + 63) // 64)
+        for bit in bits:
+            words[bit // 64] |= 1 << (bit % 64)
+        return self.write_array([struct.pack("<Q", word) for word in words], 8)
+
+    def write_space_map(self):
+        """Writes the metadata space map, in which all allocated blocks have a reference count of one,
+        and returns its root.
+        """
+        nr_bitmaps = (self.__nr_blocks + SPACE_MAP_ENTRIES_PER_BITMAP - 1) // SPACE_MAP_ENTRIES_PER_BITMAP
+        if nr_bitmaps > SPACE_MAP_MAX_BITMAPS:
+            sys.exit("Metadata device is too large")
+        ref_count_root = self.write_btree([], 4)
+        index_block = self.allocate()
+        bitmap_blocks = [self.allocate() for _ in range(nr_bitmaps)]
+        nr_allocated = self.__next_block
+
+        index_entries = []
+        for i, bitmap_block in enumerate(bitmap_blocks):
+            first = i * SPACE_MAP_ENTRIES_PER_BITMAP
+            allocated = max(0, min(nr_allocated - first, SPACE_MAP_ENTRIES_PER_BITMAP))
+            bitmap = bytearray(METADATA_BLOCK_SIZE - 16)
+            for entry in range(allocated):
+                bitmap[entry // 4] |= 1 << (entry % 4 * 2 + 1)
+            self.write_block(bitmap_block, struct.pack("<IIQ", 0, 0, bitmap_block) + bitmap, BITMAP_CSUM_XOR)
+            index_entries.append(struct.pack(SPACE_MAP_INDEX_ENTRY_FORMAT,
+                    bitmap_block, SPACE_MAP_ENTRIES_PER_BITMAP - allocated, allocated))
+        self.write_block(index_block, struct.pack("<IIQ", 0, 0, index_block) + b"".join(index_entries),
+                INDEX_CSUM_XOR)
+        return struct.pack(SPACE_MAP_ROOT_FORMAT, self.__nr_blocks, nr_allocated, index_block, ref_count_root)
+
+    def write_to(self, fd):
+        for block, data in sorted(self.__blocks.items()):
+            os.pwrite(fd, data, block * METADATA_BLOCK_SIZE)
+
+
+def write_metadata(path, entries, *, block_size, nr_cache_blocks, policy="smq", policy_version=(2, 0, 0),
+        hint_size=4, version=2):
+    """Writes a fresh metadata device containing the given entries.
+    All blocks are marked as allocated exactly once in the space map,
+    and the superblock is flagged as cleanly shut down.
+    """
+    mappings = [0] * nr_cache_blocks
+    hints = [bytes(hint_size)] * nr_cache_blocks
+    dirty_blocks = []
+    for entry in entries:
+        if entry.block_sectors != block_size:
+            sys.exit(f"Block size of cache block {entry.cache_block} does not match {block_size}")
+        if entry.cache_block >= nr_cache_blocks:
+            sys.exit(f"Cache block {entry.cache_block} beyond {nr_cache_blocks} cache blocks")
+        if mappings[entry.cache_block]:
+            sys.exit(f"Cache block {entry.cache_block} is mapped twice")
+        flags = MAPPING_VALID
+        if entry.dirty:
+            dirty_blocks.append(entry.cache_block)
+            if version == 1:
+                flags |= MAPPING_DIRTY
+        mappings[entry.cache_block] = entry.origin_block << 16 | flags
+        if entry.hint is not None:
+            if len(entry.hint) != hint_size:
+                sys.exit(f"Hint of cache block {entry.cache_block} does not have {hint_size} bytes")
+            hints[entry.cache_block] = entry.hint
+
+    if not Path(path).exists():
+        sys.exit(f"{path} does not exist, the metadata device or image has to be created beforehand")
+    with dev_open(path, write=True) as fd:
+        nr_blocks = min(os.lseek(fd, 0, os.SEEK_END) // METADATA_BLOCK_SIZE,
+                SPACE_MAP_MAX_BITMAPS * SPACE_MAP_ENTRIES_PER_BITMAP)
+        writer = MetadataWriter(nr_blocks)
+        mapping_root = writer.write_array([struct.pack("<Q", value) for value in mappings], 8)
+        hint_root = writer.write_array(hints, hint_size) if hint_size else 0
+        discard_root = writer.write_bitset([], 0)
+        dirty_root = writer.write_bitset(dirty_blocks, nr_cache_blocks) if version >= 2 else 0
+        space_map_root = writer.write_space_map()
+        superblock = struct.pack(
+            SUPERBLOCK_FORMAT,
+            0,                              # csum
+            SUPERBLOCK_CLEAN_SHUTDOWN,      # flags
+            SUPERBLOCK_LOCATION,            # blocknr
+            bytes(16),                      # uuid
+            SUPERBLOCK_MAGIC,               # magic
+            version,                        # version
+            policy.encode("ascii"),         # policy_name
+            hint_size,                      # policy_hint_size
+            space_map_root,                 # metadata_space_map_root
+            mapping_root,                   # mapping_root
+            hint_root,                      # hint_root
+            discard_root,                   # discard_root
+            0,                              # discard_block_size
+            0,                              # discard_nr_blocks
+            block_size,                     # data_block_size
+            METADATA_BLOCK_SIZE // 512,     # metadata_block_size
+            nr_cache_blocks,                # cache_blocks
+            0, 0, 0,                        # compat_flags, compat_ro_flags, incompat_flags
+            0, 0, 0, 0,                     # read_hits, read_misses, write_hits, write_misses
+            *policy_version,                # policy_version
+            dirty_root,                     # dirty_root
+        )
+        writer.write_block(SUPERBLOCK_LOCATION, superblock, SUPERBLOCK_CSUM_XOR)
+        writer.write_to(fd)
+        os.fsync(fd)
+
+
+def block_checksum(data, csum_xor):
+    """Calculates the checksum of a metadata block like ``dm_bm_checksum``.
+    The first four bytes containing the checksum itself are excluded.
+    """
+    return crc32c(bytes(memoryview(data)[4:])) ^ 0xffffffff ^ csum_xor
+
+
+def crc32c_python(data):
+    """Calculates the CRC-32C of the data, eight bytes at a time."""
+    t0, t1, t2, t3, t4, t5, t6, t7 = CRC32C_TABLES
+    data = bytes(data)
+    aligned = len(data) // 8 * 8
+    crc = 0xffffffff
+    for low, high in struct.iter_unpack("<II", data[:aligned]):
+        low ^= crc
+        crc = (t7[low & 0xff] ^ t6[(low >> 8) & 0xff] ^ t5[(low >> 16) & 0xff] ^ t4[low >> 24]
+               ^ t3[high & 0xff] ^ t2[(high >> 8) & 0xff] ^ t1[(high >> 16) & 0xff] ^ t0[high >> 24])
+    for byte in data[aligned:]:
+        crc = t0[(crc ^ byte) & 0xff] ^ (crc >> 8)
+    return crc ^ 0xffffffff
+
+
+def make_crc32c_tables():
+    """Returns the tables to calculate the CRC-32C of eight bytes at once.
+    The first table is the usual one for a single byte.
+    """
+    table = []
+    for i in range(256):
+        crc = i
+        for _ in range(8):
+            crc = (crc >> 1) ^ 0x82f63b78 if crc & 1 else crc >> 1
+        table.append(crc)
+    tables = [table]
+    for _ in range(7):
+        tables.append([(crc >> 8) ^ table[crc & 0xff] for crc in tables[-1]])
+    return tables
+
+
+CRC32C_TABLES = make_crc32c_tables()
+
+# Every block of the metadata device is checksummed, so a native implementation is used if available
+try:
+    from google_crc32c import value as crc32c
+except ImportError:
+    try:
+        from crc32c import crc32c
+    except ImportError:
+        crc32c = crc32c_python
+
+
+def space_map_allocated(bitmaps, block):
+    """Returns whether the given block is in use according to the space map."""
+    index, entry = divmod(block, SPACE_MAP_ENTRIES_PER_BITMAP)
+    if index >= len(bitmaps) or bitmaps[index] is None:
+        return False
+    byte = bitmaps[index][16 + entry // 4]
+    return byte >> (entry % 4 * 2) & 3 != 0
+
+
+def is_mapping_value(value):
+    """Returns whether the value could be an entry of the mapping array.
+    Unmapped entries are zero, mapped entries have the valid flag and no unknown flags.
+    """
+    return value == 0 or value & 0xffff & ~MAPPING_DIRTY == MAPPING_VALID
+
+
+def format_hint(hint):
+    """Formats a hint as integer, which is how mq and smq store the hotness of a block."""
+    if hint is None:
+        return "none"
+    if len(hint) in (1, 2, 4, 8):
+        return str(int.from_bytes(hint, "little"))
+    return hint.hex()
+
+
+def unpack_mapping(value):
+    """Returns the origin block and the flags of a mapping."""
+    return value >> 16, value & 0xffff
+
+
+def test_bit(words, bit):
+    return bool(words[bit // 64] >> (bit % 64) & 1)
+
+
+@contextmanager
+def dev_open(path, *, write=False):
+    fd = os.open(path, os.O_RDWR if write else os.O_RDONLY)
+    try:
+        yield fd
+    finally:
+        os.close(fd)
+
+
+def parse_bool(string):
+    if string == "true":
+        return True
+    elif string == "false":
+        return False
+    else:
+        raise ValueError("invalid boolean", string)
+
+
+if __name__ == "__main__":
+    main()
@@ -28,18 +28,156 @@ This option uses the ``linear`` target [1]_ of device mapper. ::
     $ ./cache_verify.py --emulate writeback metadata.xml /dev/mapper/cache /dev/mapper/data
     $ ls /dev/mapper/writeback
 
+Instead of an XML file created by ``cache_dump``,
+the metadata device can also be passed directly.
+In this case, the binary metadata is decoded by this script. ::
+
+    $ ./cache_verify.py /dev/mapper/cachemeta /dev/mapper/cache /dev/mapper/data
+
+XML files can also be compressed with gzip, xz or zstd,
+or be read from stdin. ::
+
+    $ cache_dump /dev/mapper/cachemeta | ./cache_verify.py - /dev/mapper/cache /dev/mapper/data
+
+For lvmcache, the hidden sub-volumes can be accessed without activating the volume group,
+by reading the LVM metadata from a physical volume or a backup file,
+see ``lvm_metadata.py``. ::
+
+    $ ./cache_verify.py --lvm /dev/sdb vg/cpool_cmeta vg/cpool_cdata vg/data_corig
+
+The name of the cached logical volume can be given instead,
+which also works for caches using a cachevol. ::
+
+    $ ./cache_verify.py --lvm /dev/sdb vg/data vg/data vg/data
+
+An activated cachevol, like ``/dev/mapper/vg-fast_cvol``, can be passed as metadata and cache device
+with ``--cachevol``. The metadata region at its beginning is sized by the space map of the metadata,
+and the data region directly follows it, as lvmcache lays it out;
+``--cachevol-data-start`` gives another start in sectors, e.g. the ``data_start`` of the LVM metadata. ::
+
+    $ ./cache_verify.py --cachevol /dev/mapper/vg-fast_cvol /dev/mapper/vg-fast_cvol /dev/mapper/vg-data_corig
+
+Verifying reads the blocks with several threads, keeping up to ``--queue-depth``
+comparisons in flight. The output is in the order of the mappings regardless.
+
+With ``--detail``, verify lists the differing sectors or filesystem blocks
+of each mismatched block, in units of ``--detail-unit`` bytes, which must divide the block size,
+followed by a hex diff of at most ``--hex-bytes`` bytes from the first difference. ::
+
+    $ ./cache_verify.py --detail --detail-unit 512 metadata.xml /dev/mapper/cache /dev/mapper/data
+
+With ``--classify``, mismatched and dirty blocks are classified:
+``cache-zero`` (e.g. after a TRIM of the cache device), ``origin-zero``,
+``partially-equal``, ``identical-to-other-origin-block``,
+``cache-high-entropy`` and ``origin-high-entropy`` (encrypted or compressed data).
+From the classification, verify suggests which copy of the block to trust.
+Cache blocks are compared with the neighbouring origin blocks
+and with all origin blocks read before.
+
+With ``--report``, verify writes the status of each mapping as JSON Lines or CSV:
+``match``, ``mismatch``, ``duplicate-target``, ``read-error``,
+``skipped-dirty`` or ``skipped-discarded``.
+A summary with the counts, the bytes compared and the elapsed time is printed to stderr,
+and appended as last record to JSON Lines. ::
+
+    $ ./cache_verify.py --report report.csv --report-format csv metadata.xml /dev/mapper/cache /dev/mapper/data
+
+Long runs of verify and ``--writeback`` can record their progress with ``--checkpoint``.
+After an interruption, ``--resume`` continues after the last processed mapping,
+unless the metadata, the devices or the relevant options have changed.
+The report of verify is continued as well, so it must be given again. ::
+
+    $ ./cache_verify.py --writeback --checkpoint writeback.json metadata.xml /dev/mapper/cache /dev/mapper/data
+    $ ./cache_verify.py --writeback --checkpoint writeback.json --resume metadata.xml /dev/mapper/cache /dev/mapper/data
+
+Failed reads are retried and then narrowed down to single sectors.
+``--bad-sectors`` decides what happens to a mapping with unreadable sectors:
+``fail`` reports it as read error and skips its writeback,
+``zero`` uses zeros instead, and ``skip`` leaves the sectors out of the comparison and the writeback.
+Either way, the mapping is marked, and the unreadable ranges can be recorded
+in mapfiles of GNU ddrescue with ``--cache-error-map`` and ``--origin-error-map``. ::
+
+    $ ./cache_verify.py --writeback --bad-sectors skip --cache-error-map cache.map metadata.xml /dev/mapper/cache /dev/mapper/data
+
+When working on images created by GNU ddrescue, their mapfiles can be passed
+with ``--cache-mapfile`` and ``--origin-mapfile``.
+Ranges which have not been rescued are then treated as unknown instead of as zeros:
+verify leaves them out and reports the affected mappings as ``unrecovered``,
+``--writeback`` handles them like unreadable sectors,
+and ``--table`` and ``--emulate`` map them to the ``error`` target. ::
+
+    $ ./cache_verify.py --cache-mapfile cache.map --origin-mapfile data.map metadata.xml cache.img data.img
+
+If the metadata device is damaged,
+``--salvage`` recovers the mappings by scanning the whole metadata device.
+All salvaged mappings are considered dirty. ::
+
+    $ ./cache_verify.py --salvage --all --block-size 128 /dev/mapper/cachemeta /dev/mapper/cache /dev/mapper/data
+
+If the last commit of the metadata is broken,
+an older generation of the metadata can be used instead.
+The available generations can be listed with ``cache_metadata.py history``. ::
+
+    $ ./cache_metadata.py history /dev/mapper/cachemeta
+    $ ./cache_verify.py --mapping-root 1234 --dirty-root 1240 /dev/mapper/cachemeta /dev/mapper/cache /dev/mapper/data
+
+The discard bitset of the current generation does not belong to older mappings,
+so with ``--mapping-root`` or ``--dirty-root``, no ranges are considered discarded
+unless ``--discard-root`` selects a generation of the discard bitset as well.
+
+If the cache was not shut down cleanly, all mapped blocks are considered dirty,
+like the kernel does.
+In this case, ``--writeback`` and ``--emulate`` compare the blocks
+to decide which of them have actually changed.
+
+Ranges recorded in the discard bitset of the metadata are not compared
+and not written back.
+With ``--zero-discards``, ``--table`` and ``--emulate`` map them to the ``zero`` target.
+With ``--punch-discards``, ``--writeback`` discards them on the origin device.
+
+With ``--undo-journal``, ``--writeback`` saves the original contents of each origin block
+before overwriting it, so that ``undo_journal.py rollback`` can restore the origin device.
+The journal is continued with ``--resume``. ::
+
+    $ ./cache_verify.py --writeback --undo-journal undo.bin metadata.xml /dev/mapper/cache /dev/mapper/data
+    $ ./undo_journal.py rollback undo.bin /dev/mapper/data
+
+With ``--dry-run``, ``--writeback`` only lists what it would do:
+the mappings to copy with the bytes and origin sectors to write,
+the ``no-op`` mappings whose cache and origin blocks are already equal,
+and the ``read-error`` mappings whose cache block cannot be read completely.
+``--plan`` saves this plan as JSON Lines, with the digests of the blocks read.
+A later ``--writeback`` with ``--plan`` and the same inputs and options executes exactly this plan,
+and leaves out the mappings whose blocks have changed since. ::
+
+    $ ./cache_verify.py --writeback --dry-run --plan plan.jsonl metadata.xml /dev/mapper/cache /dev/mapper/data
+    $ ./cache_verify.py --writeback --plan plan.jsonl metadata.xml /dev/mapper/cache /dev/mapper/data
+
+With ``--files``, the mismatched and dirty blocks found by verify,
+or the dirty blocks of ``--table`` and ``--emulate``, are mapped to the files
+and filesystem structures of the origin filesystem, see ``filesystem_files.py``.
+With ``--emulate``, the filesystem is read from the emulated device,
+as it looks like after the writeback; ``--files-device`` reads it from another device. ::
+
+    $ ./cache_verify.py --files metadata.xml /dev/mapper/cache /dev/mapper/data
+
 .. [1] Documentation of the ``linear`` target of device-mapper:
    https://www.kernel.org/doc/Documentation/device-mapper/linear.txt
 """
 
-import heapq, os, stat, sys
+import bisect, cache_metadata, csv, ctypes, ddrescue_mapfile, fcntl, filesystem_files, hashlib, heapq, json, lvm_metadata, math, os, stat, struct, sys, time, undo_journal
 from argparse import ArgumentParser
-from contextlib import contextmanager
-from dataclasses import dataclass
+from collections import Counter, deque
+from concurrent.futures import ThreadPoolExecutor
+from contextlib import contextmanager, ExitStack
+from dataclasses import dataclass, replace
 from pathlib import Path
 from subprocess import Popen, PIPE
-from xml.sax import parse
-from xml.sax.handler import ContentHandler
+
+
+BLKDISCARD = 0x1277
+FALLOC_FL_KEEP_SIZE = 0x01
+FALLOC_FL_PUNCH_HOLE = 0x02
 
 
 def main():
@@ -51,102 +189,701 @@ def main():
     argparser.add_argument("--emulate", metavar="DEVICE_NAME")
     argparser.add_argument("--table", action="store_true")
     argparser.add_argument("--writeback", action="store_true")
+    argparser.add_argument("--salvage", action="store_true",
+            help="Recover mappings by scanning the whole metadata device")
+    argparser.add_argument("--block-size", type=int,
+            help="Size of cache blocks in sectors, required by --salvage if the superblock is damaged")
+    argparser.add_argument("--mapping-root", type=int, metavar="BLOCK",
+            help="Use an older generation of the mapping array, see `cache_metadata.py history`")
+    argparser.add_argument("--dirty-root", type=int, metavar="BLOCK",
+            help="Use an older generation of the dirty bitset, see `cache_metadata.py history`")
+    argparser.add_argument("--discard-root", type=int, metavar="BLOCK",
+            help="Use an older generation of the discard bitset, see `cache_metadata.py history`;"
+                 " with --mapping-root or --dirty-root, no ranges are discarded without it")
+    argparser.add_argument("--hints", action="store_true",
+            help="Print the policy hint of each mapping")
+    argparser.add_argument("--zero-discards", action="store_true",
+            help="Map discarded ranges of the origin device to the zero target")
+    argparser.add_argument("--punch-discards", action="store_true",
+            help="Discard the discarded ranges of the origin device on writeback")
+    argparser.add_argument("--queue-depth", type=int, default=32,
+            help="Number of blocks compared concurrently by verify")
+    argparser.add_argument("--detail", action="store_true",
+            help="Describe the differences within mismatched blocks")
+    argparser.add_argument("--detail-unit", type=int, default=4096, metavar="BYTES",
+            help="Size of the units reported by --detail, e.g. the sector or filesystem block size;"
+                 " must divide the block size")
+    argparser.add_argument("--hex-bytes", type=int, default=64,
+            help="Number of bytes shown in the hex diff of --detail")
+    argparser.add_argument("--classify", action="store_true",
+            help="Classify mismatched and dirty blocks to tell which copy is more trustworthy")
+    argparser.add_argument("--classify-neighbours", type=int, default=2, metavar="BLOCKS",
+            help="Number of neighbouring origin blocks compared with a mismatched cache block")
+    argparser.add_argument("--report", type=Path, metavar="FILE",
+            help="Write the status of each mapping to FILE, or to stdout for -")
+    argparser.add_argument("--report-format", choices=["jsonl", "csv"], default="jsonl")
+    argparser.add_argument("--checkpoint", type=Path, metavar="FILE",
+            help="Record the progress of verify or --writeback in FILE")
+    argparser.add_argument("--checkpoint-interval", type=float, default=10, metavar="SECONDS")
+    argparser.add_argument("--resume", action="store_true",
+            help="Continue from the position recorded by --checkpoint")
+    argparser.add_argument("--retries", type=int, default=2,
+            help="Number of retries of failed reads")
+    argparser.add_argument("--bad-sectors", choices=["fail", "zero", "skip"], default="fail",
+            help="Handling of unreadable sectors: fail the mapping, use zeros or leave them out")
+    argparser.add_argument("--cache-error-map", type=Path, metavar="FILE",
+            help="Write the unreadable ranges of the cache device as ddrescue mapfile")
+    argparser.add_argument("--origin-error-map", type=Path, metavar="FILE",
+            help="Write the unreadable ranges of the origin device as ddrescue mapfile")
+    argparser.add_argument("--cache-mapfile", type=Path, metavar="FILE",
+            help="ddrescue mapfile of an imaged cache device")
+    argparser.add_argument("--origin-mapfile", type=Path, metavar="FILE",
+            help="ddrescue mapfile of an imaged origin device")
+    argparser.add_argument("--lvm", type=Path, metavar="SOURCE",
+            help="Physical volume or LVM backup file; the devices are given as logical volumes")
+    argparser.add_argument("--pv-device", type=Path, action="append", default=[],
+            help="Device of another physical volume of the volume group, used by --lvm")
+    argparser.add_argument("--cachevol", action="store_true",
+            help="The metadata and the cache device are an activated cachevol holding both regions")
+    argparser.add_argument("--cachevol-data-start", type=int, metavar="SECTOR",
+            help="Start of the data region of the cachevol, directly after the metadata region by default")
+    argparser.add_argument("--files", action="store_true",
+            help="List the files of the origin filesystem affected by mismatched and dirty blocks")
+    argparser.add_argument("--files-device", type=Path, metavar="DEVICE",
+            help="Read the filesystem for --files from DEVICE instead of the origin device")
+    argparser.add_argument("--undo-journal", type=Path, metavar="FILE",
+            help="Save the original contents of the origin blocks overwritten by --writeback to FILE")
+    argparser.add_argument("--dry-run", action="store_true",
+            help="List what --writeback would write without writing to the origin device")
+    argparser.add_argument("--plan", type=Path, metavar="FILE",
+            help="Save the plan of --dry-run to FILE, or execute a saved plan with --writeback")
     args = argparser.parse_args()
 
     if len(list(filter(bool, [args.emulate, args.table, args.writeback]))) > 1:
         sys.exit("The options --emulate, --table and --writeback cannot used together")
+    if args.queue_depth < 1:
+        sys.exit("The queue depth must be at least 1")
+    if args.detail_unit < 512 or args.detail_unit % 512 != 0:
+        sys.exit("The detail unit must be a multiple of 512 bytes")
+    if args.resume and args.checkpoint is None:
+        sys.exit("The option --resume requires --checkpoint")
+    if args.checkpoint is not None and (args.emulate or args.table):
+        sys.exit("The option --checkpoint can only be used with verify and --writeback")
+    if args.files and args.writeback:
+        sys.exit("The option --files cannot be used with --writeback")
+    if args.files and args.report is not None and cache_metadata.is_stdin(args.report):
+        sys.exit("The option --files cannot be used with a report written to stdout")
+    if args.undo_journal is not None and not args.writeback:
+        sys.exit("The option --undo-journal can only be used with --writeback")
+    if (args.dry_run or args.plan is not None) and not args.writeback:
+        sys.exit("The options --dry-run and --plan can only be used with --writeback")
+    if args.dry_run and (args.checkpoint is not None or args.undo_journal is not None):
+        sys.exit("The options --checkpoint and --undo-journal cannot be used with --dry-run")
+    if args.undo_journal is not None and args.punch_discards:
+        sys.exit("The option --punch-discards cannot be used with --undo-journal")
+    if args.checkpoint is not None and cache_metadata.is_stdin(args.metadata):
+        sys.exit("The option --checkpoint cannot be used when reading the metadata from stdin")
+    if args.plan is not None and cache_metadata.is_stdin(args.metadata):
+        sys.exit("The option --plan cannot be used when reading the metadata from stdin")
+    if args.cachevol and args.lvm:
+        sys.exit("The option --cachevol cannot be used with --lvm, which finds the regions of cachevols itself")
+    if args.cachevol and cache_metadata.is_stdin(args.metadata):
+        sys.exit("The option --cachevol cannot be used when reading the metadata from stdin")
+    if args.cachevol_data_start is not None and not args.cachevol:
+        sys.exit("The option --cachevol-data-start requires --cachevol")
 
-    if args.emulate:
-        emulate(args)
-    elif args.table:
-        print_table(args)
-    elif args.writeback:
-        writeback(args)
-    else:
-        verify(args)
+    args.names = {"metadata": str(args.metadata), "cache": str(args.cache), "origin": str(args.origin)}
+    with ExitStack() as stack:
+        if args.lvm:
+            vg = lvm_metadata.read_volume_group(args.lvm)
+            candidates = [args.lvm, *args.pv_device]
+            def lv_device(name, role, write=False):
+                name = lvm_metadata.cache_volume(vg, str(name), role)
+                return stack.enter_context(lvm_metadata.make_lv_device(vg, name, candidates, write=write))
+            args.metadata = lv_device(args.metadata, "metadata")
+            args.cache = lv_device(args.cache, "cache")
+            args.origin = lv_device(args.origin, "origin", write=args.writeback and not args.dry_run)
+        if args.cachevol:
+            metadata_sectors, data_sectors = cache_metadata.cachevol_regions(args.metadata)
+            data_start = metadata_sectors if args.cachevol_data_start is None else args.cachevol_data_start
+            args.metadata = stack.enter_context(lvm_metadata.make_loop_device(args.metadata,
+                    offset=0, size=512 * metadata_sectors))
+            args.cache = stack.enter_context(lvm_metadata.make_loop_device(args.cache,
+                    offset=512 * data_start, size=512 * data_sectors))
+
+        if args.emulate:
+            emulate(args)
+        elif args.table:
+            print_table(args)
+        elif args.writeback and args.dry_run:
+            plan_writeback(args)
+        elif args.writeback and args.plan is not None:
+            execute_plan(args)
+        elif args.writeback:
+            writeback(args)
+        else:
+            verify(args)
 
 
 def verify(args):
-    with dev_open(args.cache) as fd_cache, dev_open(args.origin) as fd_origin:
-        seen_targets = set()
+    discards = []
+    start_time = time.monotonic()
+    with dev_open(args.cache) as fd_cache, dev_open(args.origin) as fd_origin, \
+            open_readers(args, fd_cache, fd_origin) as (cache_reader, origin_reader), \
+            ThreadPoolExecutor(max_workers=args.queue_depth) as executor, \
+            open_checkpoint(args, "verify") as checkpoint, \
+            open_report(args, checkpoint.state.get("report_position")) as report_writer:
+        seen_targets = {}
+        affected = [tuple(item) for item in checkpoint.state.get("affected", [])]
+        counts = dict.fromkeys(VERIFY_STATUSES, 0) | checkpoint.state.get("counts", {})
+        bytes_compared = checkpoint.state.get("bytes_compared", 0)
+        bad_sector_mappings = checkpoint.state.get("bad_sector_mappings", 0)
+        index = 0
+        # Digests of all origin blocks read so far, to find cache blocks belonging elsewhere
+        origin_digests = checkpoint.state.get("origin_digests", {})
+        # Messages of each mapping are queued together with the comparison of its blocks,
+        # so the output is in the same order as with synchronous reads
+        pending = deque()
+        def report(entry, status, duplicate_of, messages, comparison):
+            nonlocal bytes_compared, bad_sector_mappings
+            error = None
+            compared = 0
+            differing_units = None
+            differing_bytes = None
+            classification = None
+            similar_origin_block = None
+            trust = None
+            bad_sectors = None
+            unrecovered = None
+            if comparison is not None:
+                result = comparison.result()
+                bad_sectors = result.bad_sectors
+                unrecovered = result.unrecovered
+                if bad_sectors is not None:
+                    bad_sector_mappings += 1
+                if result.status in ("mismatch", "read-error", "unrecovered") or status == "match":
+                    status = result.status
+                messages.extend((sys.stdout, message) for message in result.messages)
+                error = result.error
+                compared = result.bytes_compared
+                if result.differing_units is not None:
+                    differing_units = ",".join(format_range(first, end) for first, end in result.differing_units)
+                    differing_bytes = result.differing_bytes
+                if result.classification is not None:
+                    classification = list(result.classification)
+                    similar_origin_block = result.similar_origin_block
+                    if similar_origin_block is None and result.cache_digest in origin_digests:
+                        similar_origin_block = origin_digests[result.cache_digest]
+                        classification.append("identical-to-other-origin-block")
+                    trust = trusted_copy(classification)
+                    similar = f" {similar_origin_block}" if similar_origin_block is not None else ""
+                    dirty = f"cache block {entry.cache_block} (dirty) " if status == "skipped-dirty" else "  "
+                    messages.append((sys.stdout, f"{dirty}classified as {', '.join(classification) or 'unclassified'}"
+                                                 f"{similar}; trust {trust}"))
+                if result.origin_digest is not None:
+                    origin_digests.setdefault(result.origin_digest, entry.origin_block)
+            for file, message in messages:
+                if file is not sys.stdout or not report_writer.uses_stdout:
+                    print(message, file=file)
+            counts[status] += 1
+            bytes_compared += compared
+            if args.files and status in filesystem_files.AFFECTED_STATUSES:
+                affected.append((512 * entry.origin_sector, 512 * (entry.origin_sector + entry.block_sectors),
+                                 f"origin block {entry.origin_block} ({status})"))
+            report_writer.write_mapping({
+                "cache_block": entry.cache_block,
+                "origin_block": entry.origin_block,
+                "dirty": entry.dirty,
+                "status": status,
+                "duplicate_of": duplicate_of,
+                "bytes_compared": compared,
+                "error": error,
+                "differing_units": differing_units,
+                "differing_bytes": differing_bytes,
+                "classification": None if classification is None else ",".join(classification),
+                "similar_origin_block": similar_origin_block,
+                "trust": trust,
+                "bad_sectors": bad_sectors,
+                "unrecovered": unrecovered,
+            })
+            checkpoint.advance(counts=counts, bytes_compared=bytes_compared, bad_sector_mappings=bad_sector_mappings,
+                               affected=affected, origin_digests=origin_digests,
+                               report_position=report_writer.position())
+
         def callback(entry):
+            nonlocal index
+            if (args.detail or args.classify) and entry.block_bytes % args.detail_unit != 0:
+                sys.exit(f"The detail unit must divide the block size of {entry.block_bytes} bytes")
+            index += 1
+            if index <= checkpoint.processed:
+                # Processed before resuming, but still needed to detect duplicate targets
+                seen_targets.setdefault(entry.origin_block, entry)
+                return
             cache_block = entry.cache_block
             origin_block = entry.origin_block
-            block_size = entry.block_bytes
-            print(f"{cache_block} -> {origin_block} (dirty={entry.dirty})", file=sys.stderr)
+            messages = []
+            salvaged = ", salvaged" if entry.salvaged else ""
+            hint = f", hint={cache_metadata.format_hint(entry.hint)}" if args.hints else ""
+            messages.append((sys.stderr, f"{cache_block} -> {origin_block} (dirty={entry.dirty}{salvaged}{hint})"))
 
+            status = "match"
+            duplicate_of = None
             if origin_block in seen_targets:
-                print(f"cache block {cache_block} points to already seen origin block {origin_block}")
+                previous = seen_targets[origin_block]
+                status = "duplicate-target"
+                duplicate_of = previous.cache_block
+                if args.hints:
+                    messages.append((sys.stdout, f"cache block {cache_block} points to already seen origin block {origin_block}"
+                          f" (hint={cache_metadata.format_hint(entry.hint)};"
+                          f" cache block {previous.cache_block} has hint={cache_metadata.format_hint(previous.hint)})"))
+                else:
+                    messages.append((sys.stdout, f"cache block {cache_block} points to already seen origin block {origin_block}"))
             else:
-                seen_targets.add(origin_block)
+                seen_targets[origin_block] = entry
 
+            comparison = None
+            parts = undiscarded_parts(discards, entry.origin_sector, entry.origin_sector + entry.block_sectors)
             if not args.all and entry.dirty:
-                return
+                if status == "match":
+                    status = "skipped-dirty"
+                if args.classify and parts:
+                    comparison = executor.submit(compare_block, entry, parts, cache_reader, origin_reader, args)
+            elif not parts:
+                if status == "match":
+                    status = "skipped-discarded"
+            else:
+                comparison = executor.submit(compare_block, entry, parts, cache_reader, origin_reader, args)
+            pending.append((entry, status, duplicate_of, messages, comparison))
+            while len(pending) > args.queue_depth:
+                report(*pending.popleft())
+
+        read_metadata(args, callback, on_discards=discards.extend)
+        while pending:
+            report(*pending.popleft())
+
+        summary = {
+            "mappings": sum(counts.values()),
+            "counts": counts,
+            "bytes_compared": bytes_compared,
+            "bad_sector_mappings": bad_sector_mappings,
+            "elapsed_seconds": round(time.monotonic() - start_time, 3),
+        }
+        report_writer.write_summary(summary)
+    if args.report is not None:
+        print(f"{summary['mappings']} mappings: "
+              + ", ".join(f"{count} {status}" for status, count in counts.items())
+              + f"; {bytes_compared} bytes compared in {summary['elapsed_seconds']} s"
+              + f"; {bad_sector_mappings} mappings with unreadable sectors", file=sys.stderr)
+    if args.files:
+        print_affected_files(args.files_device or args.origin, affected)
+    if counts["read-error"] or counts["unrecovered"] or bad_sector_mappings:
+        sys.exit(1)
+
+
+VERIFY_STATUSES = ["match", "mismatch", "duplicate-target", "read-error", "unrecovered",
+        "skipped-dirty", "skipped-discarded"]
+REPORT_FIELDS = ["cache_block", "origin_block", "dirty", "status", "duplicate_of", "bytes_compared", "error",
+        "differing_units", "differing_bytes", "classification", "similar_origin_block", "trust", "bad_sectors", "unrecovered"]
+HIGH_ENTROPY = 7.5
+
+
+@dataclass(frozen=True, kw_only=True, slots=True)
+class Comparison:
+    status: str
+    messages: list
+    bytes_compared: int = 0
+    error: str | None = None
+    differing_units: list | None = None
+    differing_bytes: int | None = None
+    classification: list | None = None
+    similar_origin_block: int | None = None
+    cache_digest: str | None = None
+    origin_digest: str | None = None
+    bad_sectors: str | None = None
+    unrecovered: str | None = None
+
+
+def compare_block(entry, parts, cache_reader, origin_reader, args):
+    """Compares the undiscarded parts of a cache block with its origin block.
+    Unrecovered ranges of imaged devices are left out of the comparison.
+    """
+    cache_block = entry.cache_block
+    origin_block = entry.origin_block
+    bytes_cache, bad_cache = cache_reader.read_block(cache_block, entry.block_bytes)
+    bytes_origin, bad_origin = origin_reader.read_block(origin_block, entry.block_bytes)
+    parts = [(512 * (first - entry.origin_sector), 512 * (end - entry.origin_sector)) for first, end in parts]
+    unrecovered_cache = cache_reader.unrecovered(cache_block, entry.block_bytes)
+    unrecovered_origin = origin_reader.unrecovered(origin_block, entry.block_bytes)
+    if unrecovered_cache or unrecovered_origin:
+        unrecovered = "; ".join(f"{name} sectors {format_byte_ranges(ranges)}"
+                for name, ranges in (("cache", unrecovered_cache), ("origin", unrecovered_origin)) if ranges)
+        message = f"cache block {cache_block} -> origin block {origin_block} touches unrecovered {unrecovered}"
+        parts = subtract_ranges(parts, unrecovered_cache + unrecovered_origin)
+        result = compare_readable(entry, bytes_cache, bad_cache, bytes_origin, bad_origin,
+                parts, origin_reader, args)
+        status = "unrecovered" if result.status == "match" else result.status
+        return replace(result, status=status, messages=[message, *result.messages], unrecovered=unrecovered)
+    return compare_readable(entry, bytes_cache, bad_cache, bytes_origin, bad_origin, parts, origin_reader, args)
+
+
+def compare_readable(entry, bytes_cache, bad_cache, bytes_origin, bad_origin, parts, origin_reader, args):
+    """Compares the given parts of the blocks, handling unreadable sectors according to ``--bad-sectors``."""
+    cache_block = entry.cache_block
+    origin_block = entry.origin_block
+    if not bad_cache and not bad_origin:
+        return compare_contents(entry, bytes_cache, bytes_origin, parts, origin_reader, args)
+
+    bad_sectors = "; ".join(f"{name} sectors {format_byte_ranges(bad)}"
+            for name, bad in (("cache", bad_cache), ("origin", bad_origin)) if bad)
+    message = f"cache block {cache_block} -> origin block {origin_block} has unreadable {bad_sectors}"
+    if not args.all and entry.dirty:
+        # Only read to be classified, so the unreadable sectors do not fail the mapping
+        result = compare_contents(entry, bytes_cache, bytes_origin, parts, origin_reader, args)
+        return replace(result, messages=[message, *result.messages])
+    if args.bad_sectors == "fail":
+        return Comparison(status="read-error", messages=[message], error=message, bad_sectors=bad_sectors)
+    if args.bad_sectors == "skip":
+        parts = subtract_ranges(parts, bad_cache + bad_origin)
+    result = compare_contents(entry, bytes_cache, bytes_origin, parts, origin_reader, args)
+    return replace(result, messages=[message, *result.messages], bad_sectors=bad_sectors)
+
+
+def compare_contents(entry, bytes_cache, bytes_origin, parts, origin_reader, args):
+    """Compares the given parts of the blocks, which are byte ranges relative to the blocks.
+    With ``--detail``, mismatches are described by the differing units and a hex diff.
+    With ``--classify``, mismatched blocks and dirty blocks are classified.
+    Dirty blocks are not compared unless ``--all`` is given.
+    """
+    cache_block = entry.cache_block
+    origin_block = entry.origin_block
+    block_size = entry.block_bytes
+    classified = {}
+    if args.classify:
+        classified = classify_blocks(entry, bytes_cache, bytes_origin, origin_reader, args)
+    if not args.all and entry.dirty:
+        return Comparison(status="skipped-dirty", messages=[], **classified)
+    compared = 0
+    for first, end in parts:
+        compared += end - first
+        if bytes_cache[first:end] != bytes_origin[first:end]:
+            break
+    else:
+        origin_digest = classified.get("origin_digest")
+        return Comparison(status="match", messages=[], bytes_compared=compared, origin_digest=origin_digest)
+    messages = [f"cache block {cache_block} does not match origin block {origin_block}"]
+    if not args.detail:
+        return Comparison(status="mismatch", messages=messages, bytes_compared=compared, **classified)
+
+    compared = sum(end - first for first, end in parts)
+    unit = args.detail_unit
+    # The last unit is shorter if the unit does not divide the block size
+    units = -(-block_size // unit)
+    differing_units = []
+    differing_bytes = 0
+    first_difference = None
+    for index in range(units):
+        differs = False
+        for first, end in parts:
+            first = max(first, index * unit)
+            end = min(end, (index + 1) * unit)
+            if first >= end or bytes_cache[first:end] == bytes_origin[first:end]:
+                continue
+            differs = True
+            for offset in range(first, end):
+                if bytes_cache[offset] != bytes_origin[offset]:
+                    differing_bytes += 1
+                    if first_difference is None:
+                        first_difference = offset
+        if not differs:
+            continue
+        if differing_units and differing_units[-1][1] == index:
+            differing_units[-1] = (differing_units[-1][0], index + 1)
+        else:
+            differing_units.append((index, index + 1))
+    count = sum(end - first for first, end in differing_units)
+    messages.append(f"  differing {unit}-byte units: "
+            + ", ".join(format_range(first, end) for first, end in differing_units)
+            + f" ({count} of {units} units, {differing_bytes} bytes)")
+    messages.extend(hex_diff(bytes_cache, bytes_origin, first_difference, args.hex_bytes))
+    return Comparison(status="mismatch", messages=messages, bytes_compared=compared,
+            differing_units=differing_units, differing_bytes=differing_bytes, **classified)
+
+
+def classify_blocks(entry, bytes_cache, bytes_origin, origin_reader, args):
+    """Classifies the differences between a cache block and its origin block.
+    Comparing the cache block with other origin blocks is limited to the neighbours
+    of the origin block here; the caller looks up the digest among all origin blocks read so far.
+    """
+    classification = []
+    cache_zero = bytes_cache.count(0) == len(bytes_cache)
+    origin_zero = bytes_origin.count(0) == len(bytes_origin)
+    if cache_zero:
+        classification.append("cache-zero")
+    if origin_zero:
+        classification.append("origin-zero")
+    unit = args.detail_unit
+    units = -(-len(bytes_cache) // unit)
+    equal_units = 0
+    for index in range(units):
+        first = index * unit
+        if bytes_cache[first:first + unit] == bytes_origin[first:first + unit]:
+            equal_units += 1
+    if 0 < equal_units < units:
+        classification.append("partially-equal")
+    if not cache_zero and entropy(bytes_cache) >= HIGH_ENTROPY:
+        classification.append("cache-high-entropy")
+    if not origin_zero and entropy(bytes_origin) >= HIGH_ENTROPY:
+        classification.append("origin-high-entropy")
+
+    similar_origin_block = None
+    if not cache_zero and bytes_cache != bytes_origin:
+        for distance in range(1, args.classify_neighbours + 1):
+            for block in (entry.origin_block - distance, entry.origin_block + distance):
+                if block < 0:
+                    continue
+                bytes_other, bad = origin_reader.read_block(block, entry.block_bytes)
+                if not bad and bytes_other == bytes_cache:
+                    similar_origin_block = block
+                    break
+            if similar_origin_block is not None:
+                classification.append("identical-to-other-origin-block")
+                break
+    return {
+        "classification": classification,
+        "similar_origin_block": similar_origin_block,
+        "cache_digest": None if cache_zero else hashlib.sha1(bytes_cache, usedforsecurity=False).hexdigest(),
+        "origin_digest": None if origin_zero else hashlib.sha1(bytes_origin, usedforsecurity=False).hexdigest(),
+    }
+
+
+def entropy(data):
+    """Returns the Shannon entropy of the given bytes in bits per byte."""
+    return -sum(count / len(data) * math.log2(count / len(data)) for count in Counter(data).values())
+
+
+def trusted_copy(classification):
+    """Returns which copy of a block is more likely to be correct according to its classification."""
+    if "identical-to-other-origin-block" in classification:
+        return "origin"
+    if "cache-zero" in classification and "origin-zero" not in classification:
+        return "origin"
+    if "origin-zero" in classification and "cache-zero" not in classification:
+        return "cache"
+    return "unknown"
+
+
+def hex_diff(bytes_cache, bytes_origin, offset, limit):
+    """Yields lines showing up to ``limit`` bytes of both blocks from the given offset,
+    with the differing bytes marked below.
+    """
+    first = offset - offset % 16
+    end = min(first + limit, len(bytes_cache))
+    for line_offset in range(first, end, 16):
+        cache_line = bytes_cache[line_offset:min(line_offset + 16, end)]
+        origin_line = bytes_origin[line_offset:min(line_offset + 16, end)]
+        marks = " ".join("^^" if a != b else "  " for a, b in zip(cache_line, origin_line))
+        yield f"  {line_offset:#08x} cache  {cache_line.hex(' ')}"
+        yield f"  {'':8} origin {origin_line.hex(' ')}"
+        if marks.strip():
+            yield f"  {'':8}        {marks.rstrip()}"
+
+
+def format_range(first, end):
+    return str(first) if end == first + 1 else f"{first}-{end - 1}"
+
+
+def format_byte_ranges(ranges):
+    """Formats byte ranges relative to a block as ranges of sectors."""
+    return ",".join(format_range(first // 512, end // 512) for first, end in ranges)
+
+
+def subtract_ranges(ranges, removed):
+    """Returns the parts of the sorted ranges which are not covered by any of the removed ranges."""
+    result = []
+    for first, end in ranges:
+        for removed_first, removed_end in sorted(removed):
+            if removed_end <= first or removed_first >= end:
+                continue
+            if first < removed_first:
+                result.append((first, removed_first))
+            first = max(first, removed_end)
+        if first < end:
+            result.append((first, end))
+    return result
+
+
+@contextmanager
+def open_report(args, position=None):
+    """Opens the structured report of verify, which is either JSON Lines or CSV.
+    ``-`` writes the report to stdout instead of the usual messages.
+    When resuming, ``position`` is the end of the records of the mappings recorded by the checkpoint.
+    The report is truncated there and continued, as the following mappings are processed again.
+    """
+    if args.report is None:
+        yield ReportWriter(None, None)
+    elif str(args.report) == "-":
+        yield ReportWriter(sys.stdout, args.report_format, uses_stdout=True)
+    elif position is not None:
+        try:
+            file = open(args.report, "r+", newline="")
+        except FileNotFoundError:
+            sys.exit(f"Cannot resume, report {args.report} not found")
+        with file:
+            file.truncate(position)
+            file.seek(position)
+            yield ReportWriter(file, args.report_format, header=position == 0)
+    else:
+        with open(args.report, "w", newline="") as file:
+            yield ReportWriter(file, args.report_format)
+
 
-            bytes_cache = dev_read_block(fd_cache, cache_block, block_size)
-            if len(bytes_cache) != block_size:
-                sys.exit(f"Incomplete cache block: {cache_block}")
-            bytes_origin = dev_read_block(fd_origin, origin_block, block_size)
-            if len(bytes_origin) != block_size:
-                sys.exit(f"Incomplete origin block: {origin_block}")
+class ReportWriter:
+    def __init__(self, file, report_format, *, uses_stdout=False, header=True):
+        self.__file = file
+        self.__format = report_format
+        self.uses_stdout = uses_stdout
+        self.__csv = None
+        if file is not None and report_format == "csv":
+            self.__csv = csv.DictWriter(file, REPORT_FIELDS)
+            if header:
+                self.__csv.writeheader()
 
-            if bytes_cache != bytes_origin:
-                print(f"cache block {cache_block} does not match origin block {origin_block}")
+    def position(self):
+        """Returns the end of the records written so far, or ``None`` for stdout and without a report."""
+        if self.__file is None or self.uses_stdout:
+            return None
+        self.__file.flush()
+        return self.__file.tell()
 
-        read_metadata(args, callback)
+    def write_mapping(self, record):
+        if self.__csv is not None:
+            self.__csv.writerow(record)
+        elif self.__file is not None:
+            self.__file.write(json.dumps({"type": "mapping", **record}) + "\n")
+
+    def write_summary(self, summary):
+        # CSV has no room for the summary, which is printed to stderr anyway
+        if self.__file is not None and self.__csv is None:
+            self.__file.write(json.dumps({"type": "summary", **summary}) + "\n")
 
 
 def emulate(args):
     name = args.emulate
+    dirty_entries = []
     with Popen(["dmsetup", "create", "-r", name], stdin=PIPE, text=True) as p:
-        for line in generate_table(args):
+        for line in generate_table(args, dirty_entries):
             p.stdin.write(line)
             p.stdin.write("\n")
         p.stdin.close()
         p.wait()
         if p.returncode != 0:
             sys.exit(f"dmsetup failed with exit code {p.returncode}")
+    if args.files:
+        # The emulated device shows the filesystem as after the writeback
+        print_affected_files(args.files_device or Path("/dev/mapper") / name, dirty_ranges(dirty_entries))
 
 
 def print_table(args):
-    for line in generate_table(args):
+    dirty_entries = []
+    for line in generate_table(args, dirty_entries):
         print(line)
+    if args.files:
+        # stdout is reserved for the table
+        print_affected_files(args.files_device or args.origin, dirty_ranges(dirty_entries), file=sys.stderr)
+
 
+def dirty_ranges(entries):
+    return [(512 * entry.origin_sector, 512 * (entry.origin_sector + entry.block_sectors),
+             f"origin block {entry.origin_block} (dirty)") for entry in entries]
 
-def is_effectively_dirty(entry, fd_origin, fd_cache):
-    origin_bytes = dev_read_block(fd_origin, entry.origin_block, entry.block_bytes)
-    cache_bytes = dev_read_block(fd_cache, entry.cache_block, entry.block_bytes)
-    return origin_bytes != cache_bytes
 
+def print_affected_files(device, ranges, file=sys.stdout):
+    """Lists the files and filesystem structures of ``device`` within the given byte ranges,
+    see ``filesystem_files.py``.
+    """
+    if not ranges:
+        print("no files affected", file=sys.stderr)
+        return
+    impacts, files = filesystem_files.affected_files(device, ranges)
+    filesystem_files.print_impacts(impacts, files, file=file)
 
-def generate_table(args):
+
+def is_effectively_dirty(entry, origin_reader, cache_reader):
+    """Returns whether the cache block differs from the origin block.
+    Blocks with unreadable or unrecovered sectors are considered dirty.
+    """
+    if cache_reader.unrecovered(entry.cache_block, entry.block_bytes) \
+            or origin_reader.unrecovered(entry.origin_block, entry.block_bytes):
+        return True
+    origin_bytes, origin_bad = origin_reader.read_block(entry.origin_block, entry.block_bytes)
+    cache_bytes, cache_bad = cache_reader.read_block(entry.cache_block, entry.block_bytes)
+    return bool(origin_bad or cache_bad) or origin_bytes != cache_bytes
+
+
+def generate_table(args, dirty_entries=None):
+    """Yields the lines of a device-mapper table of the origin device with the dirty blocks of the cache.
+    If ``dirty_entries`` is given, the entries mapped to the cache are appended to it.
+    """
     cache_device = args.cache
     origin_device = args.origin
     device_size = get_device_size(origin_device)
 
-    with dev_open(cache_device) as fd_cache, dev_open(origin_device) as fd_origin:
+    with dev_open(cache_device) as fd_cache, dev_open(origin_device) as fd_origin, \
+            open_readers(args, fd_cache, fd_origin) as (cache_reader, origin_reader):
         heap = []
         def callback(entry):
             if entry.origin_sector + entry.block_sectors > device_size:
                 sys.exit(f"block out of range: {entry.origin_sector}; device size: {device_size}")
-            if args.all and is_effectively_dirty(entry, fd_origin, fd_cache) or entry.dirty:
+            if entry.dirty and not entry.assumed_dirty \
+                    or (args.all or entry.assumed_dirty) and is_effectively_dirty(entry, origin_reader, cache_reader):
                 heapq.heappush(heap, (entry.origin_block, entry))
 
-        read_metadata(args, callback)
+        discards = []
+        read_metadata(args, callback, on_discards=discards.extend if args.zero_discards else None)
+
+    # Unrecovered ranges of imaged devices are mapped to the error target instead of showing zeros
+    origin_unrecovered = [(first // 512, -(-end // 512))
+                          for first, end in origin_reader.unrecovered(0, 512 * device_size)]
+    def origin_lines(first, end):
+        for part_first, part_end, discarded in split_discarded(discards, first, end):
+            if discarded:
+                yield f"{part_first} {part_end - part_first} zero"
+                continue
+            for piece_first, piece_end, is_unrecovered in split_discarded(origin_unrecovered, part_first, part_end):
+                count = piece_end - piece_first
+                if is_unrecovered:
+                    print(f"origin sectors {format_range(piece_first, piece_end)} are unrecovered,"
+                          f" mapped to the error target", file=sys.stderr)
+                    yield f"{piece_first} {count} error"
+                else:
+                    yield f"{piece_first} {count} linear {origin_device} {piece_first}"
+
+    def cache_lines(entry):
+        unrecovered = [(entry.origin_sector + first // 512, entry.origin_sector - (-end // 512)) for first, end
+                       in cache_reader.unrecovered(entry.cache_block, entry.block_bytes)]
+        if unrecovered:
+            print(f"cache block {entry.cache_block} -> origin block {entry.origin_block}"
+                  f" touches unrecovered cache sectors, mapped to the error target", file=sys.stderr)
+        for first, end, is_unrecovered in split_discarded(unrecovered, entry.origin_sector,
+                entry.origin_sector + entry.block_sectors):
+            if is_unrecovered:
+                yield f"{first} {end - first} error"
+            else:
+                yield f"{first} {end - first} linear {cache_device} {entry.cache_sector + first - entry.origin_sector}"
 
     next_sector = 0
     while heap:
         current_entry = heapq.heappop(heap)[1]
+        if dirty_entries is not None:
+            dirty_entries.append(current_entry)
         current_offset = current_entry.origin_sector
         block_size = current_entry.block_sectors
         if next_sector < current_offset:
-            count = current_offset - next_sector
-            yield f"{next_sector} {count} linear {origin_device} {next_sector}"
-        yield f"{current_offset} {block_size} linear {cache_device} {current_entry.cache_sector}"
+            yield from origin_lines(next_sector, current_offset)
+        yield from cache_lines(current_entry)
         next_sector = current_offset + block_size
     if next_sector < device_size:
-        count = device_size - next_sector
-        yield f"{next_sector} {count} linear {origin_device} {next_sector}"
+        yield from origin_lines(next_sector, device_size)
 
 
 def get_device_size(device):
@@ -164,89 +901,570 @@ def get_device_size(device):
 
 
 def writeback(args):
-    with dev_open(args.cache) as fd_cache, dev_open(args.origin, write=True) as fd_origin:
+    discards = []
+    affected = 0
+    with dev_open(args.cache) as fd_cache, dev_open(args.origin, write=True) as fd_origin, \
+            open_readers(args, fd_cache, fd_origin) as (cache_reader, origin_reader), \
+            open_checkpoint(args, "writeback", before_write=lambda: os.fsync(fd_origin)) as checkpoint, \
+            open_undo_journal(args) as journal:
+        def on_discards(ranges):
+            discards.extend(ranges)
+            # The discards are punched once, a resumed run continues with the mappings
+            if args.punch_discards and not checkpoint.state.get("discards_punched"):
+                for first, end in discards:
+                    dev_punch_hole(fd_origin, 512 * first, 512 * (end - first))
+                checkpoint.state = checkpoint.state | {"discards_punched": True}
+                checkpoint.write()
+        index = 0
         def callback(entry):
-            if args.all or entry.dirty:
+            nonlocal index, affected
+            index += 1
+            if index <= checkpoint.processed:
+                return
+            if is_written_back(entry, discards, cache_reader, origin_reader, args):
                 #print(f"{entry.cache_block} -> {entry.origin_block} (dirty={entry.dirty})", file=sys.stderr)
-                dev_copy_block(fd_cache, entry.cache_block, fd_origin, entry.origin_block, entry.block_bytes)
-        read_metadata(args, callback)
+                if not copy_block(entry, fd_cache, fd_origin, cache_reader, origin_reader, journal, args):
+                    affected += 1
+            checkpoint.advance(affected=affected, discards_punched=args.punch_discards)
+        affected = checkpoint.state.get("affected", 0)
+        read_metadata(args, callback, on_discards)
+    if affected:
+        sys.exit(f"{affected} cache blocks with unreadable or unrecovered sectors")
 
 
-def read_metadata(args, callback):
-    reader = MetadataReader(callback)
-    parse(args.metadata, reader)
+def is_written_back(entry, discards, cache_reader, origin_reader, args):
+    return bool(undiscarded_parts(discards, entry.origin_sector, entry.origin_sector + entry.block_sectors)) \
+            and (args.all or entry.dirty and (not entry.assumed_dirty
+            or is_effectively_dirty(entry, origin_reader, cache_reader)))
 
 
-@dataclass(frozen=True, kw_only=True, slots=True)
-class Entry:
-    origin_block: int
-    origin_sector: int
-    origin_offset: int
-    cache_block: int
-    cache_sector: int
-    cache_offset: int
-    dirty: bool
-    block_bytes: int
-    block_sectors: int
-
-
-class MetadataReader(ContentHandler):
-
-    def __init__(self, callback):
-        super().__init__()
-        self.__callback = callback
-        self.__block_size = None
-
-    def startElement(self, name, attrs):
-        if name == "superblock":
-            if self.__block_size is not None:
-                sys.exit("Invalid metadata: second superblock")
-            self.__block_size = int(attrs["block_size"])
-        if self.__block_size is None:
-            sys.exit("Invalid metadata: No superblock")
-        if name == "mapping":
-            block_size = self.__block_size
-            origin_block = int(attrs["origin_block"])
-            cache_block = int(attrs["cache_block"])
-            self.__callback(Entry(
-                origin_block=origin_block,
-                origin_sector=block_size * origin_block,
-                origin_offset=512 * block_size * origin_block,
-                cache_block=cache_block,
-                cache_sector=block_size * cache_block,
-                cache_offset=512 * block_size * cache_block,
-                dirty=parse_bool(attrs["dirty"]),
-                block_bytes=512 * block_size,
-                block_sectors=block_size,
-            ))
+def plan_writeback(args):
+    """Lists what the writeback would do, without writing to the origin device.
+    With ``--plan``, the plan is saved as JSON Lines, to be executed by a later writeback.
+    """
+    discards = []
+    counts = dict.fromkeys(PLAN_STATUSES, 0)
+    bytes_written = 0
+    bytes_unchanged = 0
+    touched = []
+    with dev_open(args.cache) as fd_cache, dev_open(args.origin) as fd_origin, \
+            open_readers(args, fd_cache, fd_origin) as (cache_reader, origin_reader), \
+            open(args.plan or os.devnull, "w") as file:
+        if args.plan is not None:
+            file.write(json.dumps({"type": "header", "fingerprint": input_fingerprint(args, "writeback"),
+                                   "options": plan_options(args)}) + "\n")
+        def on_discards(ranges):
+            discards.extend(ranges)
+            if args.punch_discards:
+                for first, end in discards:
+                    print(f"discard origin sectors {format_range(first, end)}")
+                    file.write(json.dumps({"type": "discard", "first_sector": first, "end_sector": end}) + "\n")
+                    touched.append((first, end))
+        block_sectors = None
+        def callback(entry):
+            nonlocal block_sectors, bytes_written, bytes_unchanged
+            block_sectors = entry.block_sectors
+            if not is_written_back(entry, discards, cache_reader, origin_reader, args):
+                return
+            record, _ = plan_block(entry, cache_reader, origin_reader, args)
+            file.write(json.dumps({"type": "mapping", **record}) + "\n")
+            print(format_plan_record(record))
+            counts[record["status"]] += 1
+            bytes_written += record["bytes"]
+            if record["status"] == "no-op":
+                bytes_unchanged += entry.block_bytes
+            touched.extend(record["origin_ranges"])
+        read_metadata(args, callback, on_discards)
+        touched = [[r.start, r.stop] for r in cache_metadata.merge_ranges(range(first, end) for first, end in touched)]
+        summary = {
+            "mappings": sum(counts.values()),
+            "counts": counts,
+            "block_sectors": block_sectors,
+            "bytes_written": bytes_written,
+            "bytes_unchanged": bytes_unchanged,
+            "origin_ranges": touched,
+        }
+        file.write(json.dumps({"type": "summary", **summary}) + "\n")
+    print(f"{summary['mappings']} mappings: "
+          + ", ".join(f"{count} {status}" for status, count in counts.items())
+          + f"; {bytes_written} bytes to write in {len(touched)} origin ranges"
+          + f", {bytes_unchanged} bytes already equal", file=sys.stderr)
+
+
+def execute_plan(args):
+    """Writes back exactly the mappings of a plan saved by ``--dry-run``.
+    Each mapping is planned again before it is written,
+    and mappings whose cache or origin block has changed since are left alone.
+    """
+    header, records = read_plan(args)
+    affected = 0
+    changed = 0
+    with dev_open(args.cache) as fd_cache, dev_open(args.origin, write=True) as fd_origin, \
+            open_readers(args, fd_cache, fd_origin) as (cache_reader, origin_reader), \
+            open_checkpoint(args, "writeback", before_write=lambda: os.fsync(fd_origin)) as checkpoint, \
+            open_undo_journal(args) as journal:
+        affected = checkpoint.state.get("affected", 0)
+        changed = checkpoint.state.get("changed", 0)
+        for index, record in enumerate(records, 1):
+            if index <= checkpoint.processed:
+                continue
+            if record["type"] == "discard":
+                dev_punch_hole(fd_origin, 512 * record["first_sector"], 512 * (record["end_sector"] - record["first_sector"]))
+            elif record["status"] != "no-op":
+                entry = cache_metadata.make_entry(header["block_sectors"], record["origin_block"],
+                                                  record["cache_block"], record["dirty"])
+                planned, data = plan_block(entry, cache_reader, origin_reader, args)
+                if planned != {name: value for name, value in record.items() if name != "type"}:
+                    print(f"cache block {entry.cache_block} -> origin block {entry.origin_block}"
+                          " has changed since the plan was made (not written back)", file=sys.stderr)
+                    changed += 1
+                else:
+                    write_origin_block(entry, fd_origin, origin_reader, journal,
+                                       [(512 * first - entry.origin_offset, data[512 * first - entry.origin_offset:512 * end - entry.origin_offset])
+                                        for first, end in record["origin_ranges"]])
+                    if record["status"] == "read-error":
+                        affected += 1
+            checkpoint.advance(affected=affected, changed=changed)
+    if changed:
+        sys.exit(f"{changed} mappings have changed since the plan was made")
+    if affected:
+        sys.exit(f"{affected} cache blocks with unreadable or unrecovered sectors")
+
+
+PLAN_STATUSES = ["copy", "no-op", "read-error"]
+
+
+def plan_options(args):
+    """Returns the options which decide what a plan writes, besides those of the fingerprint."""
+    return {name: getattr(args, name) for name in ("bad_sectors", "punch_discards")}
+
+
+def read_plan(args):
+    """Returns the header and the discard and mapping records of a plan saved by ``--dry-run``,
+    after checking that it was made for the same inputs and options.
+    """
+    try:
+        with open(args.plan) as file:
+            records = [json.loads(line) for line in file]
+    except FileNotFoundError:
+        sys.exit(f"Plan {args.plan} not found")
+    if not records or records[0].get("type") != "header" or records[-1].get("type") != "summary":
+        sys.exit(f"Plan {args.plan} is incomplete")
+    header = records[0] | {"block_sectors": records[-1]["block_sectors"]}
+    fingerprint = input_fingerprint(args, "writeback")
+    changes = [name for name in fingerprint if name != "plan" and header["fingerprint"].get(name) != fingerprint[name]]
+    changes += [name for name, value in plan_options(args).items() if header["options"].get(name) != value]
+    if changes:
+        sys.exit(f"Cannot execute the plan, the inputs have changed since it was made: {', '.join(changes)}")
+    return header, records[1:-1]
+
+
+def plan_block(entry, cache_reader, origin_reader, args):
+    """Plans the writeback of a mapping.
+    Returns its record in the plan and the contents of the cache block to write,
+    with zeros for unreadable and unrecovered sectors.
+    """
+    data, bad = cache_reader.read_block(entry.cache_block, entry.block_bytes)
+    unrecovered = cache_reader.unrecovered(entry.cache_block, entry.block_bytes)
+    if unrecovered:
+        data = bytearray(data)
+        for first, end in unrecovered:
+            data[first:end] = bytes(end - first)
+        data = bytes(data)
+    origin_data, origin_bad = origin_reader.read_block(entry.origin_block, entry.block_bytes)
+    origin_unrecovered = origin_reader.unrecovered(entry.origin_block, entry.block_bytes)
+    missing = sorted(bad + unrecovered)
+    if not missing:
+        status = "no-op" if not origin_bad and not origin_unrecovered and data == origin_data else "copy"
+        parts = [] if status == "no-op" else [(0, entry.block_bytes)]
+    else:
+        status = "read-error"
+        parts = {"fail": [], "zero": [(0, entry.block_bytes)],
+                 "skip": subtract_ranges([(0, entry.block_bytes)], missing)}[args.bad_sectors]
+    bad_sectors = "; ".join(f"{name} sectors {format_byte_ranges(ranges)}"
+            for name, ranges in (("cache", bad), ("origin", origin_bad)) if ranges)
+    unrecovered = "; ".join(f"{name} sectors {format_byte_ranges(ranges)}"
+            for name, ranges in (("cache", unrecovered), ("origin", origin_unrecovered)) if ranges)
+    record = {
+        "cache_block": entry.cache_block,
+        "origin_block": entry.origin_block,
+        "dirty": entry.dirty,
+        "status": status,
+        "bytes": sum(end - first for first, end in parts),
+        "origin_ranges": [[entry.origin_sector + first // 512, entry.origin_sector + end // 512] for first, end in parts],
+        "cache_sha256": hashlib.sha256(data).hexdigest(),
+        "origin_sha256": hashlib.sha256(origin_data).hexdigest(),
+        "bad_sectors": bad_sectors or None,
+        "unrecovered": unrecovered or None,
+    }
+    return record, data
+
+
+def format_plan_record(record):
+    line = f"cache block {record['cache_block']} -> origin block {record['origin_block']}: {record['status']}"
+    if record["origin_ranges"]:
+        line += f", {record['bytes']} bytes to origin sectors " \
+                + ",".join(format_range(first, end) for first, end in record["origin_ranges"])
+    else:
+        line += ", nothing written"
+    for name in ("bad_sectors", "unrecovered"):
+        if record[name] is not None:
+            line += f", {name.replace('_', ' ')} {record[name]}"
+    return line
+
+
+def copy_block(entry, fd_cache, fd_origin, cache_reader, origin_reader, journal, args):
+    """Copies a cache block to its origin block.
+    If the cache block cannot be read, it is read again sector by sector,
+    and the unreadable sectors are handled according to ``--bad-sectors``.
+    Unrecovered sectors of an imaged cache device are handled the same way.
+    With an undo journal, the origin block is saved to it first.
+    Returns whether the block was copied completely.
+    """
+    unrecovered = cache_reader.unrecovered(entry.cache_block, entry.block_bytes)
+    copied = 0
+    if not unrecovered and journal is None:
+        copied = dev_copy_block(fd_cache, entry.cache_block, fd_origin, entry.origin_block, entry.block_bytes)
+        if copied == entry.block_bytes:
+            return True
+    data, bad = cache_reader.read_block(entry.cache_block, entry.block_bytes)
+    if not bad and not unrecovered:
+        write_origin_block(entry, fd_origin, origin_reader, journal, [(0, data)])
+        return True
+    action = {"fail": "not written back", "zero": "zero-filled", "skip": "skipped"}[args.bad_sectors]
+    if bad:
+        print(f"cache block {entry.cache_block} -> origin block {entry.origin_block}"
+              f" has unreadable sectors {format_byte_ranges(bad)} ({action})", file=sys.stderr)
+    if unrecovered:
+        print(f"cache block {entry.cache_block} -> origin block {entry.origin_block}"
+              f" touches unrecovered sectors {format_byte_ranges(unrecovered)} ({action})", file=sys.stderr)
+        data = bytearray(data)
+        for first, end in unrecovered:
+            data[first:end] = bytes(end - first)
+        bad = sorted(bad + unrecovered)
+    if args.bad_sectors == "zero":
+        write_origin_block(entry, fd_origin, origin_reader, journal, [(0, data)])
+    elif args.bad_sectors == "skip":
+        write_origin_block(entry, fd_origin, origin_reader, journal,
+                           [(first, data[first:end]) for first, end in subtract_ranges([(0, entry.block_bytes)], bad)])
+    elif copied:
+        print(f"cache block {entry.cache_block} -> origin block {entry.origin_block}"
+              f" was partially written back, {copied} bytes were copied before the read failed", file=sys.stderr)
+    return False
+
+
+def write_origin_block(entry, fd_origin, origin_reader, journal, parts):
+    """Writes the given parts of an origin block, as offsets relative to the block and their data.
+    With an undo journal, the original contents of the block are saved to it first.
+    """
+    offset = entry.origin_block * entry.block_bytes
+    if journal is not None and parts:
+        old, bad = origin_reader.read_block(entry.origin_block, entry.block_bytes)
+        new = bytearray(old)
+        for first, data in parts:
+            new[first:first + len(data)] = data
+        journal.save(offset, old, bad, new)
+    for first, data in parts:
+        os.pwrite(fd_origin, data, offset + first)
+
+
+@contextmanager
+def open_undo_journal(args):
+    """Yields the writer of the undo journal of a writeback, or ``None`` without ``--undo-journal``.
+    An existing journal is only continued when resuming.
+    """
+    if args.undo_journal is None:
+        yield None
+        return
+    if args.undo_journal.exists() and not args.resume:
+        sys.exit(f"Undo journal {args.undo_journal} already exists, use --resume or remove it")
+    with undo_journal.open_journal(args.undo_journal, args.names["origin"], 512 * get_device_size(args.origin)) as journal:
+        yield journal
+
+
+@contextmanager
+def open_checkpoint(args, mode, *, before_write=None):
+    """Yields the checkpoint of a verify or writeback run.
+    The checkpoint is written periodically and once more when the run ends,
+    even if it is aborted.
+    ``before_write`` is called before each write, e.g. to flush the origin device,
+    so that the checkpoint never claims more than what is on disk.
+    """
+    checkpoint = Checkpoint(args, mode, before_write=before_write)
+    completed = False
+    try:
+        yield checkpoint
+        completed = True
+    finally:
+        checkpoint.write(completed=completed)
+
+
+class Checkpoint:
+    """Records the number of mappings processed in order,
+    together with a fingerprint of the inputs, so that a later run can skip them.
+    Without ``--checkpoint``, nothing is recorded.
+    """
+
+    def __init__(self, args, mode, *, before_write=None):
+        self.__path = args.checkpoint
+        self.__before_write = before_write
+        self.__interval = args.checkpoint_interval
+        self.__last_write = time.monotonic()
+        self.processed = 0
+        self.state = {}
+        if self.__path is None:
+            return
+        self.__fingerprint = input_fingerprint(args, mode)
+        if not args.resume:
+            if self.__path.exists():
+                sys.exit(f"Checkpoint {self.__path} already exists, use --resume or remove it")
+            return
+        try:
+            with open(self.__path) as file:
+                recorded = json.load(file)
+        except FileNotFoundError:
+            sys.exit(f"Checkpoint {self.__path} not found")
+        changes = [name for name in self.__fingerprint
+                   if recorded["fingerprint"].get(name) != self.__fingerprint[name]]
+        if changes:
+            sys.exit(f"Cannot resume, the inputs have changed since the checkpoint was written: {', '.join(changes)}")
+        if recorded["completed"]:
+            sys.exit(f"Checkpoint {self.__path} belongs to a completed run")
+        self.processed = recorded["processed"]
+        self.state = recorded["state"]
+        print(f"Resuming after {self.processed} mappings", file=sys.stderr)
+
+    def advance(self, **state):
+        self.processed += 1
+        self.state = state
+        if self.__path is not None and time.monotonic() - self.__last_write >= self.__interval:
+            self.write()
+
+    def write(self, *, completed=False):
+        if self.__path is None:
+            return
+        if self.__before_write is not None:
+            self.__before_write()
+        temporary = self.__path.with_name(f".{self.__path.name}.tmp")
+        with open(temporary, "w") as file:
+            json.dump({
+                "fingerprint": self.__fingerprint,
+                "processed": self.processed,
+                "completed": completed,
+                "state": self.state,
+            }, file, indent=2)
+            file.flush()
+            os.fsync(file.fileno())
+        os.replace(temporary, self.__path)
+        self.__last_write = time.monotonic()
+
+
+def input_fingerprint(args, mode):
+    """Returns a fingerprint of the options and inputs which determine the mappings of a run.
+    The content of the devices is not included, as writeback changes the origin device.
+    """
+    return {
+        "mode": mode,
+        "options": {name: getattr(args, name) for name in ("all", "salvage", "block_size", "mapping_root", "dirty_root", "discard_root",
+                                                           "cachevol", "cachevol_data_start", "report_format")},
+        "report": None if args.report is None else str(args.report),
+        "metadata": file_fingerprint(args.names["metadata"], args.metadata, content=True,
+                                     identity=not args.lvm and not args.cachevol),
+        "cache": file_fingerprint(args.names["cache"], args.cache, identity=not args.lvm and not args.cachevol),
+        "origin": file_fingerprint(args.names["origin"], args.origin, identity=not args.lvm),
+        "lvm": None if args.lvm is None else file_fingerprint(str(args.lvm), args.lvm, content=True),
+        "plan": None if args.plan is None or args.dry_run else file_fingerprint(str(args.plan), args.plan, content=True),
+    }
+
+
+def file_fingerprint(name, path, *, content=False, identity=True):
+    statinfo = os.stat(path)
+    result = {"name": name}
+    if stat.S_ISBLK(statinfo.st_mode):
+        result["size"] = 512 * get_device_size(path)
+        if identity:
+            result["device"] = f"{os.major(statinfo.st_rdev)}:{os.minor(statinfo.st_rdev)}"
+    else:
+        result["size"] = statinfo.st_size
+        if identity:
+            result["inode"] = f"{statinfo.st_dev}:{statinfo.st_ino}"
+        if content:
+            result["mtime_ns"] = statinfo.st_mtime_ns
+    if content:
+        # All of the content, as changes of the metadata can be anywhere, and block devices have no mtime
+        digest = hashlib.sha1(usedforsecurity=False)
+        with open(path, "rb") as file:
+            while chunk := file.read(1 << 20):
+                digest.update(chunk)
+        result["sha1"] = digest.hexdigest()
+    return result
+
+
+def split_discarded(discards, first, end):
+    """Splits the given range of sectors into parts which are either discarded or not.
+    Yields tuples containing the first sector, the end sector and whether the part is discarded.
+    """
+    index = bisect.bisect_right(discards, (first, float("inf"))) - 1
+    index = max(index, 0)
+    current = first
+    while current < end:
+        if index < len(discards) and discards[index][1] <= current:
+            index += 1
+            continue
+        if index < len(discards) and discards[index][0] <= current:
+            part_end = min(end, discards[index][1])
+            yield current, part_end, True
+        else:
+            part_end = end if index >= len(discards) else min(end, discards[index][0])
+            yield current, part_end, False
+        current = part_end
+
+
+def undiscarded_parts(discards, first, end):
+    return [(part_first, part_end) for part_first, part_end, discarded
+            in split_discarded(discards, first, end) if not discarded]
+
+
+def read_metadata(args, callback, on_discards=None):
+    """Calls ``callback`` for every mapping,
+    and ``on_discards`` with the discarded ranges before the first mapping, see ``cache_metadata.read_metadata``.
+    Salvaged metadata has no discards.
+    """
+    if args.salvage:
+        if on_discards is not None:
+            on_discards([])
+        cache_metadata.salvage_metadata(args.metadata, callback, args.block_size)
+    else:
+        cache_metadata.read_metadata(args.metadata, callback, args.mapping_root, args.dirty_root, args.hints,
+                                     on_discards=on_discards, discard_root=args.discard_root)
 
 
 @contextmanager
 def dev_open(path, *, write=False):
-    fd = os.open(path, os.O_WRONLY if write else os.O_RDONLY)
+    fd = os.open(path, os.O_RDWR if write else os.O_RDONLY)
     try:
         yield fd
     finally:
         os.close(fd)
 
 
-def dev_read_block(fd, block, block_size):
-    return os.pread(fd, block_size, block * block_size)
+@contextmanager
+def open_readers(args, fd_cache, fd_origin):
+    """Yields the readers of the cache and the origin device,
+    and writes their error maps once done.
+    The readers know the unrecovered ranges if the devices are images with mapfiles.
+    """
+    readers = []
+    for fd, path, map_path, mapfile_path in ((fd_cache, args.cache, args.cache_error_map, args.cache_mapfile),
+                                             (fd_origin, args.origin, args.origin_error_map, args.origin_mapfile)):
+        error_map = None if map_path is None else ddrescue_mapfile.Mapfile(512 * get_device_size(path))
+        mapfile = None if mapfile_path is None else ddrescue_mapfile.Mapfile.read(mapfile_path)
+        readers.append(DeviceReader(fd, retries=args.retries, error_map=error_map, mapfile=mapfile))
+    try:
+        yield readers
+    finally:
+        for reader, map_path in zip(readers, (args.cache_error_map, args.origin_error_map)):
+            if map_path is not None:
+                reader.error_map.write(map_path)
 
 
-def dev_copy_block(src_fd, src_block, dest_fd, dest_block, block_size):
-    os.lseek(src_fd, src_block * block_size, os.SEEK_SET)
-    os.lseek(dest_fd, dest_block * block_size, os.SEEK_SET)
-    return os.sendfile(dest_fd, src_fd, src_block * block_size, block_size)
+class DeviceReader:
+    """Reads blocks from a device, tolerating unreadable sectors.
+    A failing read is retried, then bisected down to single sectors.
+    Unreadable sectors are returned as zeros, together with their byte ranges relative to the block.
+    Reads beyond the end of the device are treated as unreadable too.
+    """
 
+    def __init__(self, fd, *, retries=2, error_map=None, mapfile=None):
+        self.__fd = fd
+        self.__retries = retries
+        self.error_map = error_map
+        self.__mapfile = mapfile
 
-def parse_bool(string):
-    if string == "true":
-        return True
-    elif string == "false":
-        return False
+    def unrecovered(self, block, block_size):
+        """Returns the byte ranges of the block, relative to the block,
+        which have not been rescued according to the mapfile of the image.
+        """
+        if self.__mapfile is None:
+            return []
+        offset = block * block_size
+        return [(first - offset, end - offset) for first, end in self.__mapfile.unfinished(offset, offset + block_size)]
+
+    def read_block(self, block, block_size):
+        offset = block * block_size
+        for _ in range(self.__retries + 1):
+            data = self.__try_read(offset, block_size)
+            if data is not None:
+                return data, []
+        data = bytearray(block_size)
+        bad = []
+        self.__bisect(offset, block_size, data, offset, bad)
+        return bytes(data), bad
+
+    def __bisect(self, offset, length, data, block_offset, bad):
+        if length > 512:
+            half = length // 2 // 512 * 512 or 512
+            self.__bisect(offset, half, data, block_offset, bad)
+            self.__bisect(offset + half, length - half, data, block_offset, bad)
+            return
+        part = self.__try_read(offset, length)
+        for _ in range(self.__retries):
+            if part is not None:
+                break
+            part = self.__try_read(offset, length)
+        if part is not None:
+            data[offset - block_offset:offset - block_offset + length] = part
+            return
+        first = offset - block_offset
+        if bad and bad[-1][1] == first:
+            bad[-1] = (bad[-1][0], first + length)
+        else:
+            bad.append((first, first + length))
+        if self.error_map is not None:
+            self.error_map.set(offset, offset + length, "-")
+
+    def __try_read(self, offset, length):
+        try:
+            data = os.pread(self.__fd, length, offset)
+        except OSError:
+            return None
+        if len(data) != length:
+            return None
+        if self.error_map is not None:
+            self.error_map.set(offset, offset + length, "+")
+        return data
+
+
+def dev_punch_hole(fd, offset, length):
+    """Discards the given range of a block device, or punches a hole into a regular file."""
+    if stat.S_ISBLK(os.fstat(fd).st_mode):
+        fcntl.ioctl(fd, BLKDISCARD, struct.pack("=QQ", offset, length))
     else:
-        raise ValueError("invalid boolean", string)
+        libc = ctypes.CDLL(None, use_errno=True)
+        mode = FALLOC_FL_KEEP_SIZE | FALLOC_FL_PUNCH_HOLE
+        if libc.fallocate(fd, mode, ctypes.c_int64(offset), ctypes.c_int64(length)) != 0:
+            errno = ctypes.get_errno()
+            raise OSError(errno, os.strerror(errno))
+
+
+def dev_copy_block(src_fd, src_block, dest_fd, dest_block, block_size):
+    """Copies a block and returns the number of bytes copied,
+    which is less than the block size if reading the source failed within the block.
+    Nothing is copied if the source ends within the block.
+    """
+    if os.lseek(src_fd, 0, os.SEEK_END) < (src_block + 1) * block_size:
+        return 0
+    os.lseek(dest_fd, dest_block * block_size, os.SEEK_SET)
+    copied = 0
+    while copied < block_size:
+        try:
+            count = os.sendfile(dest_fd, src_fd, src_block * block_size + copied, block_size - copied)
+        except OSError:
+            break
+        if count == 0:
+            break
+        copied += count
+    return copied
 
 
 if __name__ == "__main__":
@@ -0,0 +1,151 @@
+# -*- coding: utf-8 -*-
+"""Reads and writes the mapfiles of GNU ddrescue.
+``cache_verify.py`` records the unreadable ranges of the devices in them,
+and ``cache_verify.py`` and ``cache_guess_mapping.py`` read them
+to leave out the ranges of imaged devices which have not been rescued.
+
+.. [1] Format of the mapfiles of GNU ddrescue:
+   https://www.gnu.org/software/ddrescue/manual/ddrescue_manual.html#Mapfile-structure
+"""
+
+import bisect, os, shlex, sys, threading
+from datetime import datetime
+
+
+class Mapfile:
+    """Status of the ranges of a device in the format of the mapfiles of GNU ddrescue [1]_.
+    Used to record which ranges have been read successfully,
+    and to read which ranges of an image have been rescued.
+    Ranges which have not been read are marked as non-tried.
+
+    Rescued ranges are collected unsorted and only merged when they are needed,
+    so that recording the random reads of a large device stays cheap.
+    They take precedence over the other statuses, since their data has been read at least once.
+    """
+
+    def __init__(self, size):
+        self.__size = size
+        # Sorted and merged rescued ranges
+        self.__starts = []
+        self.__ends = []
+        self.__pending = []
+        # Ranges with a status other than rescued or non-tried, e.g. bad sectors
+        self.__others = []
+        self.__lock = threading.Lock()
+
+    def set(self, first, end, status):
+        first = min(first, self.__size)
+        end = min(end, self.__size)
+        if first >= end:
+            return
+        with self.__lock:
+            if status == "+":
+                self.__pending.append((first, end))
+                if len(self.__pending) >= max(4096, len(self.__starts)):
+                    self.__merge()
+            elif status != "?":
+                self.__others.append((first, end, status))
+
+    def __merge(self):
+        if not self.__pending:
+            return
+        ranges = sorted(zip(self.__starts, self.__ends))
+        ranges.extend(self.__pending)
+        ranges.sort()
+        starts = []
+        ends = []
+        for first, end in ranges:
+            if ends and first <= ends[-1]:
+                ends[-1] = max(ends[-1], end)
+            else:
+                starts.append(first)
+                ends.append(end)
+        self.__starts = starts
+        self.__ends = ends
+        self.__pending = []
+
+    @classmethod
+    def read(cls, path):
+        """Reads a mapfile written by ddrescue."""
+        blocks = []
+        status_line_seen = False
+        with open(path) as file:
+            for number, line in enumerate(file, 1):
+                line = line.split("#", 1)[0].strip()
+                if not line:
+                    continue
+                if not status_line_seen:
+                    # The first line contains the current position and status of ddrescue
+                    status_line_seen = True
+                    continue
+                try:
+                    position, size, status = line.split()
+                    blocks.append((int(position, 0), int(size, 0), status))
+                except ValueError:
+                    sys.exit(f"Invalid mapfile {path}: line {number}")
+        mapfile = cls(max((position + size for position, size, _ in blocks), default=0))
+        for position, size, status in blocks:
+            mapfile.set(position, position + size, status)
+        return mapfile
+
+    def unfinished(self, first, end):
+        """Returns the ranges between ``first`` and ``end`` which have not been rescued,
+        including ranges beyond the end of the mapfile.
+        """
+        with self.__lock:
+            self.__merge()
+            starts = self.__starts
+            ends = self.__ends
+        ranges = []
+        position = first
+        index = max(bisect.bisect_right(starts, first) - 1, 0)
+        while index < len(starts) and starts[index] < end:
+            if starts[index] > position:
+                ranges.append((position, starts[index]))
+            position = max(position, ends[index])
+            index += 1
+        if position < end:
+            ranges.append((position, end))
+        return ranges
+
+    def write(self, path):
+        entries = []
+        def add(first, end, status):
+            if first >= end:
+                return
+            if entries and entries[-1][2] == status:
+                entries[-1] = (entries[-1][0], end, status)
+            else:
+                entries.append((first, end, status))
+        with self.__lock:
+            others = sorted(self.__others)
+        index = 0
+        position = 0
+        for gap_first, gap_end in self.unfinished(0, self.__size):
+            add(position, gap_first, "+")
+            position = gap_first
+            while index < len(others) and others[index][1] <= gap_first:
+                index += 1
+            for other_first, other_end, status in others[index:]:
+                if other_first >= gap_end:
+                    break
+                other_first = max(other_first, position)
+                other_end = min(other_end, gap_end)
+                if other_first < other_end:
+                    add(position, other_first, "?")
+                    add(other_first, other_end, status)
+                    position = other_end
+            add(position, gap_end, "?")
+            position = gap_end
+        add(position, self.__size, "+")
+
+        now = datetime.now().strftime("%Y-%m-%d %H:%M:%S")
+        with open(path, "w") as file:
+            file.write(f"# Mapfile. Created by {os.path.basename(sys.argv[0])}\n")
+            file.write(f"# Command line: {shlex.join(sys.argv)}\n")
+            file.write(f"# Current time: {now}\n")
+            file.write("# current_pos  current_status  current_pass\n")
+            file.write("0x00000000     +               1\n")
+            file.write("#      pos        size  status\n")
+            for first, end, status in entries:
+                file.write(f"0x{first:08X}  0x{end - first:08X}  {status}\n")
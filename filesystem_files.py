@@ -0,0 +1,1334 @@
+#!/usr/bin/env python3
+# -*- coding: utf-8 -*-
+"""Maps ranges of a device to the files and filesystem structures stored there.
+
+Block numbers of mismatched or dirty blocks say little about the damage.
+This script reads the filesystem on the origin device, or on an emulated writeback view,
+and lists for each range the inodes and path names whose data is stored there.
+Ranges within filesystem metadata, like inode tables, bitmaps or the journal,
+are flagged as such, together with the inodes described by affected inode tables.
+The filesystem is only read, never modified. Supported are:
+
+* ext4 [1]_, including ext2 and ext3 with block maps instead of extents.
+* XFS [2]_, whose inodes are found by the inode btrees of the allocation groups,
+  and whose files are mapped by their extent maps.
+  The blocks of the btrees of the allocation groups and the log are flagged as metadata.
+* btrfs [3]_, whose logical addresses are mapped to the device by the chunk tree.
+  The extent tree tells the owners of all allocated extents:
+  the tree of a block, or the subvolume, inode and offset of data.
+  For compressed data, the offsets within the file are approximate.
+  Only the copies stored on the given device are reported,
+  chunks using RAID5 or RAID6 are not supported.
+
+The ranges are given in sectors, or taken from a report of ``cache_verify.py``,
+in which case the block size of the cache in sectors is required. ::
+
+    $ ./filesystem_files.py --range 2048-2175 /dev/mapper/data
+    $ ./cache_verify.py --report report.jsonl metadata.xml /dev/mapper/cache /dev/mapper/data
+    $ ./filesystem_files.py --report report.jsonl --block-size 128 /dev/mapper/data
+
+If the filesystem does not start at the beginning of the device,
+for example within a partition, its position is given by ``--offset`` in bytes.
+
+``cache_verify.py`` does the same for the blocks it finds at risk with ``--files``. ::
+
+    $ ./cache_verify.py --files metadata.xml /dev/mapper/cache /dev/mapper/data
+    $ ./cache_verify.py --files --emulate writeback metadata.xml /dev/mapper/cache /dev/mapper/data
+
+.. [1] Description of the on-disk format of ext4:
+   https://www.kernel.org/doc/html/latest/filesystems/ext4/index.html
+.. [2] Description of the on-disk format of XFS:
+   https://mirrors.edge.kernel.org/pub/linux/utils/fs/xfs/docs/xfs_filesystem_structure.pdf
+.. [3] Description of the on-disk format of btrfs:
+   https://btrfs.readthedocs.io/en/latest/dev/On-disk-format.html
+"""
+
+import bisect, csv, json, os, stat, struct, sys
+from argparse import ArgumentParser
+from dataclasses import dataclass
+from pathlib import Path
+
+
+# Statuses of cache_verify.py whose origin blocks are at risk
+AFFECTED_STATUSES = {"mismatch", "read-error", "unrecovered", "skipped-dirty"}
+# Extent kinds which belong to a file; all others are filesystem metadata
+FILE_KINDS = {"data", "directory", "extent-tree", "indirect-block", "xattr"}
+INODE_TABLE_CHUNK = 1024 * 1024
+
+EXT4_SUPERBLOCK_OFFSET = 1024
+EXT4_SUPERBLOCK_SIZE = 1024
+EXT4_MAGIC = 0xEF53
+EXT4_ROOT_INODE = 2
+EXT4_GOOD_OLD_FIRST_INODE = 11
+EXT4_GOOD_OLD_INODE_SIZE = 128
+
+EXT4_COMPAT_HAS_JOURNAL = 0x4
+EXT4_COMPAT_SPARSE_SUPER2 = 0x200
+EXT4_INCOMPAT_FILETYPE = 0x2
+EXT4_INCOMPAT_JOURNAL_DEV = 0x8
+EXT4_INCOMPAT_META_BG = 0x10
+EXT4_INCOMPAT_64BIT = 0x80
+EXT4_RO_COMPAT_SPARSE_SUPER = 0x1
+EXT4_RO_COMPAT_GDT_CSUM = 0x10
+EXT4_RO_COMPAT_METADATA_CSUM = 0x400
+
+EXT4_BG_INODE_UNINIT = 0x1
+EXT4_EXTENTS_FL = 0x80000
+EXT4_INLINE_DATA_FL = 0x10000000
+EXT4_EXTENT_MAGIC = 0xF30A
+EXT4_EXTENT_HEADER_FORMAT = "<HHHHI"
+EXT4_EXTENT_HEADER_SIZE = struct.calcsize(EXT4_EXTENT_HEADER_FORMAT)
+EXT4_EXTENT_ENTRY_FORMAT = "<IHHI"
+EXT4_EXTENT_INDEX_FORMAT = "<IIH2x"
+EXT4_EXTENT_ENTRY_SIZE = 12
+EXT4_EXTENT_INIT_MAX_LEN = 32768
+EXT4_DIRECT_BLOCKS = 12
+EXT4_FT_DIR = 2
+
+XFS_SB_MAGIC = b"XFSB"
+XFS_AGF_MAGIC = b"XAGF"
+XFS_AGI_MAGIC = b"XAGI"
+XFS_DINODE_MAGIC = b"IN"
+XFS_VERSION_5 = 5
+XFS_SB_VERSION2_FTYPE = 0x200
+XFS_RO_COMPAT_FINOBT = 0x1
+XFS_RO_COMPAT_RMAPBT = 0x2
+XFS_RO_COMPAT_REFLINK = 0x4
+XFS_INCOMPAT_FTYPE = 0x1
+XFS_INCOMPAT_SPINODES = 0x2
+XFS_NULL_INODE = 2**64 - 1
+XFS_INODES_PER_CHUNK = 64
+XFS_INODES_PER_HOLEMASK_BIT = 4
+XFS_DIFLAG_REALTIME = 0x1
+XFS_DIFLAG2_NREXT64 = 0x10
+XFS_DINODE_FMT_LOCAL = 1
+XFS_DINODE_FMT_EXTENTS = 2
+XFS_DINODE_FMT_BTREE = 3
+XFS_BMBT_MAGICS = (b"BMAP", b"BMA3")
+XFS_BMBT_REC_SIZE = 16
+XFS_DIR2_LEAF_OFFSET = 32 * 1024**3
+XFS_DIR2_BLOCK_MAGICS = (b"XD2B", b"XDB3")
+XFS_DIR2_DATA_MAGICS = (b"XD2D", b"XDD3")
+XFS_DIR2_DATA_FREE_TAG = 0xFFFF
+XFS_DIR3_FT_DIR = 2
+
+BTRFS_SUPERBLOCK_OFFSETS = (64 * 1024, 64 * 1024**2, 256 * 1024**3)
+BTRFS_SUPERBLOCK_SIZE = 4096
+BTRFS_MAGIC = b"_BHRfS_M"
+BTRFS_HEADER_SIZE = 101
+BTRFS_ITEM_SIZE = 25
+BTRFS_KEY_POINTER_SIZE = 33
+BTRFS_KEY_FORMAT = "<QBQ"
+BTRFS_KEY_SIZE = struct.calcsize(BTRFS_KEY_FORMAT)
+BTRFS_CHUNK_ITEM_FORMAT = "<QQQQIIIHH"
+BTRFS_CHUNK_ITEM_SIZE = struct.calcsize(BTRFS_CHUNK_ITEM_FORMAT)
+BTRFS_STRIPE_SIZE = 32
+BTRFS_ROOT_TREE = 1
+BTRFS_EXTENT_TREE = 2
+BTRFS_FS_TREE = 5
+BTRFS_FIRST_FREE_OBJECTID = 256
+BTRFS_LAST_FREE_OBJECTID = 2**64 - 256
+BTRFS_TREES = {1: "root-tree", 2: "extent-tree", 3: "chunk-tree", 4: "device-tree", 5: "fs-tree",
+        7: "checksum-tree", 8: "quota-tree", 9: "uuid-tree", 10: "free-space-tree", 11: "block-group-tree",
+        12: "raid-stripe-tree", 2**64 - 9: "data-relocation-tree"}
+
+BTRFS_INODE_REF_KEY = 12
+BTRFS_INODE_EXTREF_KEY = 13
+BTRFS_EXTENT_DATA_KEY = 108
+BTRFS_ROOT_ITEM_KEY = 132
+BTRFS_ROOT_BACKREF_KEY = 144
+BTRFS_EXTENT_ITEM_KEY = 168
+BTRFS_METADATA_ITEM_KEY = 169
+BTRFS_EXTENT_OWNER_REF_KEY = 172
+BTRFS_TREE_BLOCK_REF_KEY = 176
+BTRFS_EXTENT_DATA_REF_KEY = 178
+BTRFS_SHARED_BLOCK_REF_KEY = 182
+BTRFS_SHARED_DATA_REF_KEY = 184
+BTRFS_CHUNK_ITEM_KEY = 228
+
+BTRFS_EXTENT_FLAG_DATA = 0x1
+BTRFS_EXTENT_FLAG_TREE_BLOCK = 0x2
+BTRFS_BLOCK_GROUP_RAID0 = 0x8
+BTRFS_BLOCK_GROUP_RAID10 = 0x40
+BTRFS_BLOCK_GROUP_RAID56 = 0x80 | 0x100
+BTRFS_FILE_EXTENT_INLINE = 0
+
+
+def main():
+    argparser = ArgumentParser()
+    argparser.add_argument("device", type=Path)
+    argparser.add_argument("--range", action="append", default=[], metavar="FIRST-LAST",
+            help="Range of sectors, e.g. 2048-2175 or 2048")
+    argparser.add_argument("--report", type=Path, metavar="FILE",
+            help="Report of cache_verify.py as JSON Lines or CSV, the affected origin blocks are mapped")
+    argparser.add_argument("--block-size", type=int,
+            help="Size of cache blocks in sectors, required by --report")
+    argparser.add_argument("--offset", type=int, default=0, metavar="BYTES",
+            help="Position of the filesystem on the device")
+    argparser.add_argument("--format", choices=["text", "json"], default="text")
+    args = argparser.parse_args()
+
+    if args.report is not None and args.block_size is None:
+        sys.exit("The option --report requires --block-size")
+    ranges = [parse_range(text) for text in args.range]
+    if args.report is not None:
+        ranges.extend(read_report(args.report, args.block_size))
+    if not ranges:
+        sys.exit("No ranges given, use --range or --report")
+
+    impacts, files = affected_files(args.device, ranges, offset=args.offset)
+    if args.format == "json":
+        json.dump({
+            "ranges": [{
+                "first": impact.first,
+                "end": impact.end,
+                "label": impact.label,
+                "hits": [{
+                    "first": hit.first,
+                    "end": hit.end,
+                    "kind": hit.extent.kind,
+                    "metadata": hit.extent.kind not in FILE_KINDS,
+                    "root": hit.extent.root,
+                    "inode": hit.extent.inode,
+                    "file_offset": None if hit.extent.offset is None else hit.extent.offset + hit.first - hit.extent.first,
+                    "group": hit.extent.group,
+                    "inodes": None if hit.inodes is None else [hit.inodes.start, hit.inodes.stop - 1],
+                } for hit in impact.hits],
+            } for impact in impacts],
+            "files": [{"root": root, "inode": inode, "paths": paths} for (root, inode), paths in files.items()],
+        }, sys.stdout, indent=2)
+        print()
+    else:
+        print_impacts(impacts, files)
+
+
+def parse_range(text):
+    """Parses a range of sectors as printed by ``cache_verify.py`` into a range of bytes."""
+    first, _, last = text.partition("-")
+    try:
+        first = int(first)
+        last = int(last) if last else first
+    except ValueError:
+        sys.exit(f"Invalid range of sectors: {text}")
+    if last < first:
+        sys.exit(f"Invalid range of sectors: {text}")
+    return 512 * first, 512 * (last + 1), f"sectors {text}"
+
+
+def read_report(path, block_size):
+    """Returns the byte ranges of the origin blocks at risk according to a report of ``cache_verify.py``."""
+    ranges = []
+    with open(path, newline="") as file:
+        is_jsonl = file.read(1) == "{"
+        file.seek(0)
+        records = (json.loads(line) for line in file if line.strip()) if is_jsonl else csv.DictReader(file)
+        for record in records:
+            if record.get("type", "mapping") != "mapping" or record["status"] not in AFFECTED_STATUSES:
+                continue
+            origin_block = int(record["origin_block"])
+            ranges.append((512 * block_size * origin_block, 512 * block_size * (origin_block + 1),
+                           f"origin block {origin_block} ({record['status']})"))
+    return ranges
+
+
+@dataclass(frozen=True, kw_only=True, slots=True)
+class Extent:
+    """A range of bytes on the device used by a file or a filesystem structure.
+    ``offset`` is the position of the range within the file,
+    ``inodes`` are the inodes stored within an inode table.
+    Inode numbers of btrfs are only unique within their subvolume, given by ``root``.
+    """
+    first: int
+    end: int
+    kind: str
+    root: int | None = None
+    inode: int | None = None
+    offset: int | None = None
+    group: int | None = None
+    inodes: range | None = None
+
+
+@dataclass(frozen=True, kw_only=True, slots=True)
+class Hit:
+    first: int
+    end: int
+    extent: Extent
+    inodes: range | None
+
+
+@dataclass(frozen=True, kw_only=True, slots=True)
+class Impact:
+    first: int
+    end: int
+    label: str
+    hits: list
+
+
+def affected_files(device, ranges, *, offset=0):
+    """Maps the given byte ranges of the device to the extents of the filesystem overlapping them.
+    ``ranges`` contains tuples of the first byte, the end and a label.
+    Returns the impacts in the order of the ranges,
+    and the path names of all affected inodes, keyed by their root and number.
+    """
+    with open(device, "rb", buffering=0) as file:
+        filesystem = open_filesystem(file.fileno(), offset)
+        impacts = [Impact(first=first, end=end, label=label, hits=[]) for first, end, label in ranges]
+        by_first = sorted(impacts, key=lambda impact: impact.first)
+        firsts = [impact.first for impact in by_first]
+        # Ranges may overlap, so the search has to go back by the longest range
+        longest = max(impact.end - impact.first for impact in impacts)
+        for extent in filesystem.extents():
+            index = bisect.bisect_left(firsts, extent.first - longest + 1)
+            while index < len(by_first) and by_first[index].first < extent.end:
+                impact = by_first[index]
+                index += 1
+                first = max(impact.first, extent.first)
+                end = min(impact.end, extent.end)
+                if first >= end:
+                    continue
+                inodes = None
+                if extent.inodes is not None:
+                    inode_size = (extent.end - extent.first) // len(extent.inodes)
+                    inodes = extent.inodes[(first - extent.first) // inode_size:
+                                           -(-(end - extent.first) // inode_size)]
+                impact.hits.append(Hit(first=first, end=end, extent=extent, inodes=inodes))
+        for impact in impacts:
+            impact.hits.sort(key=lambda hit: (hit.first, hit.extent.kind))
+
+        file_inodes = set()
+        table_inodes = set()
+        for impact in impacts:
+            for hit in impact.hits:
+                if hit.extent.kind in FILE_KINDS:
+                    file_inodes.add((hit.extent.root, hit.extent.inode))
+                elif hit.inodes is not None:
+                    table_inodes.update((hit.extent.root, inode) for inode in hit.inodes)
+        paths = filesystem.paths(file_inodes | table_inodes)
+    # Inodes of affected inode tables are only listed if they are in use
+    files = {inode: sorted(paths.get(inode, [])) for inode in sorted(file_inodes | table_inodes)
+             if inode in file_inodes or inode in paths}
+    return impacts, files
+
+
+def print_impacts(impacts, files, file=sys.stdout):
+    def describe_inode(key):
+        root, inode = key
+        text = f"inode {inode}" if root is None else f"inode {inode} of subvolume {root}"
+        paths = files.get(key)
+        return f"{text}: {', '.join(paths)}" if paths else f"{text} (no path found)"
+
+    for impact in impacts:
+        print(f"{impact.label}: bytes {impact.first}-{impact.end - 1}", file=file)
+        if not impact.hits:
+            print("  not used by any file or filesystem structure", file=file)
+        for hit in impact.hits:
+            extent = hit.extent
+            text = extent.kind
+            if extent.kind not in FILE_KINDS:
+                text += " (metadata)"
+            if extent.group is not None:
+                text += f" of group {extent.group}"
+            if extent.offset is not None:
+                file_first = extent.offset + hit.first - extent.first
+                text += f", file bytes {file_first}-{file_first + hit.end - hit.first - 1}"
+            if extent.inode is not None and extent.kind in FILE_KINDS:
+                text += f", {describe_inode((extent.root, extent.inode))}"
+            elif extent.inode is not None:
+                text += f", inode {extent.inode}"
+            elif extent.root is not None:
+                text += f", subvolume {extent.root}"
+            if hit.inodes is not None:
+                text += f", inodes {hit.inodes.start}-{hit.inodes.stop - 1}"
+            print(f"  {text}", file=file)
+            if hit.inodes is not None:
+                for inode in hit.inodes:
+                    if (extent.root, inode) in files:
+                        print(f"    {describe_inode((extent.root, inode))}", file=file)
+    print(f"{len(files)} files affected", file=file)
+    for key in files:
+        print(describe_inode(key), file=file)
+
+
+def open_filesystem(fd, offset):
+    for filesystem in FILESYSTEMS:
+        if filesystem.detect(fd, offset):
+            return filesystem(fd, offset)
+    sys.exit("Unsupported or unknown filesystem")
+
+
+class Ext4:
+    """Reads the layout of an ext2, ext3 or ext4 filesystem."""
+
+    @staticmethod
+    def detect(fd, offset):
+        data = os.pread(fd, EXT4_SUPERBLOCK_SIZE, offset + EXT4_SUPERBLOCK_OFFSET)
+        return len(data) == EXT4_SUPERBLOCK_SIZE and struct.unpack_from("<H", data, 0x38)[0] == EXT4_MAGIC
+
+    def __init__(self, fd, offset):
+        self.__fd = fd
+        self.__offset = offset
+        sb = os.pread(fd, EXT4_SUPERBLOCK_SIZE, offset + EXT4_SUPERBLOCK_OFFSET)
+        blocks_count_lo, = struct.unpack_from("<I", sb, 0x04)
+        self.__first_data_block, log_block_size = struct.unpack_from("<II", sb, 0x14)
+        self.__blocks_per_group, = struct.unpack_from("<I", sb, 0x20)
+        self.__inodes_per_group, = struct.unpack_from("<I", sb, 0x28)
+        rev_level, = struct.unpack_from("<I", sb, 0x4C)
+        first_inode, inode_size = struct.unpack_from("<IH", sb, 0x54)
+        self.__compat, self.__incompat, self.__ro_compat = struct.unpack_from("<III", sb, 0x5C)
+        self.__reserved_gdt_blocks, = struct.unpack_from("<H", sb, 0xCE)
+        journal_inode, = struct.unpack_from("<I", sb, 0xE0)
+        desc_size, = struct.unpack_from("<H", sb, 0xFE)
+        self.__first_meta_bg, = struct.unpack_from("<I", sb, 0x104)
+        blocks_count_hi, = struct.unpack_from("<I", sb, 0x150)
+        self.__backup_groups = struct.unpack_from("<II", sb, 0x24C)
+        quota_inodes = struct.unpack_from("<II", sb, 0x240) + struct.unpack_from("<I", sb, 0x26C)
+
+        self.__block_size = 1024 << log_block_size
+        self.__blocks_count = blocks_count_lo
+        self.__desc_size = 32
+        if self.__incompat & EXT4_INCOMPAT_64BIT:
+            self.__blocks_count |= blocks_count_hi << 32
+            self.__desc_size = max(desc_size, 64)
+        if rev_level == 0:
+            first_inode = EXT4_GOOD_OLD_FIRST_INODE
+            inode_size = EXT4_GOOD_OLD_INODE_SIZE
+        self.__first_inode = first_inode
+        self.__inode_size = inode_size
+        if self.__incompat & EXT4_INCOMPAT_JOURNAL_DEV:
+            sys.exit("The device contains an external ext4 journal, not a filesystem")
+        self.__groups = -(-(self.__blocks_count - self.__first_data_block) // self.__blocks_per_group)
+        self.__descs_per_block = self.__block_size // self.__desc_size
+        self.__gdt_blocks = -(-self.__groups // self.__descs_per_block)
+
+        # Special inodes below the first regular inode, except the root directory, are metadata
+        self.__special_inodes = {1: "bad-blocks", 5: "boot-loader"}
+        for inode in quota_inodes:
+            if inode != 0:
+                self.__special_inodes[inode] = "quota"
+        if self.__compat & EXT4_COMPAT_HAS_JOURNAL and journal_inode != 0:
+            self.__special_inodes[journal_inode] = "journal"
+        self.__descriptors = self.__read_descriptors()
+
+    def __read_block(self, block):
+        data = os.pread(self.__fd, self.__block_size, self.__offset + block * self.__block_size)
+        if len(data) != self.__block_size:
+            sys.exit(f"Incomplete ext4 block: {block}")
+        return data
+
+    def __block_extent(self, block, count, kind, **kwargs):
+        first = self.__offset + block * self.__block_size
+        return Extent(first=first, end=first + count * self.__block_size, kind=kind, **kwargs)
+
+    def __group_first_block(self, group):
+        return self.__first_data_block + group * self.__blocks_per_group
+
+    def __has_super(self, group):
+        if group == 0:
+            return True
+        if self.__compat & EXT4_COMPAT_SPARSE_SUPER2:
+            return group in self.__backup_groups
+        if not self.__ro_compat & EXT4_RO_COMPAT_SPARSE_SUPER or group == 1:
+            return True
+        for base in (3, 5, 7):
+            power = base
+            while power < group:
+                power *= base
+            if power == group:
+                return True
+        return False
+
+    def __descriptor_block(self, index):
+        """Returns the location of a block of the group descriptor table."""
+        if self.__incompat & EXT4_INCOMPAT_META_BG and index >= self.__first_meta_bg:
+            group = index * self.__descs_per_block
+            return self.__group_first_block(group) + self.__has_super(group)
+        return self.__first_data_block + 1 + index
+
+    def __read_descriptors(self):
+        descriptors = []
+        for index in range(self.__gdt_blocks):
+            data = self.__read_block(self.__descriptor_block(index))
+            for position in range(0, self.__block_size, self.__desc_size):
+                if len(descriptors) == self.__groups:
+                    break
+                block_bitmap, inode_bitmap, inode_table = struct.unpack_from("<III", data, position)
+                flags, = struct.unpack_from("<H", data, position + 0x12)
+                itable_unused, = struct.unpack_from("<H", data, position + 0x1C)
+                if self.__desc_size >= 64:
+                    block_bitmap_hi, inode_bitmap_hi, inode_table_hi = struct.unpack_from("<III", data, position + 0x20)
+                    itable_unused_hi, = struct.unpack_from("<H", data, position + 0x32)
+                    block_bitmap |= block_bitmap_hi << 32
+                    inode_bitmap |= inode_bitmap_hi << 32
+                    inode_table |= inode_table_hi << 32
+                    itable_unused |= itable_unused_hi << 16
+                descriptors.append((block_bitmap, inode_bitmap, inode_table, flags, itable_unused))
+        return descriptors
+
+    def extents(self):
+        """Yields the extents of all filesystem structures and all files."""
+        yield from self.__metadata_extents()
+        for inode, data in self.__used_inodes():
+            yield from self.__inode_extents(inode, data)
+
+    def __metadata_extents(self):
+        meta_bg = self.__incompat & EXT4_INCOMPAT_META_BG
+        for group, (block_bitmap, inode_bitmap, inode_table, _, _) in enumerate(self.__descriptors):
+            first_block = self.__group_first_block(group)
+            if self.__has_super(group):
+                yield self.__block_extent(first_block, 1, "superblock", group=group)
+                gdt_blocks = min(self.__gdt_blocks, self.__first_meta_bg) if meta_bg else self.__gdt_blocks
+                if gdt_blocks:
+                    yield self.__block_extent(first_block + 1, gdt_blocks, "group-descriptors", group=group)
+                if self.__reserved_gdt_blocks:
+                    yield self.__block_extent(first_block + 1 + gdt_blocks, self.__reserved_gdt_blocks,
+                                              "reserved-gdt", group=group)
+            meta_group, index = divmod(group, self.__descs_per_block)
+            if meta_bg and meta_group >= self.__first_meta_bg and index in (0, 1, self.__descs_per_block - 1):
+                yield self.__block_extent(first_block + self.__has_super(group), 1, "group-descriptors", group=group)
+            yield self.__block_extent(block_bitmap, 1, "block-bitmap", group=group)
+            yield self.__block_extent(inode_bitmap, 1, "inode-bitmap", group=group)
+            first_inode = group * self.__inodes_per_group + 1
+            first = self.__offset + inode_table * self.__block_size
+            yield Extent(first=first, end=first + self.__inodes_per_group * self.__inode_size, kind="inode-table",
+                         group=group, inodes=range(first_inode, first_inode + self.__inodes_per_group))
+
+    def __used_inodes(self):
+        """Yields the number and raw data of all inodes in use."""
+        checksums = self.__ro_compat & (EXT4_RO_COMPAT_GDT_CSUM | EXT4_RO_COMPAT_METADATA_CSUM)
+        inodes_per_chunk = max(1, INODE_TABLE_CHUNK // self.__inode_size)
+        for group, (_, _, inode_table, flags, itable_unused) in enumerate(self.__descriptors):
+            count = self.__inodes_per_group
+            if checksums:
+                if flags & EXT4_BG_INODE_UNINIT:
+                    continue
+                count -= min(itable_unused, count)
+            for chunk_start in range(0, count, inodes_per_chunk):
+                chunk_count = min(inodes_per_chunk, count - chunk_start)
+                data = os.pread(self.__fd, chunk_count * self.__inode_size, self.__offset
+                                + inode_table * self.__block_size + chunk_start * self.__inode_size)
+                for index in range(len(data) // self.__inode_size):
+                    inode = group * self.__inodes_per_group + chunk_start + index + 1
+                    raw = data[index * self.__inode_size:(index + 1) * self.__inode_size]
+                    mode, = struct.unpack_from("<H", raw, 0x00)
+                    links_count, = struct.unpack_from("<H", raw, 0x1A)
+                    if inode in self.__special_inodes:
+                        yield inode, raw
+                    elif inode >= self.__first_inode or inode == EXT4_ROOT_INODE:
+                        if mode != 0 and links_count != 0:
+                            yield inode, raw
+
+    def __read_inode(self, inode):
+        group, index = divmod(inode - 1, self.__inodes_per_group)
+        inode_table = self.__descriptors[group][2]
+        return os.pread(self.__fd, self.__inode_size,
+                        self.__offset + inode_table * self.__block_size + index * self.__inode_size)
+
+    def __inode_extents(self, inode, raw):
+        """Yields the extents of the data and the block mapping of an inode."""
+        mode, = struct.unpack_from("<H", raw, 0x00)
+        size_lo, = struct.unpack_from("<I", raw, 0x04)
+        flags, = struct.unpack_from("<I", raw, 0x20)
+        file_acl_lo, = struct.unpack_from("<I", raw, 0x68)
+        file_acl_hi, = struct.unpack_from("<H", raw, 0x76)
+        file_acl = file_acl_lo | file_acl_hi << 32
+        if file_acl:
+            yield self.__block_extent(file_acl, 1, "xattr", inode=inode)
+
+        if inode in self.__special_inodes:
+            kind = self.__special_inodes[inode]
+        elif stat.S_ISDIR(mode):
+            kind = "directory"
+        elif stat.S_ISREG(mode) or stat.S_ISLNK(mode):
+            kind = "data"
+        else:
+            return
+        if flags & EXT4_INLINE_DATA_FL:
+            return
+        # Fast symlinks store the target within the inode
+        if stat.S_ISLNK(mode) and not flags & EXT4_EXTENTS_FL and size_lo < 60:
+            return
+        i_block = raw[0x28:0x28 + 60]
+        if flags & EXT4_EXTENTS_FL:
+            mappings = self.__extent_tree(inode, i_block)
+        else:
+            mappings = self.__block_map(inode, i_block)
+        for mapping in mappings:
+            if isinstance(mapping, Extent):
+                yield mapping
+            else:
+                logical, physical, count = mapping
+                yield self.__block_extent(physical, count, kind, inode=inode, offset=logical * self.__block_size)
+
+    def __extent_tree(self, inode, node, depth_limit=8):
+        """Yields the tree blocks and the mapped ranges of an extent tree."""
+        magic, entries, _, depth, _ = struct.unpack_from(EXT4_EXTENT_HEADER_FORMAT, node)
+        if magic != EXT4_EXTENT_MAGIC or depth_limit == 0:
+            print(f"inode {inode}: invalid extent tree node", file=sys.stderr)
+            return
+        for index in range(entries):
+            position = EXT4_EXTENT_HEADER_SIZE + index * EXT4_EXTENT_ENTRY_SIZE
+            if position + EXT4_EXTENT_ENTRY_SIZE > len(node):
+                break
+            if depth > 0:
+                _, leaf_lo, leaf_hi = struct.unpack_from(EXT4_EXTENT_INDEX_FORMAT, node, position)
+                leaf = leaf_lo | leaf_hi << 32
+                yield self.__block_extent(leaf, 1, "extent-tree", inode=inode)
+                yield from self.__extent_tree(inode, self.__read_block(leaf), depth_limit - 1)
+            else:
+                logical, length, start_hi, start_lo = struct.unpack_from(EXT4_EXTENT_ENTRY_FORMAT, node, position)
+                # Uninitialized extents are allocated, but read as zeros
+                if length > EXT4_EXTENT_INIT_MAX_LEN:
+                    length -= EXT4_EXTENT_INIT_MAX_LEN
+                yield logical, start_lo | start_hi << 32, length
+
+    def __block_map(self, inode, i_block):
+        """Yields the indirect blocks and the mapped ranges of a block map as used by ext2 and ext3."""
+        pointers = struct.unpack_from("<15I", i_block)
+        per_block = self.__block_size // 4
+        run = None
+        def mapped(logical, physical):
+            nonlocal run
+            if run is not None and run[0] + run[2] == logical and run[1] + run[2] == physical:
+                run = (run[0], run[1], run[2] + 1)
+                return None
+            previous, run = run, (logical, physical, 1)
+            return previous
+
+        def walk(block, level, logical):
+            yield self.__block_extent(block, 1, "indirect-block", inode=inode)
+            children = struct.unpack_from(f"<{per_block}I", self.__read_block(block))
+            span = per_block ** (level - 1)
+            for index, child in enumerate(children):
+                if child == 0:
+                    continue
+                if level == 1:
+                    previous = mapped(logical + index, child)
+                    if previous is not None:
+                        yield previous
+                else:
+                    yield from walk(child, level - 1, logical + index * span)
+
+        for index, block in enumerate(pointers[:EXT4_DIRECT_BLOCKS]):
+            if block != 0:
+                previous = mapped(index, block)
+                if previous is not None:
+                    yield previous
+        logical = EXT4_DIRECT_BLOCKS
+        for level, block in enumerate(pointers[EXT4_DIRECT_BLOCKS:], 1):
+            if block != 0:
+                yield from walk(block, level, logical)
+            logical += per_block ** level
+        if run is not None:
+            yield run
+
+    def paths(self, wanted):
+        """Returns the path names of the wanted inodes by walking the directory tree."""
+        result = {}
+        if (None, EXT4_ROOT_INODE) in wanted:
+            result[None, EXT4_ROOT_INODE] = ["/"]
+        filetype = self.__incompat & EXT4_INCOMPAT_FILETYPE
+        visited = {EXT4_ROOT_INODE}
+        pending = [(EXT4_ROOT_INODE, "")]
+        while pending:
+            directory, path = pending.pop()
+            for inode, name, file_type in self.__directory_entries(directory):
+                child_path = f"{path}/{name}"
+                if (None, inode) in wanted:
+                    result.setdefault((None, inode), []).append(child_path)
+                if filetype and file_type != EXT4_FT_DIR or inode in visited:
+                    continue
+                if not filetype and not stat.S_ISDIR(struct.unpack_from("<H", self.__read_inode(inode))[0]):
+                    continue
+                visited.add(inode)
+                pending.append((inode, child_path))
+        return result
+
+    def __directory_entries(self, directory):
+        """Yields the inode, name and file type of the entries of a directory, without ``.`` and ``..``."""
+        filetype = self.__incompat & EXT4_INCOMPAT_FILETYPE
+        inodes_count = self.__groups * self.__inodes_per_group
+        for extent in self.__inode_extents(directory, self.__read_inode(directory)):
+            if extent.kind != "directory":
+                continue
+            data = os.pread(self.__fd, extent.end - extent.first, extent.first)
+            for block_start in range(0, len(data), self.__block_size):
+                position = block_start
+                block_end = block_start + self.__block_size
+                while position + 8 <= block_end:
+                    inode, rec_len, name_len, file_type = struct.unpack_from("<IHBB", data, position)
+                    if not filetype:
+                        name_len |= file_type << 8
+                        file_type = 0
+                    if rec_len < 8 or position + rec_len > block_end:
+                        break
+                    name = data[position + 8:position + 8 + min(name_len, rec_len - 8)]
+                    position += rec_len
+                    if inode == 0 or inode > inodes_count or name in (b".", b".."):
+                        continue
+                    yield inode, name.decode("utf-8", "backslashreplace"), file_type
+
+
+class Xfs:
+    """Reads the layout of an XFS filesystem on its data device.
+    The allocation groups are found by their headers,
+    the inodes by the inode btrees and the data of the files by their extent maps.
+    """
+
+    @staticmethod
+    def detect(fd, offset):
+        return os.pread(fd, len(XFS_SB_MAGIC), offset) == XFS_SB_MAGIC
+
+    def __init__(self, fd, offset):
+        self.__fd = fd
+        self.__offset = offset
+        sb = os.pread(fd, 512, offset)
+        self.__block_size, = struct.unpack_from(">I", sb, 4)
+        log_start, self.__root_inode, realtime_bitmap, realtime_summary = struct.unpack_from(">QQQQ", sb, 48)
+        self.__ag_blocks, self.__ag_count = struct.unpack_from(">II", sb, 84)
+        log_blocks, version, self.__sector_size, self.__inode_size = struct.unpack_from(">IHHH", sb, 96)
+        inopblog, agblklog = struct.unpack_from(">BB", sb, 123)
+        user_quota, group_quota = struct.unpack_from(">QQ", sb, 160)
+        dirblklog, = struct.unpack_from(">B", sb, 192)
+        features2, = struct.unpack_from(">I", sb, 200)
+        ro_compat, incompat = struct.unpack_from(">II", sb, 212)
+        project_quota, = struct.unpack_from(">Q", sb, 232)
+
+        self.__crc = version & 0xF == XFS_VERSION_5
+        if not self.__crc:
+            ro_compat = incompat = project_quota = 0
+        self.__inopblog = inopblog
+        self.__agblklog = agblklog
+        self.__ro_compat = ro_compat
+        self.__sparse_inodes = incompat & XFS_INCOMPAT_SPINODES
+        self.__ftype = incompat & XFS_INCOMPAT_FTYPE if self.__crc else features2 & XFS_SB_VERSION2_FTYPE
+        self.__dir_block_size = self.__block_size << dirblklog
+        self.__short_header_size = 56 if self.__crc else 16
+        self.__long_header_size = 72 if self.__crc else 24
+        self.__log = (log_start, log_blocks) if log_start != 0 else None
+
+        self.__special_inodes = {realtime_bitmap: "realtime-bitmap", realtime_summary: "realtime-summary"}
+        for inode in (user_quota, group_quota, project_quota):
+            self.__special_inodes[inode] = "quota"
+        self.__special_inodes.pop(0, None)
+        self.__special_inodes.pop(XFS_NULL_INODE, None)
+
+    def __ag_position(self, ag, ag_block):
+        return self.__offset + (ag * self.__ag_blocks + ag_block) * self.__block_size
+
+    def __fs_block_position(self, fs_block):
+        ag, ag_block = fs_block >> self.__agblklog, fs_block & ((1 << self.__agblklog) - 1)
+        return self.__ag_position(ag, ag_block)
+
+    def __inode_position(self, inode):
+        ag = inode >> (self.__agblklog + self.__inopblog)
+        ag_inode = inode & ((1 << (self.__agblklog + self.__inopblog)) - 1)
+        index = ag_inode & ((1 << self.__inopblog) - 1)
+        return self.__ag_position(ag, ag_inode >> self.__inopblog) + index * self.__inode_size
+
+    def __read(self, position, size):
+        data = os.pread(self.__fd, size, position)
+        if len(data) != size:
+            sys.exit(f"Incomplete XFS read at byte {position}")
+        return data
+
+    def __blocks_extent(self, position, count, kind, **kwargs):
+        return Extent(first=position, end=position + count * self.__block_size, kind=kind, **kwargs)
+
+    def extents(self):
+        """Yields the extents of the headers and btrees of all allocation groups, of the log and of all files."""
+        if self.__log is not None:
+            log_start, log_blocks = self.__log
+            yield self.__blocks_extent(self.__fs_block_position(log_start), log_blocks, "log")
+        for ag in range(self.__ag_count):
+            yield from self.__ag_extents(ag)
+
+    def __ag_extents(self, ag):
+        sector_size = self.__sector_size
+        position = self.__ag_position(ag, 0)
+        for index, kind in enumerate(("superblock", "agf", "agi", "agfl")):
+            yield Extent(first=position + index * sector_size, end=position + (index + 1) * sector_size,
+                         kind=kind, group=ag)
+        agf = self.__read(position + sector_size, sector_size)
+        agi = self.__read(position + 2 * sector_size, sector_size)
+        if agf[:4] != XFS_AGF_MAGIC or agi[:4] != XFS_AGI_MAGIC:
+            print(f"allocation group {ag}: invalid headers", file=sys.stderr)
+            return
+
+        # Blocks reserved for the growth of the free space btrees
+        agfl = self.__read(position + 3 * sector_size, sector_size)
+        agfl_header = 36 if self.__crc else 0
+        slots = (sector_size - agfl_header) // 4
+        fl_first, _, fl_count = struct.unpack_from(">III", agf, 40)
+        for index in range(min(fl_count, slots)):
+            ag_block, = struct.unpack_from(">I", agfl, agfl_header + 4 * ((fl_first + index) % slots))
+            yield self.__blocks_extent(self.__ag_position(ag, ag_block), 1, "free-list", group=ag)
+
+        bno_root, cnt_root, rmap_root = struct.unpack_from(">III", agf, 16)
+        yield from self.__short_btree(ag, bno_root, 8, "free-space-btree")
+        yield from self.__short_btree(ag, cnt_root, 8, "free-space-btree")
+        if self.__ro_compat & XFS_RO_COMPAT_RMAPBT:
+            # Nodes of the reverse mapping btree hold a low and a high key per child
+            yield from self.__short_btree(ag, rmap_root, 40, "rmap-btree")
+        if self.__ro_compat & XFS_RO_COMPAT_REFLINK:
+            refcount_root, = struct.unpack_from(">I", agf, 88)
+            yield from self.__short_btree(ag, refcount_root, 4, "refcount-btree")
+        if self.__ro_compat & XFS_RO_COMPAT_FINOBT:
+            free_root, = struct.unpack_from(">I", agi, 328)
+            yield from self.__short_btree(ag, free_root, 4, "inode-btree")
+        inode_root, = struct.unpack_from(">I", agi, 20)
+        records = []
+        yield from self.__short_btree(ag, inode_root, 4, "inode-btree", records, 16)
+        for record in records:
+            yield from self.__inode_chunk(ag, record)
+
+    def __short_btree(self, ag, root, key_size, kind, records=None, record_size=None):
+        """Yields the blocks of a btree of an allocation group.
+        The records of the leaves are appended to ``records`` if given.
+        """
+        header_size = self.__short_header_size
+        pending = [root]
+        visited = set()
+        while pending:
+            ag_block = pending.pop()
+            if ag_block in visited or ag_block >= self.__ag_blocks:
+                print(f"allocation group {ag}: invalid {kind} block {ag_block}", file=sys.stderr)
+                continue
+            visited.add(ag_block)
+            position = self.__ag_position(ag, ag_block)
+            yield self.__blocks_extent(position, 1, kind, group=ag)
+            data = self.__read(position, self.__block_size)
+            level, count = struct.unpack_from(">HH", data, 4)
+            if level > 0:
+                max_count = (self.__block_size - header_size) // (key_size + 4)
+                pending.extend(struct.unpack_from(f">{min(count, max_count)}I", data, header_size + max_count * key_size))
+            elif records is not None:
+                count = min(count, (self.__block_size - header_size) // record_size)
+                records.extend(data[header_size + index * record_size:header_size + (index + 1) * record_size]
+                               for index in range(count))
+
+    def __inode_chunk(self, ag, record):
+        """Yields the extents of an inode chunk and of all its inodes in use."""
+        start, = struct.unpack_from(">I", record, 0)
+        if self.__sparse_inodes:
+            holes, _, _, free = struct.unpack_from(">HBBQ", record, 4)
+        else:
+            holes = 0
+            _, free = struct.unpack_from(">IQ", record, 4)
+        first_inode = ag << (self.__agblklog + self.__inopblog) | start
+        position = self.__inode_position(first_inode)
+        index = 0
+        while index < XFS_INODES_PER_CHUNK:
+            if holes >> (index // XFS_INODES_PER_HOLEMASK_BIT) & 1:
+                index += XFS_INODES_PER_HOLEMASK_BIT
+                continue
+            end = index
+            while end < XFS_INODES_PER_CHUNK and not holes >> (end // XFS_INODES_PER_HOLEMASK_BIT) & 1:
+                end += XFS_INODES_PER_HOLEMASK_BIT
+            first = position + index * self.__inode_size
+            yield Extent(first=first, end=first + (end - index) * self.__inode_size, kind="inode-chunk",
+                         group=ag, inodes=range(first_inode + index, first_inode + end))
+            data = self.__read(first, (end - index) * self.__inode_size)
+            for number in range(index, end):
+                if not free >> number & 1:
+                    raw = data[(number - index) * self.__inode_size:(number - index + 1) * self.__inode_size]
+                    yield from self.__inode_extents(first_inode + number, raw)
+            index = end
+
+    def __inode_forks(self, inode, raw):
+        """Returns the mode and the data and attribute forks of an inode,
+        each as tuple of the format, the number of extents and the raw fork.
+        """
+        if raw[:2] != XFS_DINODE_MAGIC:
+            print(f"inode {inode}: invalid inode", file=sys.stderr)
+            return None
+        mode, version, data_format = struct.unpack_from(">HBB", raw, 2)
+        data_extents, attr_extents, fork_offset, attr_format = struct.unpack_from(">IHBB", raw, 76)
+        flags, = struct.unpack_from(">H", raw, 90)
+        if version >= 3:
+            flags2, = struct.unpack_from(">Q", raw, 120)
+            if flags2 & XFS_DIFLAG2_NREXT64:
+                data_extents, = struct.unpack_from(">Q", raw, 24)
+                attr_extents, = struct.unpack_from(">I", raw, 76)
+        core_size = 176 if version >= 3 else 100
+        attr_start = core_size + fork_offset * 8 if fork_offset else len(raw)
+        data_fork = (data_format, data_extents, raw[core_size:attr_start])
+        attr_fork = (attr_format, attr_extents, raw[attr_start:]) if fork_offset else None
+        return mode, flags, data_fork, attr_fork
+
+    def __inode_extents(self, inode, raw):
+        """Yields the extents of the data, the attributes and the extent maps of an inode."""
+        forks = self.__inode_forks(inode, raw)
+        if forks is None:
+            return
+        mode, flags, data_fork, attr_fork = forks
+        if inode in self.__special_inodes:
+            kind = self.__special_inodes[inode]
+        elif stat.S_ISDIR(mode):
+            kind = "directory"
+        elif stat.S_ISREG(mode) or stat.S_ISLNK(mode):
+            kind = "data"
+        else:
+            kind = None
+        for fork, fork_kind in ((data_fork, kind), (attr_fork, "xattr")):
+            if fork is None or fork_kind is None:
+                continue
+            # The data of realtime files is on the realtime device, only their extent maps are here
+            on_realtime = fork is data_fork and flags & XFS_DIFLAG_REALTIME
+            for mapping in self.__fork_mappings(inode, *fork):
+                if isinstance(mapping, Extent):
+                    yield mapping
+                elif not on_realtime:
+                    logical, fs_block, count = mapping
+                    offset = logical * self.__block_size if fork is data_fork else None
+                    yield self.__blocks_extent(self.__fs_block_position(fs_block), count, fork_kind,
+                                               inode=inode, offset=offset)
+
+    def __fork_mappings(self, inode, fork_format, extent_count, fork):
+        """Yields the blocks of the extent map of a fork and its mapped ranges."""
+        if fork_format == XFS_DINODE_FMT_EXTENTS:
+            count = min(extent_count, len(fork) // XFS_BMBT_REC_SIZE)
+            yield from (decode_bmbt_record(fork, index * XFS_BMBT_REC_SIZE) for index in range(count))
+        elif fork_format == XFS_DINODE_FMT_BTREE:
+            level, count = struct.unpack_from(">HH", fork, 0)
+            max_count = (len(fork) - 4) // 16
+            pending = list(struct.unpack_from(f">{min(count, max_count)}Q", fork, 4 + max_count * 8))
+            pending.reverse()
+            header_size = self.__long_header_size
+            while pending:
+                fs_block = pending.pop()
+                position = self.__fs_block_position(fs_block)
+                yield self.__blocks_extent(position, 1, "extent-tree", inode=inode)
+                data = self.__read(position, self.__block_size)
+                if data[:4] not in XFS_BMBT_MAGICS:
+                    print(f"inode {inode}: invalid extent map block {fs_block}", file=sys.stderr)
+                    continue
+                level, count = struct.unpack_from(">HH", data, 4)
+                max_count = (self.__block_size - header_size) // 16
+                count = min(count, max_count)
+                if level > 0:
+                    children = struct.unpack_from(f">{count}Q", data, header_size + max_count * 8)
+                    pending.extend(reversed(children))
+                else:
+                    yield from (decode_bmbt_record(data, header_size + index * XFS_BMBT_REC_SIZE)
+                                for index in range(count))
+
+    def paths(self, wanted):
+        """Returns the path names of the wanted inodes by walking the directory tree."""
+        result = {}
+        if (None, self.__root_inode) in wanted:
+            result[None, self.__root_inode] = ["/"]
+        visited = {self.__root_inode}
+        pending = [(self.__root_inode, "")]
+        while pending:
+            directory, path = pending.pop()
+            for inode, name, file_type in self.__directory_entries(directory):
+                child_path = f"{path}/{name}"
+                if (None, inode) in wanted:
+                    result.setdefault((None, inode), []).append(child_path)
+                if self.__ftype and file_type != XFS_DIR3_FT_DIR or inode in visited:
+                    continue
+                if not self.__ftype:
+                    mode, = struct.unpack_from(">H", self.__read(self.__inode_position(inode), 4), 2)
+                    if not stat.S_ISDIR(mode):
+                        continue
+                visited.add(inode)
+                pending.append((inode, child_path))
+        return result
+
+    def __directory_entries(self, directory):
+        """Yields the inode, name and file type of the entries of a directory, without ``.`` and ``..``."""
+        forks = self.__inode_forks(directory, self.__read(self.__inode_position(directory), self.__inode_size))
+        if forks is None:
+            return
+        data_format, extent_count, fork = forks[2]
+        if data_format == XFS_DINODE_FMT_LOCAL:
+            yield from self.__short_form_entries(fork)
+            return
+        # Only the data blocks hold entries, the leaf and free index blocks follow them
+        data_blocks = XFS_DIR2_LEAF_OFFSET // self.__block_size
+        blocks = {}
+        for mapping in self.__fork_mappings(directory, data_format, extent_count, fork):
+            if isinstance(mapping, Extent):
+                continue
+            logical, fs_block, count = mapping
+            count = min(count, data_blocks - logical)
+            if count <= 0:
+                continue
+            data = self.__read(self.__fs_block_position(fs_block), count * self.__block_size)
+            for index in range(count):
+                blocks[logical + index] = data[index * self.__block_size:(index + 1) * self.__block_size]
+        per_dir_block = self.__dir_block_size // self.__block_size
+        for logical in sorted(blocks):
+            if logical % per_dir_block != 0:
+                continue
+            data = b"".join(blocks.get(logical + index, b"") for index in range(per_dir_block))
+            if len(data) == self.__dir_block_size:
+                yield from self.__data_block_entries(data)
+
+    def __short_form_entries(self, fork):
+        count, large_count = struct.unpack_from(">BB", fork, 0)
+        inode_format = ">Q" if large_count else ">I"
+        position = 2 + struct.calcsize(inode_format)
+        for _ in range(count):
+            name_length, = struct.unpack_from(">B", fork, position)
+            name = fork[position + 3:position + 3 + name_length]
+            position += 3 + name_length
+            file_type = 0
+            if self.__ftype:
+                file_type = fork[position]
+                position += 1
+            inode, = struct.unpack_from(inode_format, fork, position)
+            position += struct.calcsize(inode_format)
+            yield inode, name.decode("utf-8", "backslashreplace"), file_type
+
+    def __data_block_entries(self, data):
+        magic = data[:4]
+        if magic in XFS_DIR2_BLOCK_MAGICS:
+            # Single block directories end with their leaf entries and a tail
+            leaf_count, = struct.unpack_from(">I", data, len(data) - 8)
+            end = len(data) - 8 - 8 * leaf_count
+        elif magic in XFS_DIR2_DATA_MAGICS:
+            end = len(data)
+        else:
+            return
+        position = 64 if self.__crc else 16
+        while position + 8 <= end:
+            tag, length = struct.unpack_from(">HH", data, position)
+            if tag == XFS_DIR2_DATA_FREE_TAG:
+                if length < 8:
+                    break
+                position += length
+                continue
+            inode, name_length = struct.unpack_from(">QB", data, position)
+            name = data[position + 9:position + 9 + name_length]
+            file_type = data[position + 9 + name_length] if self.__ftype else 0
+            position += (9 + name_length + bool(self.__ftype) + 2 + 7) & ~7
+            if name not in (b".", b".."):
+                yield inode, name.decode("utf-8", "backslashreplace"), file_type
+
+
+def decode_bmbt_record(data, position):
+    """Decodes an extent of XFS into the offset within the file, the first block and the number of blocks."""
+    high, low = struct.unpack_from(">QQ", data, position)
+    logical = high >> 9 & ((1 << 54) - 1)
+    fs_block = (high & 0x1FF) << 43 | low >> 21
+    count = low & ((1 << 21) - 1)
+    return logical, fs_block, count
+
+
+@dataclass(frozen=True, kw_only=True, slots=True)
+class BtrfsChunk:
+    logical: int
+    length: int
+    stripe_length: int
+    type: int
+    sub_stripes: int
+    stripes: list
+
+
+class Btrfs:
+    """Reads the layout of a btrfs filesystem on one of its devices.
+    The chunk tree maps the logical addresses to the devices,
+    the extent tree lists all allocated extents with their owners,
+    and the trees of the subvolumes provide the path names.
+    Chunks with the RAID5 or RAID6 profile are not supported.
+    """
+
+    @staticmethod
+    def detect(fd, offset):
+        return os.pread(fd, len(BTRFS_MAGIC), offset + BTRFS_SUPERBLOCK_OFFSETS[0] + 0x40) == BTRFS_MAGIC
+
+    def __init__(self, fd, offset):
+        self.__fd = fd
+        self.__offset = offset
+        sb = os.pread(fd, BTRFS_SUPERBLOCK_SIZE, offset + BTRFS_SUPERBLOCK_OFFSETS[0])
+        root, chunk_root = struct.unpack_from("<QQ", sb, 0x50)
+        self.__node_size, = struct.unpack_from("<I", sb, 0x94)
+        sys_chunk_array_size, = struct.unpack_from("<I", sb, 0xA0)
+        self.__device_id, = struct.unpack_from("<Q", sb, 0xC9)
+        self.__unsupported_chunks = set()
+        self.__parents = {}
+        self.__parents_by_root = {}
+
+        # The system chunks in the superblock are needed to read the chunk tree
+        self.__chunks = []
+        array = sb[0x32B:0x32B + sys_chunk_array_size]
+        position = 0
+        while position + BTRFS_KEY_SIZE + BTRFS_CHUNK_ITEM_SIZE <= len(array):
+            _, _, logical = struct.unpack_from(BTRFS_KEY_FORMAT, array, position)
+            position += BTRFS_KEY_SIZE
+            chunk = self.__decode_chunk(logical, array[position:])
+            self.__add_chunk(chunk)
+            position += BTRFS_CHUNK_ITEM_SIZE + len(chunk.stripes) * BTRFS_STRIPE_SIZE
+        for key, item in self.__tree_items(chunk_root):
+            if key[1] == BTRFS_CHUNK_ITEM_KEY:
+                self.__add_chunk(self.__decode_chunk(key[2], item))
+
+        self.__roots = {}
+        self.__root_backrefs = {}
+        for (objectid, key_type, key_offset), item in self.__tree_items(root):
+            if key_type == BTRFS_ROOT_ITEM_KEY:
+                self.__roots[objectid], = struct.unpack_from("<Q", item, 176)
+            elif key_type == BTRFS_ROOT_BACKREF_KEY:
+                directory, _, name_length = struct.unpack_from("<QQH", item, 0)
+                self.__root_backrefs[objectid] = (key_offset, directory, item[18:18 + name_length])
+        self.__roots[BTRFS_ROOT_TREE] = root
+
+    @staticmethod
+    def __decode_chunk(logical, item):
+        length, _, stripe_length, chunk_type, _, _, _, stripe_count, sub_stripes = \
+            struct.unpack_from(BTRFS_CHUNK_ITEM_FORMAT, item)
+        stripes = [struct.unpack_from("<QQ", item, BTRFS_CHUNK_ITEM_SIZE + index * BTRFS_STRIPE_SIZE)
+                   for index in range(stripe_count)]
+        return BtrfsChunk(logical=logical, length=length, stripe_length=stripe_length, type=chunk_type,
+                          sub_stripes=sub_stripes, stripes=stripes)
+
+    def __add_chunk(self, chunk):
+        index = bisect.bisect_left(self.__chunks, chunk.logical, key=lambda chunk: chunk.logical)
+        if index < len(self.__chunks) and self.__chunks[index].logical == chunk.logical:
+            self.__chunks[index] = chunk
+        else:
+            self.__chunks.insert(index, chunk)
+
+    def __map_logical(self, logical, length):
+        """Yields the logical address, the position on this device and the length
+        of all copies of the given range of logical addresses stored on this device.
+        """
+        index = bisect.bisect_right(self.__chunks, logical, key=lambda chunk: chunk.logical) - 1
+        if index < 0 or logical >= self.__chunks[index].logical + self.__chunks[index].length:
+            print(f"logical address {logical} is not within any chunk", file=sys.stderr)
+            return
+        chunk = self.__chunks[index]
+        if chunk.type & BTRFS_BLOCK_GROUP_RAID56:
+            if chunk.logical not in self.__unsupported_chunks:
+                self.__unsupported_chunks.add(chunk.logical)
+                print(f"chunk at logical address {chunk.logical} uses RAID5 or RAID6, which is not supported",
+                      file=sys.stderr)
+            return
+        offset = logical - chunk.logical
+        length = min(length, chunk.length - offset)
+        if chunk.type & BTRFS_BLOCK_GROUP_RAID0:
+            columns, copies = len(chunk.stripes), 1
+        elif chunk.type & BTRFS_BLOCK_GROUP_RAID10:
+            columns, copies = len(chunk.stripes) // chunk.sub_stripes, chunk.sub_stripes
+        else:
+            # Single, DUP and RAID1 store a full copy in every stripe
+            for device_id, stripe_offset in chunk.stripes:
+                if device_id == self.__device_id:
+                    yield logical, self.__offset + stripe_offset + offset, length
+            return
+        while length > 0:
+            stripe_number, stripe_position = divmod(offset, chunk.stripe_length)
+            piece = min(length, chunk.stripe_length - stripe_position)
+            row, column = divmod(stripe_number, columns)
+            for device_id, stripe_offset in chunk.stripes[column * copies:(column + 1) * copies]:
+                if device_id == self.__device_id:
+                    yield (chunk.logical + offset,
+                           self.__offset + stripe_offset + row * chunk.stripe_length + stripe_position, piece)
+            offset += piece
+            length -= piece
+
+    def __read_logical(self, logical, size):
+        """Reads a range of logical addresses from the first copy on this device, or returns None."""
+        data = bytearray(size)
+        read = set()
+        for piece_logical, position, length in self.__map_logical(logical, size):
+            if piece_logical in read:
+                continue
+            read.add(piece_logical)
+            data[piece_logical - logical:piece_logical - logical + length] = os.pread(self.__fd, length, position)
+            size -= length
+        return bytes(data) if size == 0 else None
+
+    def __read_node(self, logical):
+        data = self.__read_logical(logical, self.__node_size)
+        if data is None:
+            print(f"tree block {logical} is not stored on this device", file=sys.stderr)
+            return None
+        if struct.unpack_from("<Q", data, 48)[0] != logical:
+            print(f"tree block {logical} is invalid", file=sys.stderr)
+            return None
+        return data
+
+    def __tree_items(self, logical):
+        """Yields the keys and items of the leaves of a tree in the order of the keys."""
+        pending = [logical]
+        while pending:
+            node = self.__read_node(pending.pop())
+            if node is None:
+                continue
+            count, level = struct.unpack_from("<IB", node, 96)
+            if level > 0:
+                count = min(count, (len(node) - BTRFS_HEADER_SIZE) // BTRFS_KEY_POINTER_SIZE)
+                children = [struct.unpack_from("<Q", node, BTRFS_HEADER_SIZE + index * BTRFS_KEY_POINTER_SIZE
+                                               + BTRFS_KEY_SIZE)[0] for index in range(count)]
+                pending.extend(reversed(children))
+                continue
+            count = min(count, (len(node) - BTRFS_HEADER_SIZE) // BTRFS_ITEM_SIZE)
+            for index in range(count):
+                position = BTRFS_HEADER_SIZE + index * BTRFS_ITEM_SIZE
+                key = struct.unpack_from(BTRFS_KEY_FORMAT, node, position)
+                item_offset, item_size = struct.unpack_from("<II", node, position + BTRFS_KEY_SIZE)
+                yield key, node[BTRFS_HEADER_SIZE + item_offset:BTRFS_HEADER_SIZE + item_offset + item_size]
+
+    def extents(self):
+        """Yields the extents of the superblocks, of all tree blocks and of all data extents."""
+        device_size = os.lseek(self.__fd, 0, os.SEEK_END)
+        for position in BTRFS_SUPERBLOCK_OFFSETS:
+            if self.__offset + position + BTRFS_SUPERBLOCK_SIZE <= device_size:
+                first = self.__offset + position
+                yield Extent(first=first, end=first + BTRFS_SUPERBLOCK_SIZE, kind="superblock")
+        if BTRFS_EXTENT_TREE not in self.__roots:
+            sys.exit("The extent tree of the btrfs filesystem is missing")
+
+        # Keyed references follow the item of their extent
+        current = None
+        for (objectid, key_type, key_offset), item in self.__tree_items(self.__roots[BTRFS_EXTENT_TREE]):
+            if key_type in (BTRFS_EXTENT_ITEM_KEY, BTRFS_METADATA_ITEM_KEY):
+                if current is not None:
+                    yield from self.__allocated_extents(*current)
+                length = key_offset if key_type == BTRFS_EXTENT_ITEM_KEY else self.__node_size
+                flags, = struct.unpack_from("<Q", item, 16)
+                # Tree blocks of the old format store the key and level of the block before the references
+                position = 24 + 18 if key_type == BTRFS_EXTENT_ITEM_KEY and flags & BTRFS_EXTENT_FLAG_TREE_BLOCK else 24
+                current = (objectid, length, flags, list(decode_inline_refs(item, position)))
+            elif current is not None and objectid == current[0]:
+                if key_type == BTRFS_EXTENT_DATA_REF_KEY:
+                    current[3].append((key_type, struct.unpack_from("<QQQ", item)))
+                elif key_type in (BTRFS_TREE_BLOCK_REF_KEY, BTRFS_SHARED_BLOCK_REF_KEY, BTRFS_SHARED_DATA_REF_KEY):
+                    current[3].append((key_type, key_offset))
+        if current is not None:
+            yield from self.__allocated_extents(*current)
+
+    def __allocated_extents(self, logical, length, flags, refs):
+        """Yields the extents on this device of an allocated extent for each of its owners."""
+        owners = []
+        if flags & BTRFS_EXTENT_FLAG_TREE_BLOCK:
+            roots = {value for key_type, value in refs if key_type == BTRFS_TREE_BLOCK_REF_KEY}
+            # Blocks only referenced by their parents belong to snapshotted subvolumes or relocation
+            for root in sorted(roots) or [None]:
+                kind = f"{BTRFS_TREES.get(root, 'fs-tree')}-block"
+                subvolume = root if root is not None and is_subvolume(root) else None
+                owners.append((dict(kind=kind, root=subvolume), None))
+        elif flags & BTRFS_EXTENT_FLAG_DATA:
+            for key_type, value in refs:
+                if key_type == BTRFS_EXTENT_DATA_REF_KEY:
+                    root, inode, file_offset = value
+                    owners.append((dict(kind="data", root=root, inode=inode), file_offset))
+                elif key_type == BTRFS_SHARED_DATA_REF_KEY:
+                    for root, inode, file_offset in self.__shared_data_owners(value, logical):
+                        owners.append((dict(kind="data", root=root, inode=inode), file_offset))
+            if not owners:
+                owners.append((dict(kind="data-of-unknown-file"), None))
+        for piece_logical, position, piece_length in self.__map_logical(logical, length):
+            for fields, file_offset in owners:
+                offset = None if file_offset is None else file_offset + piece_logical - logical
+                yield Extent(first=position, end=position + piece_length, offset=offset, **fields)
+
+    def __shared_data_owners(self, parent, logical):
+        """Returns the root, inode and file offset of the file extents of a leaf referring to a data extent."""
+        if parent not in self.__parents:
+            # Consecutive extents are often referenced by the same leaf
+            self.__parents.clear()
+            node = self.__read_node(parent)
+            self.__parents[parent] = [] if node is None else list(self.__leaf_file_extents(node))
+        return [(root, inode, file_offset) for root, inode, file_offset, disk_logical in self.__parents[parent]
+                if disk_logical == logical]
+
+    @staticmethod
+    def __leaf_file_extents(node):
+        owner, = struct.unpack_from("<Q", node, 88)
+        count, level = struct.unpack_from("<IB", node, 96)
+        if level != 0:
+            return
+        for index in range(min(count, (len(node) - BTRFS_HEADER_SIZE) // BTRFS_ITEM_SIZE)):
+            position = BTRFS_HEADER_SIZE + index * BTRFS_ITEM_SIZE
+            inode, key_type, key_offset = struct.unpack_from(BTRFS_KEY_FORMAT, node, position)
+            item_offset, _ = struct.unpack_from("<II", node, position + BTRFS_KEY_SIZE)
+            if key_type != BTRFS_EXTENT_DATA_KEY:
+                continue
+            item_position = BTRFS_HEADER_SIZE + item_offset
+            extent_type, = struct.unpack_from("<B", node, item_position + 20)
+            if extent_type == BTRFS_FILE_EXTENT_INLINE:
+                continue
+            disk_logical, _, extent_offset = struct.unpack_from("<QQQ", node, item_position + 21)
+            yield owner, inode, key_offset - extent_offset, disk_logical
+
+    def paths(self, wanted):
+        """Returns the path names of the wanted inodes, following the references to their directories."""
+        result = {}
+        for root, inode in wanted:
+            paths = self.__inode_paths(root, inode)
+            if paths:
+                result[root, inode] = paths
+        return result
+
+    def __inode_paths(self, root, inode, depth=0):
+        """Returns the path names of an inode, including the path of its subvolume."""
+        if depth > 256:
+            return []
+        if inode == BTRFS_FIRST_FREE_OBJECTID:
+            if root == BTRFS_FS_TREE:
+                return ["/"]
+            if root not in self.__root_backrefs:
+                return []
+            parent_root, directory, name = self.__root_backrefs[root]
+            return [join_path(path, name) for path in self.__inode_paths(parent_root, directory, depth + 1)]
+        return [join_path(path, name) for parent, name in self.__inode_parents(root).get(inode, [])
+                for path in self.__inode_paths(root, parent, depth + 1)]
+
+    def __inode_parents(self, root):
+        """Returns the directories and names of all inodes of a subvolume."""
+        if root not in self.__parents_by_root:
+            parents = {}
+            if root in self.__roots:
+                for (inode, key_type, key_offset), item in self.__tree_items(self.__roots[root]):
+                    if key_type == BTRFS_INODE_REF_KEY:
+                        position = 0
+                        while position + 10 <= len(item):
+                            name_length, = struct.unpack_from("<H", item, position + 8)
+                            parents.setdefault(inode, []).append(
+                                (key_offset, item[position + 10:position + 10 + name_length]))
+                            position += 10 + name_length
+                    elif key_type == BTRFS_INODE_EXTREF_KEY:
+                        position = 0
+                        while position + 18 <= len(item):
+                            parent, _, name_length = struct.unpack_from("<QQH", item, position)
+                            parents.setdefault(inode, []).append(
+                                (parent, item[position + 18:position + 18 + name_length]))
+                            position += 18 + name_length
+            self.__parents_by_root[root] = parents
+        return self.__parents_by_root[root]
+
+
+def decode_inline_refs(item, position):
+    """Yields the type and value of the references stored within an extent item of btrfs."""
+    while position < len(item):
+        ref_type, = struct.unpack_from("<B", item, position)
+        position += 1
+        if ref_type == BTRFS_EXTENT_DATA_REF_KEY:
+            yield ref_type, struct.unpack_from("<QQQ", item, position)
+            position += 28
+        elif ref_type == BTRFS_SHARED_DATA_REF_KEY:
+            yield ref_type, struct.unpack_from("<Q", item, position)[0]
+            position += 12
+        elif ref_type in (BTRFS_TREE_BLOCK_REF_KEY, BTRFS_SHARED_BLOCK_REF_KEY, BTRFS_EXTENT_OWNER_REF_KEY):
+            yield ref_type, struct.unpack_from("<Q", item, position)[0]
+            position += 8
+        else:
+            break
+
+
+def is_subvolume(root):
+    return root == BTRFS_FS_TREE or BTRFS_FIRST_FREE_OBJECTID <= root <= BTRFS_LAST_FREE_OBJECTID
+
+
+def join_path(path, name):
+    return f"{path.rstrip('/')}/{name.decode('utf-8', 'backslashreplace')}"
+
+
+FILESYSTEMS = [Ext4, Xfs, Btrfs]
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,519 @@
+#!/usr/bin/env python3
+# -*- coding: utf-8 -*-
+"""Reads the text metadata of LVM2 without activating the volume group.
+
+The metadata is either read from the metadata area of a physical volume,
+or from a backup file as found in ``/etc/lvm/backup`` [1]_.
+The command ``show`` lists the logical volumes with their physical locations,
+including the hidden sub-volumes of lvmcache like ``_cmeta``, ``_cdata`` and ``_corig``. ::
+
+    $ ./lvm_metadata.py show /dev/sdb
+    $ ./lvm_metadata.py show --pv-device /dev/sdb --pv-device /dev/sdc /etc/lvm/backup/vg
+
+The command ``table`` prints a table for device-mapper,
+which maps the given logical volume to the physical volumes. ::
+
+    $ ./lvm_metadata.py table /dev/sdb vg/cpool_cmeta | dmsetup create -r cmeta
+
+The scripts ``cache_verify.py`` and ``snapshot.py`` accept ``--lvm``,
+in which case their devices are given as names of logical volumes.
+A logical volume consisting of a single linear segment is accessed
+through a loop device with the offset and size of the segment,
+others through a device-mapper device created from the generated table. ::
+
+    $ ./cache_verify.py --lvm /dev/sdb vg/cpool_cmeta vg/cpool_cdata vg/data_corig
+
+Newer setups use a cachevol instead of a cache pool,
+a single logical volume which holds the metadata followed by the cache.
+Both regions are available as the views ``LV:metadata`` and ``LV:data``.
+``cache_verify.py`` also accepts the name of the cached logical volume itself,
+for which the metadata, cache and origin volume are looked up automatically. ::
+
+    $ ./lvm_metadata.py table /dev/sdb vg/fast_cvol:metadata
+    $ ./cache_verify.py --lvm /dev/sdb vg/data vg/data vg/data
+
+An activated cachevol can be passed to ``cache_verify.py`` with ``--cachevol`` instead.
+
+.. [1] Description of the on-disk format of LVM2:
+   https://github.com/lvmteam/lvm2/blob/main/lib/format_text/layout.h
+"""
+
+import os, re, struct, subprocess, sys, zlib
+from argparse import ArgumentParser
+from contextlib import contextmanager, ExitStack
+from dataclasses import dataclass
+from pathlib import Path
+
+
+SECTOR_SIZE = 512
+LABEL_SCAN_SECTORS = 4
+LABEL_ID = b"LABELONE"
+LABEL_TYPE = b"LVM2 001"
+MDA_HEADER_SIZE = 512
+MDA_MAGIC = b" LVM2 x[5A%r0N*>"
+RAW_LOCN_IGNORED = 0x1
+INITIAL_CRC = 0xf597a6cf
+
+TOKEN_PATTERN = re.compile(r"""
+      (?P<space>\s+|\#[^\n]*)
+    | (?P<string>"(?:[^"\\]|\\.)*")
+    | (?P<word>[\w.+-]+)
+    | (?P<symbol>[={}\[\],])
+""", re.VERBOSE)
+
+
+def main():
+    argparser = ArgumentParser()
+    subparsers = argparser.add_subparsers(required=True)
+
+    subparser = subparsers.add_parser("show",
+            help="Lists the logical volumes with their physical locations")
+    subparser.add_argument("source", type=Path, help="Physical volume or backup file")
+    subparser.add_argument("--pv-device", type=Path, action="append", default=[],
+            help="Device of another physical volume of the volume group")
+    subparser.set_defaults(func=show)
+
+    subparser = subparsers.add_parser("table",
+            help="Prints a device-mapper table for the given logical volume")
+    subparser.add_argument("source", type=Path, help="Physical volume or backup file")
+    subparser.add_argument("lv")
+    subparser.add_argument("--pv-device", type=Path, action="append", default=[],
+            help="Device of another physical volume of the volume group")
+    subparser.set_defaults(func=table)
+
+    args = argparser.parse_args()
+    args.func(args)
+
+
+def show(args):
+    vg = read_volume_group(args.source)
+    devices = find_pv_devices(vg, [args.source, *args.pv_device])
+    print(f"volume group {vg.name}: extent size {vg.extent_size} sectors")
+    for name, pv in vg.pvs.items():
+        device = devices.get(name, "missing")
+        print(f"  physical volume {name}: {device}, id {pv.id}, data at sector {pv.pe_start}")
+    for lv in vg.lvs.values():
+        print(f"logical volume {lv.name}:")
+        cache = cache_volumes(vg, lv.name)
+        if cache is not None:
+            metadata, data, origin = cache
+            print(f"  cache with metadata {metadata}, cache {data} and origin {origin}")
+        for segment in lv.segments:
+            if segment.type not in ("linear", "striped"):
+                print(f"  {segment.start}+{segment.length}: {segment.type}")
+                continue
+            stripes = ", ".join(f"{pv} at sector {sector}" for pv, sector in segment.stripes)
+            print(f"  {segment.start}+{segment.length}: {segment.type} on {stripes}")
+
+
+def table(args):
+    vg = read_volume_group(args.source)
+    devices = find_pv_devices(vg, [args.source, *args.pv_device])
+    for line in table_lines(vg, args.lv, devices):
+        print(line)
+
+
+@dataclass(frozen=True, kw_only=True, slots=True)
+class PhysicalVolume:
+    name: str
+    id: str
+    device_hint: str | None
+    pe_start: int
+
+
+@dataclass(frozen=True, kw_only=True, slots=True)
+class Segment:
+    """A segment of a logical volume.
+    ``start`` and ``length`` are given in sectors of the logical volume,
+    ``stripes`` contains the name of the physical volume and the first sector of each stripe.
+    """
+    start: int
+    length: int
+    type: str
+    stripe_size: int
+    stripes: list
+    config: dict
+
+
+@dataclass(frozen=True, kw_only=True, slots=True)
+class LogicalVolume:
+    name: str
+    segments: list
+
+
+@dataclass(frozen=True, kw_only=True, slots=True)
+class VolumeGroup:
+    name: str
+    extent_size: int
+    pvs: dict
+    lvs: dict
+
+
+@dataclass(frozen=True, kw_only=True, slots=True)
+class PhysicalVolumeLabel:
+    uuid: str
+    device_size: int
+    data_areas: list
+    metadata_areas: list
+
+
+def read_volume_group(source):
+    """Reads the volume group from a physical volume or from a backup file."""
+    with open(source, "rb") as file:
+        label = read_label(file.fileno())
+        if label is None:
+            text = file.read()
+        else:
+            text = read_metadata_text(file.fileno(), label)
+    config = parse_config(text.rstrip(b"\0").decode())
+    for name, value in config.items():
+        if isinstance(value, dict) and "extent_size" in value:
+            return make_volume_group(name, value)
+    sys.exit(f"No volume group found in {source}")
+
+
+def read_label(fd):
+    """Returns the label of a physical volume, or ``None`` if there is none."""
+    for sector in range(LABEL_SCAN_SECTORS):
+        data = os.pread(fd, SECTOR_SIZE, SECTOR_SIZE * sector)
+        if len(data) < SECTOR_SIZE or data[:8] != LABEL_ID or data[24:32] != LABEL_TYPE:
+            continue
+        crc, offset = struct.unpack_from("<II", data, 16)
+        if lvm_crc(data[20:]) != crc:
+            print(f"Checksum mismatch in label at sector {sector}", file=sys.stderr)
+        uuid = data[offset:offset + 32].decode("ascii")
+        device_size = struct.unpack_from("<Q", data, offset + 32)[0]
+        position = offset + 40
+        areas = []
+        for _ in range(2):
+            locations = []
+            while True:
+                area_offset, area_size = struct.unpack_from("<QQ", data, position)
+                position += 16
+                if area_offset == 0:
+                    break
+                locations.append((area_offset, area_size))
+            areas.append(locations)
+        return PhysicalVolumeLabel(uuid=uuid, device_size=device_size,
+                data_areas=areas[0], metadata_areas=areas[1])
+    return None
+
+
+def read_metadata_text(fd, label):
+    """Returns the current metadata from the first intact metadata area of a physical volume.
+    The metadata areas are ring buffers, so the text may wrap around.
+    """
+    for area_offset, _ in label.metadata_areas:
+        header = os.pread(fd, MDA_HEADER_SIZE, area_offset)
+        if header[4:20] != MDA_MAGIC:
+            print(f"Invalid metadata area at byte {area_offset}", file=sys.stderr)
+            continue
+        if lvm_crc(header[4:]) != struct.unpack_from("<I", header)[0]:
+            print(f"Checksum mismatch in metadata area at byte {area_offset}", file=sys.stderr)
+        _, start, size = struct.unpack_from("<IQQ", header, 20)
+        offset, length, checksum, flags = struct.unpack_from("<QQII", header, 40)
+        if offset == 0 or flags & RAW_LOCN_IGNORED:
+            continue
+        if offset + length > size:
+            first = size - offset
+            text = os.pread(fd, first, start + offset) \
+                    + os.pread(fd, length - first, start + MDA_HEADER_SIZE)
+        else:
+            text = os.pread(fd, length, start + offset)
+        if lvm_crc(text) != checksum:
+            print(f"Checksum mismatch in metadata text at byte {start + offset}", file=sys.stderr)
+        return text
+    sys.exit("No metadata area found on the physical volume")
+
+
+def lvm_crc(data):
+    """Returns the CRC used by LVM2, which is CRC-32 without final inversion."""
+    return zlib.crc32(data, INITIAL_CRC ^ 0xffffffff) ^ 0xffffffff
+
+
+def parse_config(text):
+    """Parses the configuration format of LVM2 into nested dictionaries."""
+    tokens = []
+    position = 0
+    while position < len(text):
+        match = TOKEN_PATTERN.match(text, position)
+        if match is None:
+            sys.exit(f"Invalid LVM metadata: unexpected {text[position]!r}")
+        if match.lastgroup != "space":
+            tokens.append((match.lastgroup, match.group()))
+        position = match.end()
+    tokens.append((None, None))
+    position = 0
+
+    def next_token():
+        nonlocal position
+        if tokens[position][0] is None:
+            sys.exit("Invalid LVM metadata: unexpected end")
+        position += 1
+        return tokens[position - 1]
+
+    def parse_value():
+        kind, token = next_token()
+        if kind == "string":
+            return re.sub(r"\\(.)", r"\1", token[1:-1])
+        if kind == "word":
+            return int(token) if re.fullmatch(r"-?\d+", token) else token
+        if token != "[":
+            sys.exit(f"Invalid LVM metadata: unexpected {token!r}")
+        values = []
+        while tokens[position][1] != "]":
+            values.append(parse_value())
+            if tokens[position][1] == ",":
+                next_token()
+        next_token()
+        return values
+
+    def parse_section(end):
+        section = {}
+        while tokens[position][1] != end:
+            kind, name = next_token()
+            if kind not in ("word", "string"):
+                sys.exit(f"Invalid LVM metadata: unexpected {name!r}")
+            _, symbol = next_token()
+            if symbol == "=":
+                section[name] = parse_value()
+            elif symbol == "{":
+                section[name] = parse_section("}")
+                next_token()
+            else:
+                sys.exit(f"Invalid LVM metadata: unexpected {symbol!r} after {name}")
+        return section
+
+    return parse_section(None)
+
+
+def make_volume_group(name, config):
+    extent_size = config["extent_size"]
+    pvs = {}
+    for pv_name, pv in config.get("physical_volumes", {}).items():
+        pvs[pv_name] = PhysicalVolume(name=pv_name, id=pv["id"], device_hint=pv.get("device"),
+                pe_start=pv["pe_start"])
+    lvs = {}
+    for lv_name, lv in config.get("logical_volumes", {}).items():
+        segments = []
+        for index in range(1, lv.get("segment_count", 0) + 1):
+            segment = lv[f"segment{index}"]
+            stripes = []
+            if segment["type"] == "striped":
+                raw = segment["stripes"]
+                for pv_name, extent in zip(raw[0::2], raw[1::2]):
+                    if pv_name not in pvs:
+                        sys.exit(f"Logical volume {lv_name} refers to unknown physical volume {pv_name}")
+                    stripes.append((pv_name, pvs[pv_name].pe_start + extent * extent_size))
+            segments.append(Segment(
+                start=segment["start_extent"] * extent_size,
+                length=segment["extent_count"] * extent_size,
+                type="linear" if len(stripes) == 1 else segment["type"],
+                stripe_size=segment.get("stripe_size", 0),
+                stripes=stripes,
+                config=segment,
+            ))
+        lvs[lv_name] = LogicalVolume(name=lv_name, segments=segments)
+    return VolumeGroup(name=name, extent_size=extent_size, pvs=pvs, lvs=lvs)
+
+
+def cache_volumes(vg, lv_name):
+    """Returns the names of the metadata, cache and origin volume of a cached logical volume,
+    or ``None`` if it is not cached.
+    A cachevol holds both the metadata and the cache,
+    which are returned as the views ``LV:metadata`` and ``LV:data``.
+    """
+    lv = find_lv(vg, lv_name)
+    if len(lv.segments) != 1 or lv.segments[0].type != "cache":
+        return None
+    segment = lv.segments[0].config
+    if "metadata_start" in segment:
+        cachevol = segment["cache_pool"]
+        return f"{cachevol}:metadata", f"{cachevol}:data", segment["origin"]
+    pool = find_lv(vg, segment["cache_pool"]).segments[0].config
+    if pool["type"] != "cache-pool":
+        sys.exit(f"Unsupported cache pool type of {segment['cache_pool']}: {pool['type']}")
+    return pool["metadata"], pool["data"], segment["origin"]
+
+
+def cache_volume(vg, name, role):
+    """Returns the name of the metadata, cache or origin volume if ``name`` is a cached logical volume,
+    and ``name`` itself otherwise.
+    """
+    volumes = cache_volumes(vg, name) if ":" not in name else None
+    if volumes is None:
+        return name
+    return volumes[("metadata", "cache", "origin").index(role)]
+
+
+def find_view(vg, name):
+    """Returns the name of the logical volume and the range of sectors of a view.
+    The views ``LV:metadata`` and ``LV:data`` refer to the regions of a cachevol,
+    as recorded in the cache segment which uses it.
+    """
+    lv_name, _, view = name.partition(":")
+    lv = find_lv(vg, lv_name)
+    if not view:
+        return lv.name, None
+    if view not in ("metadata", "data"):
+        sys.exit(f"Unknown view {view} of {lv.name}, expected metadata or data")
+    for other in vg.lvs.values():
+        for segment in other.segments:
+            config = segment.config
+            if segment.type == "cache" and config.get("cache_pool") == lv.name and "metadata_start" in config:
+                start = config[f"{view}_start"]
+                return lv.name, range(start, start + config[f"{view}_len"])
+    sys.exit(f"Logical volume {lv.name} is not used as cachevol")
+
+
+def find_lv(vg, name):
+    """Looks up a logical volume. The name may be prefixed with the name of the volume group,
+    and hidden volumes may be enclosed in brackets, as printed by ``lvs -a``.
+    """
+    vg_name, _, lv_name = name.rpartition("/")
+    if vg_name and vg_name != vg.name:
+        sys.exit(f"Logical volume {name} is not part of volume group {vg.name}")
+    lv_name = lv_name.removeprefix("[").removesuffix("]")
+    if lv_name not in vg.lvs:
+        sys.exit(f"Logical volume {lv_name} not found in volume group {vg.name}")
+    return vg.lvs[lv_name]
+
+
+def find_pv_devices(vg, candidates):
+    """Maps the names of the physical volumes to their devices.
+    The candidates are identified by the UUID in their label.
+    The device recorded in the metadata is used for physical volumes not among the candidates.
+    """
+    uuids = {}
+    for candidate in candidates:
+        try:
+            with open(candidate, "rb") as file:
+                label = read_label(file.fileno())
+        except OSError:
+            continue
+        if label is not None:
+            uuids[label.uuid] = candidate
+    devices = {}
+    for name, pv in vg.pvs.items():
+        uuid = pv.id.replace("-", "")
+        if uuid in uuids:
+            devices[name] = uuids[uuid]
+        elif pv.device_hint is not None and os.path.exists(pv.device_hint):
+            devices[name] = Path(pv.device_hint)
+    return devices
+
+
+def resolve_segments(vg, name, devices):
+    """Returns the segments of a logical volume or a view with the devices of the physical volumes."""
+    lv_name, sectors = find_view(vg, name)
+    lv = vg.lvs[lv_name]
+    segments = []
+    for segment in lv.segments:
+        if segment.type not in ("linear", "striped"):
+            sys.exit(f"Unsupported segment type of {lv.name}: {segment.type}")
+        stripes = []
+        for pv_name, sector in segment.stripes:
+            if pv_name not in devices:
+                sys.exit(f"Device of physical volume {pv_name} ({vg.pvs[pv_name].id}) not found,"
+                         f" use --pv-device")
+            stripes.append((devices[pv_name], sector))
+        segments.append(Segment(start=segment.start, length=segment.length, type=segment.type,
+                stripe_size=segment.stripe_size, stripes=stripes, config=segment.config))
+    if sectors is not None:
+        segments = slice_segments(segments, sectors, name)
+    return segments
+
+
+def slice_segments(segments, sectors, name):
+    """Returns the part of the segments covering the given range of sectors,
+    relative to the start of the range.
+    Striped segments cannot be split.
+    """
+    result = []
+    for segment in segments:
+        first = max(segment.start, sectors.start)
+        end = min(segment.start + segment.length, sectors.stop)
+        if first >= end:
+            continue
+        if segment.type != "linear" and (first, end) != (segment.start, segment.start + segment.length):
+            sys.exit(f"View {name} splits a striped segment")
+        stripes = segment.stripes
+        if segment.type == "linear":
+            device, sector = stripes[0]
+            stripes = [(device, sector + first - segment.start)]
+        result.append(Segment(start=first - sectors.start, length=end - first, type=segment.type,
+                stripe_size=segment.stripe_size, stripes=stripes, config=segment.config))
+    if sum(segment.length for segment in result) != len(sectors):
+        sys.exit(f"View {name} exceeds its logical volume")
+    return result
+
+
+def table_lines(vg, lv_name, devices):
+    for segment in resolve_segments(vg, lv_name, devices):
+        if segment.type == "linear":
+            device, sector = segment.stripes[0]
+            yield f"{segment.start} {segment.length} linear {device} {sector}"
+        else:
+            stripes = " ".join(f"{device} {sector}" for device, sector in segment.stripes)
+            yield f"{segment.start} {segment.length} striped {len(segment.stripes)} {segment.stripe_size} {stripes}"
+
+
+@contextmanager
+def make_lv_device(vg, lv_name, candidates, *, write=False):
+    """Yields a block device for the given logical volume,
+    which is removed automatically once it is no longer in use.
+    """
+    devices = find_pv_devices(vg, candidates)
+    segments = resolve_segments(vg, lv_name, devices)
+    if len(segments) == 1 and segments[0].type == "linear":
+        device, sector = segments[0].stripes[0]
+        with make_loop_device(device, write=write,
+                offset=SECTOR_SIZE * sector, size=SECTOR_SIZE * segments[0].length) as path:
+            yield path
+        return
+
+    with ExitStack() as stack:
+        for name, device in devices.items():
+            if not Path(device).is_block_device():
+                devices[name] = stack.enter_context(make_loop_device(device, write=write))
+        lv, _ = find_view(vg, lv_name)
+        view = lv_name.partition(":")[2]
+        dm_name = f"lvm_metadata-{vg.name}-{lv}" + (f"-{view}" if view else "")
+        table = "".join(f"{line}\n" for line in table_lines(vg, lv_name, devices))
+        run("dmsetup", "create", *([] if write else ["-r"]), dm_name, input=table)
+        try:
+            yield Path("/dev/mapper", dm_name)
+        finally:
+            run("dmsetup", "remove", "--deferred", dm_name)
+
+
+@contextmanager
+def make_loop_device(path, *, write=False, offset=None, size=None):
+    if Path(path).is_block_device() and offset is None:
+        yield Path(path)
+        return
+    stdout = run(
+        "losetup",
+        *([] if write else ["--read-only"]),
+        "--find", "--show",
+        *([] if offset is None else ["--offset", str(offset)]),
+        *([] if size is None else ["--sizelimit", str(size)]),
+        str(path),
+    )
+    result = Path(stdout.rstrip("\n"))
+    try:
+        yield result
+    finally:
+        # Detaching a loop device which is still in use enables its autoclear
+        run("losetup", "--detach", str(result))
+
+
+def run(*command, input=None):
+    result = subprocess.run(command, input=input, stdout=subprocess.PIPE, text=True)
+    if result.returncode != 0:
+        sys.exit(f"{command[0]} failed with exit code {result.returncode}")
+    return result.stdout
+
+
+if __name__ == "__main__":
+    main()
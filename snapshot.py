@@ -16,11 +16,17 @@ You can later load the same snapshot again. ::
 
     $ ./snapshot.py load snap /dev/sda1 sda1-cow.bin
 
+With ``--lvm``, the base is a logical volume,
+which is located by reading the LVM metadata from a physical volume or a backup file,
+without activating the volume group. ::
+
+    $ ./snapshot.py create --lvm /dev/sdb snap vg/data_corig corig-cow.bin
+
 .. [1] Documentation of the ``snapshot`` target of device-mapper:
    https://www.kernel.org/doc/Documentation/device-mapper/snapshot.txt
 """
 
-import os, re, shlex, subprocess, sys
+import lvm_metadata, os, re, shlex, subprocess, sys
 from argparse import ArgumentParser
 from contextlib import contextmanager
 from pathlib import Path
@@ -40,6 +46,10 @@ def main():
             help="Chunksize in sectors (512 bytes per sector)")
     #subparser.add_argument("--offset", help="Offset in base counted in bytes", type=int)
     #subparser.add_argument("--size", help="Offset in base counted in bytes", type=int)
+    subparser.add_argument("--lvm", type=Path, metavar="SOURCE",
+            help="Physical volume or LVM backup file; base is given as logical volume")
+    subparser.add_argument("--pv-device", type=Path, action="append", default=[],
+            help="Device of another physical volume of the volume group, used by --lvm")
     subparser.set_defaults(func=create)
 
     subparser = subparsers.add_parser("load")
@@ -48,6 +58,10 @@ def main():
     subparser.add_argument("cow_device", type=Path)
     subparser.add_argument("--chunksize", type=int, default=512,
             help="Chunksize in sectors (512 bytes per sector)")
+    subparser.add_argument("--lvm", type=Path, metavar="SOURCE",
+            help="Physical volume or LVM backup file; base is given as logical volume")
+    subparser.add_argument("--pv-device", type=Path, action="append", default=[],
+            help="Device of another physical volume of the volume group, used by --lvm")
     subparser.set_defaults(func=load)
 
     subparser = subparsers.add_parser("unload")
@@ -64,17 +78,24 @@ def main():
 
 
 def create(args):
-    with make_block_device(args.base) as base_device, \
+    with make_base_device(args) as base_device, \
             create_cow_device(args) as cow_device:
         setup_snapshot(args.name, base_device, cow_device, args.chunksize)
 
 
 def load(args):
-    with make_block_device(args.base) as base_device, \
+    with make_base_device(args) as base_device, \
             make_block_device(args.cow_device, write=True) as cow_device:
         setup_snapshot(args.name, base_device, cow_device, args.chunksize)
 
 
+def make_base_device(args):
+    if args.lvm is None:
+        return make_block_device(args.base)
+    vg = lvm_metadata.read_volume_group(args.lvm)
+    return lvm_metadata.make_lv_device(vg, str(args.base), [args.lvm, *args.pv_device])
+
+
 def setup_snapshot(name, base_device, cow_device, chunksize):
     size = detect_size(base_device)
     run(
@@ -0,0 +1,359 @@
+#!/usr/bin/env python3
+# -*- coding: utf-8 -*-
+"""Tests of the binary metadata writer and readers of ``cache_metadata.py``. ::
+
+    $ python3 -m unittest test_cache_metadata
+"""
+
+import cache_metadata, io, json, re, struct, tempfile, unittest, zlib
+from argparse import Namespace
+from contextlib import redirect_stderr, redirect_stdout
+from dataclasses import replace
+from pathlib import Path
+
+
+BLOCK_SIZE = 128
+# Enough cache blocks to fill three blocks of the mapping array
+NR_CACHE_BLOCKS = 1500
+MAX_ENTRIES = (cache_metadata.METADATA_BLOCK_SIZE - cache_metadata.ARRAY_BLOCK_HEADER_SIZE) // 8
+
+
+def make_entries(hint_size):
+    return [cache_metadata.make_entry(BLOCK_SIZE, 7 * cache_block + 3, cache_block, cache_block % 3 == 0,
+                                      hint=cache_block.to_bytes(hint_size, "little") if hint_size else None)
+            for cache_block in range(NR_CACHE_BLOCKS) if cache_block % 4 != 1]
+
+
+class MetadataTest(unittest.TestCase):
+
+    def setUp(self):
+        directory = tempfile.TemporaryDirectory()
+        self.addCleanup(directory.cleanup)
+        self.path = Path(directory.name) / "metadata.bin"
+        with open(self.path, "wb") as file:
+            file.truncate(4 << 20)
+
+    def write(self, entries, **options):
+        cache_metadata.write_metadata(self.path, entries, block_size=BLOCK_SIZE, nr_cache_blocks=NR_CACHE_BLOCKS,
+                                      **options)
+
+    def read(self):
+        entries = []
+        with redirect_stderr(io.StringIO()):
+            cache_metadata.read_metadata(self.path, entries.append, hints=True)
+        return entries
+
+    def check(self):
+        """Runs the ``check`` command and returns its output and whether it failed."""
+        output = io.StringIO()
+        with redirect_stdout(output), redirect_stderr(io.StringIO()):
+            try:
+                cache_metadata.check(Namespace(metadata=self.path))
+            except SystemExit as e:
+                return output.getvalue(), e.code != 0
+        return output.getvalue(), False
+
+    def salvage(self, block_size=None, messages=None):
+        entries = []
+        with redirect_stderr(io.StringIO()) as stderr:
+            cache_metadata.salvage_metadata(self.path, entries.append, block_size)
+        if messages is not None:
+            messages.extend(stderr.getvalue().splitlines())
+        return entries
+
+    def array_blocks(self):
+        """Returns the locations of the blocks of the mapping array in the order of the array."""
+        with cache_metadata.dev_open(self.path) as fd:
+            device = cache_metadata.MetadataDevice(fd)
+            superblock = device.read_superblock()
+            blocks = {index // MAX_ENTRIES: block for index, _, block
+                      in device.iter_array(superblock.mapping_root, 8, "mapping array", with_blocks=True)}
+        return [blocks[index] for index in sorted(blocks)]
+
+    def corrupt(self, block, offset, data):
+        with open(self.path, "r+b") as file:
+            file.seek(block * cache_metadata.METADATA_BLOCK_SIZE + offset)
+            file.write(data)
+
+    def test_round_trip(self):
+        for version, hint_size in ((2, 8), (2, 4), (2, 0), (1, 4)):
+            with self.subTest(version=version, hint_size=hint_size):
+                entries = make_entries(hint_size)
+                self.write(entries, hint_size=hint_size, version=version)
+                self.assertEqual(self.read(), entries)
+                self.assertEqual(self.check(), ("", False))
+
+    def test_round_trip_of_hints(self):
+        entries = make_entries(8)
+        self.write(entries, hint_size=8)
+        self.assertEqual(cache_metadata.format_hint(self.read()[1].hint), "2")
+        copy = self.path.with_name("copy.bin")
+        with open(copy, "wb") as file:
+            file.truncate(4 << 20)
+        cache_metadata.write_metadata(copy, self.read(), block_size=BLOCK_SIZE, nr_cache_blocks=NR_CACHE_BLOCKS,
+                                      hint_size=8)
+        self.assertEqual(copy.read_bytes(), self.path.read_bytes())
+
+    def test_corrupted_array_block(self):
+        entries = make_entries(4)
+        self.write(entries)
+        blocks = self.array_blocks()
+        self.assertEqual(len(blocks), 3)
+        # Break the checksum of the second block of the mapping array
+        self.corrupt(blocks[1], 0, b"\xff\xff\xff\xff")
+        output, failed = self.check()
+        self.assertTrue(failed)
+        self.assertIn(f"block {blocks[1]}: ", output)
+        self.assertIn("mapping array", output)
+        self.assertIn(f"cache blocks {MAX_ENTRIES}-{2 * MAX_ENTRIES - 1}", output)
+        # The values of the block are intact, so salvage recovers all mappings
+        salvaged = self.salvage()
+        self.assertEqual([(entry.cache_block, entry.origin_block) for entry in salvaged],
+                         [(entry.cache_block, entry.origin_block) for entry in entries])
+
+    def test_cachevol_regions(self):
+        self.write(make_entries(4))
+        self.assertEqual(cache_metadata.cachevol_regions(self.path), ((4 << 20) // 512, NR_CACHE_BLOCKS * BLOCK_SIZE))
+
+    def test_damaged_superblock(self):
+        entries = make_entries(4)
+        self.write(entries)
+        self.corrupt(0, 0, bytes(64))
+        _, failed = self.check()
+        self.assertTrue(failed)
+        with self.assertRaises(SystemExit):
+            self.salvage()
+        salvaged = self.salvage(BLOCK_SIZE)
+        self.assertEqual([(entry.cache_block, entry.origin_block) for entry in salvaged],
+                         [(entry.cache_block, entry.origin_block) for entry in entries])
+        self.assertTrue(all(entry.dirty for entry in salvaged))
+
+    def test_salvage_with_sparse_dirty_set(self):
+        # Blocks of the dirty bitset are array blocks with 64-bit values, too,
+        # and a word with only the lowest bit set looks like a valid mapping
+        entries = [cache_metadata.make_entry(BLOCK_SIZE, 100 + cache_block, cache_block, cache_block == 0)
+                   for cache_block in range(10)]
+        self.write(entries)
+        messages = []
+        salvaged = self.salvage(messages=messages)
+        self.assertEqual(salvaged, [cache_metadata.make_entry(BLOCK_SIZE, entry.origin_block, entry.cache_block,
+                                                              entry.dirty, salvaged=True) for entry in entries])
+        self.assertFalse([message for message in messages if "conflicting" in message])
+        self.corrupt(0, 0, bytes(64))
+        messages = []
+        salvaged = self.salvage(BLOCK_SIZE, messages)
+        self.assertEqual([(entry.cache_block, entry.origin_block) for entry in salvaged],
+                         [(entry.cache_block, entry.origin_block) for entry in entries])
+        self.assertFalse([message for message in messages if "conflicting" in message])
+
+    def test_generations(self):
+        entries = [cache_metadata.make_entry(BLOCK_SIZE, 100 + cache_block, cache_block, cache_block == 0)
+                   for cache_block in range(10)]
+        self.write(entries)
+        with cache_metadata.dev_open(self.path) as fd:
+            superblock = cache_metadata.MetadataDevice(fd).read_superblock()
+            generations = {generation.root: generation
+                           for generation in cache_metadata.find_generations(fd, superblock)}
+            # Roots which are not referenced by the superblock are classified by their length
+            older = {generation.root: generation for generation in cache_metadata.find_generations(
+                fd, replace(superblock, mapping_root=0, dirty_root=0))}
+        mapping_array = generations[superblock.mapping_root]
+        self.assertEqual((mapping_array.structure, mapping_array.entries, mapping_array.mappings),
+                         ("mapping array", NR_CACHE_BLOCKS, len(entries)))
+        self.assertTrue(mapping_array.current)
+        dirty_bitset = generations[superblock.dirty_root]
+        self.assertEqual((dirty_bitset.structure, dirty_bitset.entries), ("dirty bitset", (NR_CACHE_BLOCKS + 63) // 64))
+        self.assertTrue(dirty_bitset.current)
+        self.assertEqual(older[superblock.mapping_root].structure, "mapping array")
+        self.assertEqual(older[superblock.dirty_root].structure, "dirty bitset")
+        self.assertFalse(older[superblock.dirty_root].current)
+
+
+class StatsTest(unittest.TestCase):
+
+    def setUp(self):
+        directory = tempfile.TemporaryDirectory()
+        self.addCleanup(directory.cleanup)
+        self.directory = Path(directory.name)
+        self.path = self.directory / "metadata.bin"
+        with open(self.path, "wb") as file:
+            file.truncate(4 << 20)
+        # Origin block, cache block and dirty flag of each mapping
+        mappings = [(10, 0, True), (11, 1, True), (12, 5, True), (20, 2, True), (30, 3, False)]
+        entries = [cache_metadata.make_entry(BLOCK_SIZE, origin_block, cache_block, dirty)
+                   for origin_block, cache_block, dirty in mappings]
+        cache_metadata.write_metadata(self.path, entries, block_size=BLOCK_SIZE, nr_cache_blocks=16)
+
+    def stats(self, heatmap=None):
+        """Runs the ``stats`` command for an origin device of 64 blocks and returns its JSON output."""
+        args = Namespace(metadata=self.path, origin=None, origin_size=64 * BLOCK_SIZE, buckets=4, top=10,
+                         heatmap=heatmap, heatmap_columns=8, heatmap_rows=4, heatmap_scale=2, format="json")
+        output = io.StringIO()
+        with redirect_stdout(output), redirect_stderr(io.StringIO()):
+            cache_metadata.stats(args)
+        return json.loads(output.getvalue())
+
+    def test_stats(self):
+        result = self.stats()
+        self.assertEqual((result["mappings"], result["occupancy"], result["dirty"], result["dirty_fraction"]),
+                         (5, 5 / 16, 4, 0.8))
+        self.assertEqual((result["origin_blocks"], result["dirty_runs"]), (64, 2))
+        self.assertEqual(result["largest_dirty_runs"], [{"first": 10, "last": 12, "blocks": 3},
+                                                        {"first": 20, "last": 20, "blocks": 1}])
+        # Origin segments before, between and after the dirty runs,
+        # and the cache blocks 0 and 1 could be merged into one segment
+        self.assertEqual(result["table"], {"cache_segments": 4, "origin_segments": 3, "segments": 7,
+                                           "segments_if_merged": 6})
+        self.assertEqual(result["histogram"], [
+            {"first": 0, "last": 15, "mapped": 3, "dirty": 3},
+            {"first": 16, "last": 31, "mapped": 2, "dirty": 1},
+            {"first": 32, "last": 47, "mapped": 0, "dirty": 0},
+            {"first": 48, "last": 63, "mapped": 0, "dirty": 0},
+        ])
+
+    def test_png_heatmap(self):
+        path = self.directory / "heatmap.png"
+        self.stats(heatmap=path)
+        data = path.read_bytes()
+        self.assertEqual(data[:8], b"\x89PNG\r\n\x1a\n")
+        length, kind, width, height, depth, colour_type = struct.unpack_from(">I4sIIBB", data, 8)
+        # 8x4 cells of 2 origin blocks each, with 2x2 pixels per cell
+        self.assertEqual((length, kind, width, height, depth, colour_type), (13, b"IHDR", 16, 8, 8, 2))
+        self.assertEqual(zlib.crc32(data[12:29]), struct.unpack_from(">I", data, 29)[0])
+        length, kind = struct.unpack_from(">I4s", data, 33)
+        self.assertEqual(kind, b"IDAT")
+        pixels = zlib.decompress(data[41:41 + length])
+        self.assertEqual(len(pixels), height * (1 + 3 * width))
+        self.assertEqual(data[41 + length + 4:], struct.pack(">I4sI", 0, b"IEND", zlib.crc32(b"IEND")))
+        def pixel(x, y):
+            offset = y * (1 + 3 * width) + 1 + 3 * x
+            return tuple(pixels[offset:offset + 3])
+        # The cell of the origin blocks 10 and 11 is completely dirty, the one of block 12 only half
+        self.assertEqual(pixel(10, 0), cache_metadata.HEATMAP_DIRTY[1])
+        self.assertEqual(pixel(11, 1), cache_metadata.HEATMAP_DIRTY[1])
+        self.assertEqual(pixel(12, 0), (178, 94, 87))
+        self.assertEqual(pixel(0, 0), cache_metadata.HEATMAP_EMPTY)
+
+    def test_svg_heatmap(self):
+        path = self.directory / "heatmap.svg"
+        self.stats(heatmap=path)
+        titles = re.findall(r"<title>(.*?)</title>", path.read_text())
+        self.assertEqual(titles, ["origin blocks 10-11: 2 mapped, 2 dirty", "origin blocks 12-13: 1 mapped, 1 dirty",
+                                  "origin blocks 20-21: 1 mapped, 1 dirty", "origin blocks 30-31: 1 mapped, 0 dirty"])
+
+
+class ValidateTest(unittest.TestCase):
+
+    def setUp(self):
+        directory = tempfile.TemporaryDirectory()
+        self.addCleanup(directory.cleanup)
+        self.path = Path(directory.name) / "metadata.xml"
+
+    def validate(self, *lines, origin_size=None):
+        """Validates an XML file with the given lines and returns the issues as reported in JSON."""
+        self.path.write_text("\n".join(lines) + "\n")
+        output = io.StringIO()
+        with redirect_stdout(output), redirect_stderr(io.StringIO()):
+            try:
+                cache_metadata.validate(Namespace(metadata=self.path, origin=None, origin_size=origin_size,
+                                                  format="json"))
+            except SystemExit:
+                pass
+        return [(issue["kind"], issue["line"]) for issue in json.loads(output.getvalue())["issues"]]
+
+    def test_valid_metadata(self):
+        issues = self.validate('<superblock uuid="" block_size="8" nr_cache_blocks="4" policy="smq" hint_width="4">',
+                               '  <mappings>',
+                               '    <mapping cache_block="0" origin_block="1" dirty="true"/>',
+                               '  </mappings>',
+                               '</superblock>', origin_size=16)
+        self.assertEqual(issues, [])
+
+    def test_duplicates_and_ranges(self):
+        self.path.write_text('<superblock uuid="" block_size="8" nr_cache_blocks="4" policy="smq" hint_width="4">\n'
+                             '  <mappings>\n'
+                             '    <mapping cache_block="0" origin_block="1" dirty="true"/>\n'
+                             '    <mapping cache_block="0" origin_block="1" dirty="false"/>\n'
+                             '    <mapping cache_block="4" origin_block="2" dirty="false"/>\n'
+                             '  </mappings>\n'
+                             '  <unknown/>\n'
+                             '</superblock>\n')
+        output = io.StringIO()
+        with redirect_stdout(output), redirect_stderr(io.StringIO()), self.assertRaises(SystemExit):
+            cache_metadata.validate(Namespace(metadata=self.path, origin=None, origin_size=16, format="text"))
+        self.assertEqual(output.getvalue().splitlines(), [
+            "error: line 4: cache block 0 is mapped twice, first at line 3 [duplicate-cache-block]",
+            "error: line 4: origin block 1 is mapped twice, first at line 3 [duplicate-origin-block]",
+            "error: line 5: cache block 4 beyond 4 cache blocks [cache-block-out-of-range]",
+            "error: line 5: origin block 2 beyond 2 origin blocks [origin-block-out-of-range]",
+            "warning: line 7: unknown element <unknown> [unknown-element]",
+        ])
+
+    def test_zero_block_size(self):
+        issues = self.validate('<superblock uuid="" block_size="0" nr_cache_blocks="4" policy="smq" hint_width="4">',
+                               '  <mappings>',
+                               '    <mapping cache_block="0" origin_block="1" dirty="true"/>',
+                               '  </mappings>',
+                               '</superblock>', origin_size=1024)
+        self.assertEqual(issues, [("invalid-block-size", 1)])
+
+    def test_malformed_superblock(self):
+        issues = self.validate('<superblock uuid="" block_size="abc" nr_cache_blocks="4" policy="smq" hint_width="4">',
+                               '  <mappings>',
+                               '    <mapping cache_block="0" origin_block="1" dirty="true"/>',
+                               '    <mapping cache_block="x" origin_block="2" dirty="maybe"/>',
+                               '    <mapping cache_block="5" origin_block="3" dirty="false"/>',
+                               '  </mappings>',
+                               '</superblock>')
+        self.assertEqual(issues, [("malformed-integer", 1), ("malformed-integer", 4), ("malformed-boolean", 4),
+                                  ("cache-block-out-of-range", 5)])
+
+    def test_missing_superblock(self):
+        issues = self.validate('<mappings>',
+                               '  <mapping cache_block="0" origin_block="1" dirty="true"/>',
+                               '  <mapping cache_block="1" origin_block="x" dirty="true"/>',
+                               '</mappings>')
+        self.assertEqual(issues, [("missing-superblock", 1), ("malformed-integer", 3)])
+
+    def test_invalid_binary_superblock(self):
+        self.path = self.path.with_suffix(".bin")
+        with open(self.path, "wb") as file:
+            file.truncate(4 << 20)
+        cache_metadata.write_metadata(self.path, make_entries(4), block_size=BLOCK_SIZE,
+                                      nr_cache_blocks=NR_CACHE_BLOCKS)
+        with open(self.path, "r+b") as file:
+            file.seek(40)
+            file.write((3).to_bytes(4, "little"))
+        issues = []
+        cache_metadata.read_metadata(self.path, lambda entry: None, on_issue=issues.append)
+        self.assertEqual([(issue.kind, issue.block) for issue in issues],
+                         [("corrupted-block", 0), ("unsupported-version", 0)])
+        with open(self.path, "r+b") as file:
+            file.truncate(1024)
+        issues = []
+        cache_metadata.read_metadata(self.path, lambda entry: None, on_issue=issues.append)
+        self.assertEqual([(issue.kind, issue.block) for issue in issues], [("incomplete-superblock", 0)])
+
+
+def crc32c_bitwise(data):
+    crc = 0xffffffff
+    for byte in data:
+        crc ^= byte
+        for _ in range(8):
+            crc = (crc >> 1) ^ 0x82f63b78 if crc & 1 else crc >> 1
+    return crc ^ 0xffffffff
+
+
+class ChecksumTest(unittest.TestCase):
+
+    def test_crc32c(self):
+        self.assertEqual(cache_metadata.crc32c_python(b"123456789"), 0xe3069283)
+        data = bytes(range(256)) * 3
+        for length in (0, 1, 7, 8, 9, 100, len(data)):
+            with self.subTest(length=length):
+                self.assertEqual(cache_metadata.crc32c_python(data[:length]), crc32c_bitwise(data[:length]))
+                self.assertEqual(cache_metadata.crc32c(data[:length]), crc32c_bitwise(data[:length]))
+
+
+if __name__ == "__main__":
+    unittest.main()
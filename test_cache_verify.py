@@ -0,0 +1,209 @@
+#!/usr/bin/env python3
+# -*- coding: utf-8 -*-
+"""Tests of verify, the checkpoints and the writeback plans of ``cache_verify.py``. ::
+
+    $ python3 -m unittest test_cache_verify
+"""
+
+import cache_verify, io, json, os, sys, tempfile, unittest
+from contextlib import redirect_stderr, redirect_stdout
+from pathlib import Path
+from unittest import mock
+
+
+BLOCK_SECTORS = 8
+BLOCK_SIZE = 512 * BLOCK_SECTORS
+# Cache block, origin block and dirty flag of each mapping
+MAPPINGS = [(0, 2, True), (1, 5, False), (2, 7, True), (3, 1, True)]
+
+
+def block(value):
+    return bytes([value]) * BLOCK_SIZE
+
+
+class CacheVerifyTest(unittest.TestCase):
+
+    def setUp(self):
+        directory = tempfile.TemporaryDirectory()
+        self.addCleanup(directory.cleanup)
+        self.directory = Path(directory.name)
+        self.metadata = self.directory / "metadata.xml"
+        self.cache = self.directory / "cache.img"
+        self.origin = self.directory / "origin.img"
+        self.write_metadata(MAPPINGS)
+        self.cache.write_bytes(b"".join(block(0x10 + cache_block) for cache_block in range(len(MAPPINGS))))
+        origin = [block(0)] * 8
+        for cache_block, origin_block, dirty in MAPPINGS:
+            if not dirty:
+                origin[origin_block] = block(0x10 + cache_block)
+        self.origin.write_bytes(b"".join(origin))
+
+    def write_metadata(self, mappings):
+        lines = [f'<superblock uuid="" block_size="{BLOCK_SECTORS}" nr_cache_blocks="{len(MAPPINGS)}"'
+                 ' policy="smq" hint_width="4">', "  <mappings>"]
+        for cache_block, origin_block, dirty in mappings:
+            lines.append(f'    <mapping cache_block="{cache_block}" origin_block="{origin_block}"'
+                         f' dirty="{"true" if dirty else "false"}"/>')
+        lines += ["  </mappings>", "</superblock>"]
+        self.metadata.write_text("\n".join(lines) + "\n")
+
+    def run_verify(self, *options):
+        """Runs ``cache_verify.py`` and returns its exit code and its output."""
+        argv = ["cache_verify.py", *map(str, options), str(self.metadata), str(self.cache), str(self.origin)]
+        stdout = io.StringIO()
+        with mock.patch.object(sys, "argv", argv), redirect_stdout(stdout), redirect_stderr(io.StringIO()):
+            try:
+                cache_verify.main()
+            except SystemExit as e:
+                return e.code, stdout.getvalue()
+        return 0, stdout.getvalue()
+
+    def origin_block(self, origin_block):
+        return self.origin.read_bytes()[origin_block * BLOCK_SIZE:(origin_block + 1) * BLOCK_SIZE]
+
+    def test_classified_dirty_block_with_unreadable_sectors(self):
+        # The last origin block, which belongs to a dirty mapping, ends beyond the origin device
+        with open(self.origin, "r+b") as file:
+            file.truncate(8 * BLOCK_SIZE - 512)
+        for bad_sectors in ("fail", "skip", "zero"):
+            with self.subTest(bad_sectors=bad_sectors):
+                code, output = self.run_verify("--classify", "--bad-sectors", bad_sectors, "--report", "-")
+                self.assertEqual(code, 0)
+                mapping = [json.loads(line) for line in output.splitlines()][2]
+                self.assertEqual((mapping["origin_block"], mapping["status"]), (7, "skipped-dirty"))
+                self.assertIsNone(mapping["bad_sectors"])
+                self.assertIsNotNone(mapping["classification"])
+
+    def test_detail(self):
+        # Change the last sector of the origin block of the clean mapping
+        with open(self.origin, "r+b") as file:
+            file.seek(6 * BLOCK_SIZE - 512)
+            file.write(bytes(512))
+        code, output = self.run_verify("--detail", "--detail-unit", 512)
+        self.assertEqual(code, 0)
+        self.assertIn("differing 512-byte units: 7 (1 of 8 units, 512 bytes)", output)
+        for unit in (1536, 2 * BLOCK_SIZE):
+            with self.subTest(unit=unit):
+                code, _ = self.run_verify("--detail", "--detail-unit", unit)
+                self.assertIn("must divide the block size", code)
+
+    def test_checkpoint_of_completed_run(self):
+        checkpoint = self.directory / "verify.json"
+        self.assertEqual(self.run_verify("--checkpoint", checkpoint)[0], 0)
+        recorded = json.loads(checkpoint.read_text())
+        self.assertTrue(recorded["completed"])
+        self.assertEqual(recorded["processed"], len(MAPPINGS))
+        code, _ = self.run_verify("--checkpoint", checkpoint)
+        self.assertIn("already exists", code)
+        code, _ = self.run_verify("--checkpoint", checkpoint, "--resume")
+        self.assertIn("completed run", code)
+
+    def test_resume_writeback(self):
+        checkpoint = self.directory / "writeback.json"
+        self.assertEqual(self.run_verify("--writeback", "--checkpoint", checkpoint)[0], 0)
+        # Pretend the run was interrupted after the first two mappings
+        recorded = json.loads(checkpoint.read_text())
+        checkpoint.write_text(json.dumps(recorded | {"completed": False, "processed": 2}))
+        self.origin.write_bytes(bytes(8 * BLOCK_SIZE))
+        self.assertEqual(self.run_verify("--writeback", "--checkpoint", checkpoint, "--resume")[0], 0)
+        self.assertEqual(self.origin_block(2), bytes(BLOCK_SIZE))
+        self.assertEqual(self.origin_block(7), block(0x12))
+        self.assertEqual(self.origin_block(1), block(0x13))
+
+    def test_resume_verify_with_report(self):
+        # The cache block of the last mapping belongs to the origin block of the first one
+        with open(self.origin, "r+b") as file:
+            file.seek(2 * BLOCK_SIZE)
+            file.write(block(0x13))
+        options = ["--all", "--classify", "--classify-neighbours", 0, "--report", self.directory / "report.jsonl"]
+        def records():
+            lines = (self.directory / "report.jsonl").read_text().splitlines()
+            return [{name: value for name, value in json.loads(line).items() if name != "elapsed_seconds"}
+                    for line in lines]
+        self.assertEqual(self.run_verify(*options)[0], 0)
+        expected = records()
+        self.assertEqual(expected[3]["similar_origin_block"], 2)
+
+        checkpoint = self.directory / "verify.json"
+        advance = cache_verify.Checkpoint.advance
+        def interrupted_advance(self, **state):
+            advance(self, **state)
+            if self.processed == 2:
+                self.write()
+                raise KeyboardInterrupt
+        with mock.patch.object(cache_verify.Checkpoint, "advance", interrupted_advance), \
+                self.assertRaises(KeyboardInterrupt):
+            self.run_verify(*options, "--checkpoint", checkpoint)
+        self.assertEqual(len(records()), 2)
+        self.assertEqual(self.run_verify(*options, "--checkpoint", checkpoint, "--resume")[0], 0)
+        self.assertEqual(records(), expected)
+
+    def test_resume_with_changed_metadata(self):
+        checkpoint = self.directory / "writeback.json"
+        self.assertEqual(self.run_verify("--writeback", "--checkpoint", checkpoint)[0], 0)
+        recorded = json.loads(checkpoint.read_text())
+        checkpoint.write_text(json.dumps(recorded | {"completed": False, "processed": 2}))
+        self.write_metadata(MAPPINGS[:3])
+        code, _ = self.run_verify("--writeback", "--checkpoint", checkpoint, "--resume")
+        self.assertIn("inputs have changed", code)
+
+    def test_resume_with_metadata_changed_after_the_first_mebibyte(self):
+        def write_metadata(mappings):
+            self.write_metadata(mappings)
+            lines = self.metadata.read_text().splitlines()
+            lines[1:1] = [f"<!-- {'x' * (1 << 20)} -->"]
+            self.metadata.write_text("\n".join(lines) + "\n")
+        write_metadata(MAPPINGS)
+        checkpoint = self.directory / "verify.json"
+        self.assertEqual(self.run_verify("--checkpoint", checkpoint)[0], 0)
+        recorded = json.loads(checkpoint.read_text())
+        checkpoint.write_text(json.dumps(recorded | {"completed": False, "processed": 2}))
+        # Block devices have no modification time, and the size stays the same, so only the content tells
+        mtime = self.metadata.stat().st_mtime_ns
+        write_metadata([(0, 2, True), (1, 6, False), (2, 7, True), (3, 1, True)])
+        os.utime(self.metadata, ns=(mtime, mtime))
+        code, _ = self.run_verify("--checkpoint", checkpoint, "--resume")
+        self.assertIn("inputs have changed", code)
+
+    def test_plan_round_trip(self):
+        plan = self.directory / "plan.jsonl"
+        original = self.origin.read_bytes()
+        code, output = self.run_verify("--writeback", "--dry-run", "--plan", plan)
+        self.assertEqual(code, 0)
+        self.assertEqual(self.origin.read_bytes(), original)
+        records = [json.loads(line) for line in plan.read_text().splitlines()]
+        self.assertEqual([record["type"] for record in records], ["header", "mapping", "mapping", "mapping", "summary"])
+        self.assertEqual([(record["cache_block"], record["status"]) for record in records[1:-1]],
+                         [(0, "copy"), (2, "copy"), (3, "copy")])
+        self.assertEqual(records[-1]["bytes_written"], 3 * BLOCK_SIZE)
+        # The origin blocks 1 and 2 are adjacent
+        self.assertEqual(records[-1]["origin_ranges"], [[BLOCK_SECTORS, 3 * BLOCK_SECTORS],
+                                                        [7 * BLOCK_SECTORS, 8 * BLOCK_SECTORS]])
+        self.assertEqual(len(output.splitlines()), 3)
+
+        self.assertEqual(self.run_verify("--writeback", "--plan", plan)[0], 0)
+        for cache_block, origin_block, _ in MAPPINGS:
+            self.assertEqual(self.origin_block(origin_block), block(0x10 + cache_block))
+
+    def test_plan_of_changed_block(self):
+        plan = self.directory / "plan.jsonl"
+        self.assertEqual(self.run_verify("--writeback", "--dry-run", "--plan", plan)[0], 0)
+        with open(self.cache, "r+b") as file:
+            file.seek(2 * BLOCK_SIZE)
+            file.write(block(0xee))
+        code, _ = self.run_verify("--writeback", "--plan", plan)
+        self.assertIn("changed since the plan", code)
+        # Only the changed mapping is left out
+        self.assertEqual(self.origin_block(2), block(0x10))
+        self.assertEqual(self.origin_block(7), bytes(BLOCK_SIZE))
+        self.assertEqual(self.origin_block(1), block(0x13))
+
+    def test_plan_with_other_options(self):
+        plan = self.directory / "plan.jsonl"
+        self.assertEqual(self.run_verify("--writeback", "--dry-run", "--plan", plan)[0], 0)
+        code, _ = self.run_verify("--writeback", "--all", "--plan", plan)
+        self.assertIn("inputs have changed", code)
+
+
+if __name__ == "__main__":
+    unittest.main()
@@ -0,0 +1,73 @@
+#!/usr/bin/env python3
+# -*- coding: utf-8 -*-
+"""Tests of the mapfiles of GNU ddrescue in ``ddrescue_mapfile.py``. ::
+
+    $ python3 -m unittest test_ddrescue_mapfile
+"""
+
+import ddrescue_mapfile, random, tempfile, unittest
+from pathlib import Path
+
+
+class MapfileTest(unittest.TestCase):
+
+    def setUp(self):
+        directory = tempfile.TemporaryDirectory()
+        self.addCleanup(directory.cleanup)
+        self.path = Path(directory.name) / "device.map"
+
+    def entries(self):
+        """Returns the ranges and statuses of the written mapfile."""
+        lines = [line.split("#", 1)[0].split() for line in self.path.read_text().splitlines()]
+        lines = [line for line in lines if line]
+        return [(int(position, 0), int(size, 0), status) for position, size, status in lines[1:]]
+
+    def test_round_trip(self):
+        mapfile = ddrescue_mapfile.Mapfile(8192)
+        mapfile.set(0, 1024, "+")
+        mapfile.set(2048, 2560, "-")
+        mapfile.set(4096, 8192, "+")
+        mapfile.write(self.path)
+        self.assertEqual(self.entries(), [(0, 1024, "+"), (1024, 1024, "?"), (2048, 512, "-"),
+                                          (2560, 1536, "?"), (4096, 4096, "+")])
+        read = ddrescue_mapfile.Mapfile.read(self.path)
+        self.assertEqual(read.unfinished(0, 8192), [(1024, 4096)])
+        self.assertEqual(read.unfinished(2048, 2560), [(2048, 2560)])
+        self.assertEqual(read.unfinished(4096, 8192), [])
+        # Ranges beyond the end of the mapfile have not been rescued either
+        self.assertEqual(read.unfinished(7168, 9216), [(8192, 9216)])
+
+    def test_random_reads_are_merged(self):
+        mapfile = ddrescue_mapfile.Mapfile(4096 * 1000)
+        blocks = list(range(1000))
+        random.Random(1).shuffle(blocks)
+        for block in blocks:
+            if block != 500:
+                mapfile.set(4096 * block, 4096 * (block + 1), "+")
+        mapfile.set(4096 * 500 + 512, 4096 * 500 + 1024, "-")
+        self.assertEqual(mapfile.unfinished(0, 4096 * 1000), [(4096 * 500, 4096 * 501)])
+        mapfile.write(self.path)
+        self.assertEqual(self.entries(), [(0, 4096 * 500, "+"), (4096 * 500, 512, "?"), (4096 * 500 + 512, 512, "-"),
+                                          (4096 * 500 + 1024, 3072, "?"), (4096 * 501, 4096 * 499, "+")])
+
+    def test_rescued_ranges_take_precedence(self):
+        mapfile = ddrescue_mapfile.Mapfile(4096)
+        mapfile.set(0, 4096, "-")
+        mapfile.set(1024, 2048, "+")
+        mapfile.write(self.path)
+        self.assertEqual(self.entries(), [(0, 1024, "-"), (1024, 1024, "+"), (2048, 2048, "-")])
+
+    def test_ranges_are_limited_to_the_size(self):
+        mapfile = ddrescue_mapfile.Mapfile(1024)
+        mapfile.set(512, 2048, "+")
+        mapfile.write(self.path)
+        self.assertEqual(self.entries(), [(0, 512, "?"), (512, 512, "+")])
+
+    def test_invalid_mapfile(self):
+        self.path.write_text("0x0 + 1\n0x0 0x200\n")
+        with self.assertRaises(SystemExit):
+            ddrescue_mapfile.Mapfile.read(self.path)
+
+
+if __name__ == "__main__":
+    unittest.main()
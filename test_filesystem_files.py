@@ -0,0 +1,180 @@
+#!/usr/bin/env python3
+# -*- coding: utf-8 -*-
+"""Tests of mapping device ranges to files in ``filesystem_files.py``.
+The filesystems are created by ``mkfs.ext4``, ``mkfs.xfs`` and ``mkfs.btrfs``;
+the tests of a filesystem are skipped without its tools. ::
+
+    $ python3 -m unittest test_filesystem_files
+"""
+
+import filesystem_files, io, mmap, re, shutil, subprocess, tempfile, unittest
+from pathlib import Path
+
+
+BLOCK_SIZE = 4096
+# Large enough for the minimum sizes of XFS and btrfs, the images are sparse
+IMAGE_SIZE = 512 << 20
+
+
+def file_content(name, blocks):
+    """Returns the content of a file whose blocks can be told apart."""
+    return b"".join(f"{name} block {index}\n".encode().ljust(BLOCK_SIZE, b".") for index in range(blocks))
+
+
+def find_block(image, data):
+    """Returns the position of the first block of the image starting with the given data."""
+    with open(image, "rb") as file, mmap.mmap(file.fileno(), 0, access=mmap.ACCESS_READ) as content:
+        position = content.find(data)
+        while position >= 0 and position % BLOCK_SIZE != 0:
+            position = content.find(data, position + 1)
+    if position < 0:
+        raise AssertionError("data not found in the image")
+    return position
+
+
+@unittest.skipIf(shutil.which("mkfs.ext4") is None or shutil.which("debugfs") is None,
+                 "mkfs.ext4 and debugfs are required")
+class Ext4Test(unittest.TestCase):
+
+    @classmethod
+    def setUpClass(cls):
+        directory = tempfile.TemporaryDirectory()
+        cls.addClassCleanup(directory.cleanup)
+        root = Path(directory.name) / "root"
+        (root / "dir").mkdir(parents=True)
+        (root / "dir" / "large.bin").write_bytes(bytes(range(256)) * 1024)
+        (root / "small.txt").write_text("small\n")
+        cls.image = Path(directory.name) / "ext4.img"
+        subprocess.run(["mkfs.ext4", "-q", "-F", "-b", str(BLOCK_SIZE), "-d", str(root), str(cls.image), "16M"],
+                       check=True, stdout=subprocess.DEVNULL)
+
+    def blocks(self, path):
+        """Returns the blocks of a file as listed by ``debugfs``."""
+        result = subprocess.run(["debugfs", "-R", f"blocks {path}", str(self.image)],
+                                check=True, capture_output=True, text=True)
+        return [int(block) for block in result.stdout.split()]
+
+    def test_data_blocks(self):
+        blocks = self.blocks("/dir/large.bin")
+        self.assertEqual(len(blocks), 64)
+        first = BLOCK_SIZE * blocks[10]
+        impacts, files = filesystem_files.affected_files(self.image, [(first, first + BLOCK_SIZE, "range")])
+        hit, = impacts[0].hits
+        self.assertEqual(hit.extent.kind, "data")
+        self.assertEqual(hit.extent.offset + hit.first - hit.extent.first, 10 * BLOCK_SIZE)
+        self.assertEqual(list(files.values()), [["/dir/large.bin"]])
+
+    def test_inode_table(self):
+        first = BLOCK_SIZE * self.blocks("/small.txt")[0]
+        impacts, files = filesystem_files.affected_files(self.image, [(0, 64 * BLOCK_SIZE, "start"),
+                                                                      (first, first + 512, "small")])
+        kinds = {hit.extent.kind for hit in impacts[0].hits}
+        self.assertIn("inode-table", kinds)
+        self.assertIn(["/small.txt"], files.values())
+        self.assertIn(["/dir/large.bin"], files.values())
+        self.assertEqual([hit.extent.kind for hit in impacts[1].hits], ["data"])
+
+    def test_report(self):
+        first = BLOCK_SIZE * self.blocks("/small.txt")[0]
+        impacts, files = filesystem_files.affected_files(self.image, [(first, first + 512, "small")])
+        output = io.StringIO()
+        filesystem_files.print_impacts(impacts, files, file=output)
+        self.assertRegex(output.getvalue(), re.compile(r"^1 files affected$", re.MULTILINE))
+        self.assertIn("/small.txt", output.getvalue())
+
+
+@unittest.skipIf(shutil.which("mkfs.xfs") is None, "mkfs.xfs is required")
+class XfsTest(unittest.TestCase):
+
+    @classmethod
+    def setUpClass(cls):
+        directory = tempfile.TemporaryDirectory()
+        cls.addClassCleanup(directory.cleanup)
+        root = Path(directory.name) / "root"
+        (root / "dir").mkdir(parents=True)
+        (root / "dir" / "large.bin").write_bytes(file_content("large.bin", 64))
+        (root / "small.txt").write_text("small\n")
+        # The protofile lists the files of each directory up to a "$"
+        protofile = Path(directory.name) / "protofile"
+        protofile.write_text("\n".join([
+            "/dev/null", "0 0", "d--755 0 0",
+            "dir d--755 0 0", f"large.bin ---644 0 0 {root / 'dir' / 'large.bin'}", "$",
+            f"small.txt ---644 0 0 {root / 'small.txt'}", "$", ""]))
+        cls.image = Path(directory.name) / "xfs.img"
+        with open(cls.image, "wb") as file:
+            file.truncate(IMAGE_SIZE)
+        subprocess.run(["mkfs.xfs", "-q", "-f", "-b", f"size={BLOCK_SIZE}", "-p", str(protofile), str(cls.image)],
+                       check=True, stdout=subprocess.DEVNULL)
+
+    def test_data_blocks(self):
+        first = find_block(self.image, b"large.bin block 10\n")
+        impacts, files = filesystem_files.affected_files(self.image, [(first, first + BLOCK_SIZE, "range")])
+        hit, = impacts[0].hits
+        self.assertEqual(hit.extent.kind, "data")
+        self.assertEqual(hit.extent.offset + hit.first - hit.extent.first, 10 * BLOCK_SIZE)
+        self.assertEqual(list(files.values()), [["/dir/large.bin"]])
+
+    def test_metadata(self):
+        first = find_block(self.image, b"small\n")
+        impacts, files = filesystem_files.affected_files(self.image, [(0, IMAGE_SIZE, "all"),
+                                                                      (first, first + 512, "small")])
+        kinds = {hit.extent.kind for hit in impacts[0].hits}
+        for kind in ("superblock", "agf", "agi", "agfl", "free-space-btree", "inode-btree", "inode-chunk", "log"):
+            self.assertIn(kind, kinds)
+        self.assertIn(["/small.txt"], files.values())
+        self.assertIn(["/dir/large.bin"], files.values())
+        self.assertEqual([hit.extent.kind for hit in impacts[1].hits], ["data"])
+
+
+@unittest.skipIf(shutil.which("mkfs.btrfs") is None, "mkfs.btrfs is required")
+class BtrfsTest(unittest.TestCase):
+
+    @classmethod
+    def setUpClass(cls):
+        directory = tempfile.TemporaryDirectory()
+        cls.addClassCleanup(directory.cleanup)
+        root = Path(directory.name) / "root"
+        (root / "dir").mkdir(parents=True)
+        (root / "sub").mkdir()
+        (root / "dir" / "large.bin").write_bytes(file_content("large.bin", 64))
+        (root / "sub" / "inner.bin").write_bytes(file_content("inner.bin", 16))
+        cls.image = Path(directory.name) / "btrfs.img"
+        with open(cls.image, "wb") as file:
+            file.truncate(IMAGE_SIZE)
+        # Older versions cannot create subvolumes without mounting the filesystem
+        usage = subprocess.run(["mkfs.btrfs", "--help"], capture_output=True, text=True)
+        cls.subvolume = "--subvol" in usage.stdout + usage.stderr
+        options = ["--subvol", "sub"] if cls.subvolume else []
+        subprocess.run(["mkfs.btrfs", "-q", "-f", "--rootdir", str(root), *options, str(cls.image)],
+                       check=True, stdout=subprocess.DEVNULL)
+
+    def test_data_blocks(self):
+        first = find_block(self.image, b"large.bin block 10\n")
+        impacts, files = filesystem_files.affected_files(self.image, [(first, first + BLOCK_SIZE, "range")])
+        hit, = impacts[0].hits
+        self.assertEqual((hit.extent.kind, hit.extent.root), ("data", filesystem_files.BTRFS_FS_TREE))
+        self.assertEqual(hit.extent.offset + hit.first - hit.extent.first, 10 * BLOCK_SIZE)
+        self.assertEqual(list(files.values()), [["/dir/large.bin"]])
+
+    def test_subvolume(self):
+        if not self.subvolume:
+            self.skipTest("mkfs.btrfs cannot create subvolumes")
+        first = find_block(self.image, b"inner.bin block 3\n")
+        impacts, files = filesystem_files.affected_files(self.image, [(first, first + BLOCK_SIZE, "range")])
+        hit, = impacts[0].hits
+        self.assertEqual(hit.extent.kind, "data")
+        self.assertNotEqual(hit.extent.root, filesystem_files.BTRFS_FS_TREE)
+        self.assertEqual(hit.extent.offset + hit.first - hit.extent.first, 3 * BLOCK_SIZE)
+        self.assertEqual(files, {(hit.extent.root, hit.extent.inode): ["/sub/inner.bin"]})
+
+    def test_metadata(self):
+        impacts, _ = filesystem_files.affected_files(self.image, [(0, IMAGE_SIZE, "all")])
+        kinds = {hit.extent.kind for hit in impacts[0].hits}
+        for kind in ("superblock", "root-tree-block", "extent-tree-block", "chunk-tree-block", "fs-tree-block"):
+            self.assertIn(kind, kinds)
+        superblock = [hit for hit in impacts[0].hits if hit.first == filesystem_files.BTRFS_SUPERBLOCK_OFFSETS[0]]
+        self.assertEqual([hit.extent.kind for hit in superblock], ["superblock"])
+
+
+if __name__ == "__main__":
+    unittest.main()
@@ -0,0 +1,260 @@
+#!/usr/bin/env python3
+# -*- coding: utf-8 -*-
+"""Tests of reading the text metadata of LVM2 in ``lvm_metadata.py``. ::
+
+    $ python3 -m unittest test_lvm_metadata
+"""
+
+import io, lvm_metadata, struct, tempfile, unittest
+from contextlib import redirect_stderr
+from pathlib import Path
+
+
+PV_UUID = "0123456789abcdefghijklmnopqrstuv"
+PV_ID = "012345-6789-abcd-efgh-ijkl-mnop-qrstuv"
+EXTENT_SIZE = 8192
+PE_START = 2048
+MDA_OFFSET = 4096
+MDA_SIZE = 1 << 16
+
+METADATA = f"""# Generated by LVM2
+contents = "Text Format Volume Group"
+version = 1
+
+vg {{
+    id = "vg-id"
+    seqno = 7
+    status = ["RESIZEABLE", "READ", "WRITE"]
+    extent_size = {EXTENT_SIZE}
+
+    physical_volumes {{
+        pv0 {{
+            id = "{PV_ID}"
+            device = "/dev/nonexistent-pv"
+            pe_start = {PE_START}
+            pe_count = 100
+        }}
+    }}
+
+    logical_volumes {{
+        data_corig {{
+            id = "corig-id"
+            segment_count = 2
+            segment1 {{
+                start_extent = 0
+                extent_count = 2
+                type = "striped"
+                stripe_count = 1
+                stripes = [
+                    "pv0", 0
+                ]
+            }}
+            segment2 {{
+                start_extent = 2
+                extent_count = 1
+                type = "striped"
+                stripe_count = 1
+                stripes = [
+                    "pv0", 20
+                ]
+            }}
+        }}
+        data {{
+            segment_count = 1
+            segment1 {{
+                start_extent = 0
+                extent_count = 3
+                type = "cache"
+                cache_mode = "writeback"
+                cache_pool = "fast_cvol"
+                origin = "data_corig"
+                metadata_format = 2
+                metadata_start = 0
+                metadata_len = 1024
+                data_start = 1024
+                data_len = {EXTENT_SIZE - 1024}
+            }}
+        }}
+        fast_cvol {{
+            segment_count = 1
+            segment1 {{
+                start_extent = 0
+                extent_count = 1
+                type = "striped"
+                stripe_count = 1
+                stripes = [
+                    "pv0", 10
+                ]
+            }}
+        }}
+        pooled {{
+            segment_count = 1
+            segment1 {{
+                start_extent = 0
+                extent_count = 1
+                type = "cache"
+                cache_pool = "cpool"
+                origin = "pooled_corig"
+            }}
+        }}
+        cpool {{
+            segment_count = 1
+            segment1 {{
+                start_extent = 0
+                extent_count = 1
+                type = "cache-pool"
+                data = "cpool_cdata"
+                metadata = "cpool_cmeta"
+            }}
+        }}
+        mirrored {{
+            segment_count = 1
+            segment1 {{
+                start_extent = 0
+                extent_count = 2
+                type = "striped"
+                stripe_count = 2
+                stripe_size = 128
+                stripes = [
+                    "pv0", 30,
+                    "pv0", 40
+                ]
+            }}
+        }}
+    }}
+}}
+"""
+
+
+def label_sector(metadata_areas):
+    """Returns the label of a physical volume with a data area and the given metadata areas."""
+    header = PV_UUID.encode() + struct.pack("<Q", 100 * EXTENT_SIZE * 512)
+    header += struct.pack("<QQ", PE_START * 512, 0) + struct.pack("<QQ", 0, 0)
+    for offset, size in metadata_areas:
+        header += struct.pack("<QQ", offset, size)
+    header += struct.pack("<QQ", 0, 0)
+    data = bytearray(512)
+    data[0:8] = lvm_metadata.LABEL_ID
+    data[8:16] = struct.pack("<Q", 1)
+    data[20:24] = struct.pack("<I", 32)
+    data[24:32] = lvm_metadata.LABEL_TYPE
+    data[32:32 + len(header)] = header
+    data[16:20] = struct.pack("<I", lvm_metadata.lvm_crc(bytes(data[20:])))
+    return bytes(data)
+
+
+def mda_header(start, size, text_offset, text):
+    data = bytearray(lvm_metadata.MDA_HEADER_SIZE)
+    data[4:20] = lvm_metadata.MDA_MAGIC
+    struct.pack_into("<IQQ", data, 20, 1, start, size)
+    struct.pack_into("<QQII", data, 40, text_offset, len(text), lvm_metadata.lvm_crc(text), 0)
+    data[0:4] = struct.pack("<I", lvm_metadata.lvm_crc(bytes(data[4:])))
+    return bytes(data)
+
+
+def make_physical_volume(path, text, text_offset):
+    """Writes a physical volume whose metadata area holds the text at the given offset,
+    wrapping around at the end of the area.
+    """
+    area = bytearray(MDA_SIZE)
+    area[:lvm_metadata.MDA_HEADER_SIZE] = mda_header(MDA_OFFSET, MDA_SIZE, text_offset, text)
+    first = min(len(text), MDA_SIZE - text_offset)
+    area[text_offset:text_offset + first] = text[:first]
+    rest = text[first:]
+    area[lvm_metadata.MDA_HEADER_SIZE:lvm_metadata.MDA_HEADER_SIZE + len(rest)] = rest
+    with open(path, "wb") as file:
+        file.write(bytes(512) + label_sector([(MDA_OFFSET, MDA_SIZE)]))
+        file.seek(MDA_OFFSET)
+        file.write(area)
+        file.truncate(PE_START * 512 + 100 * EXTENT_SIZE * 512)
+
+
+class LvmMetadataTest(unittest.TestCase):
+
+    def setUp(self):
+        directory = tempfile.TemporaryDirectory()
+        self.addCleanup(directory.cleanup)
+        self.directory = Path(directory.name)
+
+    def read(self, text=METADATA, text_offset=None):
+        """Reads the volume group from a backup file, or from a physical volume if ``text_offset`` is given."""
+        if text_offset is None:
+            path = self.directory / "backup"
+            path.write_text(text)
+        else:
+            path = self.directory / "pv.img"
+            make_physical_volume(path, text.encode(), text_offset)
+        with redirect_stderr(io.StringIO()) as stderr:
+            vg = lvm_metadata.read_volume_group(path)
+        self.assertEqual(stderr.getvalue(), "")
+        return path, vg
+
+    def test_backup_file(self):
+        _, vg = self.read()
+        self.assertEqual(vg.name, "vg")
+        self.assertEqual(vg.extent_size, EXTENT_SIZE)
+        self.assertEqual(vg.pvs["pv0"].id, PV_ID)
+        self.assertEqual(vg.pvs["pv0"].pe_start, PE_START)
+        segments = vg.lvs["data_corig"].segments
+        self.assertEqual([(segment.start, segment.length, segment.type, segment.stripes) for segment in segments], [
+            (0, 2 * EXTENT_SIZE, "linear", [("pv0", PE_START)]),
+            (2 * EXTENT_SIZE, EXTENT_SIZE, "linear", [("pv0", PE_START + 20 * EXTENT_SIZE)]),
+        ])
+        segment, = vg.lvs["mirrored"].segments
+        self.assertEqual((segment.type, segment.stripe_size), ("striped", 128))
+
+    def test_physical_volume(self):
+        path, vg = self.read(text_offset=lvm_metadata.MDA_HEADER_SIZE + 1024)
+        self.assertEqual(vg, self.read()[1])
+        with open(path, "rb") as file:
+            label = lvm_metadata.read_label(file.fileno())
+        self.assertEqual(label.uuid, PV_UUID)
+        self.assertEqual(label.data_areas, [(PE_START * 512, 0)])
+        self.assertEqual(label.metadata_areas, [(MDA_OFFSET, MDA_SIZE)])
+        self.assertEqual(lvm_metadata.find_pv_devices(vg, [path]), {"pv0": path})
+
+    def test_wrapped_metadata_text(self):
+        _, vg = self.read(text_offset=MDA_SIZE - 100)
+        self.assertEqual(vg, self.read()[1])
+
+    def test_table(self):
+        _, vg = self.read()
+        devices = {"pv0": Path("/dev/pv")}
+        self.assertEqual(list(lvm_metadata.table_lines(vg, "vg/data_corig", devices)), [
+            f"0 {2 * EXTENT_SIZE} linear /dev/pv {PE_START}",
+            f"{2 * EXTENT_SIZE} {EXTENT_SIZE} linear /dev/pv {PE_START + 20 * EXTENT_SIZE}",
+        ])
+        self.assertEqual(list(lvm_metadata.table_lines(vg, "[mirrored]", devices)), [
+            f"0 {2 * EXTENT_SIZE} striped 2 128 /dev/pv {PE_START + 30 * EXTENT_SIZE} /dev/pv {PE_START + 40 * EXTENT_SIZE}",
+        ])
+
+    def test_cache_volumes(self):
+        _, vg = self.read()
+        self.assertEqual(lvm_metadata.cache_volumes(vg, "vg/data"), ("fast_cvol:metadata", "fast_cvol:data", "data_corig"))
+        self.assertEqual(lvm_metadata.cache_volumes(vg, "pooled"), ("cpool_cmeta", "cpool_cdata", "pooled_corig"))
+        self.assertIsNone(lvm_metadata.cache_volumes(vg, "data_corig"))
+        self.assertEqual(lvm_metadata.cache_volume(vg, "vg/data", "cache"), "fast_cvol:data")
+        self.assertEqual(lvm_metadata.cache_volume(vg, "data_corig", "origin"), "data_corig")
+
+    def test_cachevol_views(self):
+        _, vg = self.read()
+        self.assertEqual(lvm_metadata.find_view(vg, "fast_cvol:metadata"), ("fast_cvol", range(0, 1024)))
+        self.assertEqual(lvm_metadata.find_view(vg, "fast_cvol:data"), ("fast_cvol", range(1024, EXTENT_SIZE)))
+        devices = {"pv0": Path("/dev/pv")}
+        start = PE_START + 10 * EXTENT_SIZE
+        self.assertEqual(list(lvm_metadata.table_lines(vg, "fast_cvol:metadata", devices)),
+                         [f"0 1024 linear /dev/pv {start}"])
+        self.assertEqual(list(lvm_metadata.table_lines(vg, "fast_cvol:data", devices)),
+                         [f"0 {EXTENT_SIZE - 1024} linear /dev/pv {start + 1024}"])
+        for name in ("fast_cvol:other", "data_corig:data"):
+            with self.subTest(name=name), self.assertRaises(SystemExit):
+                lvm_metadata.find_view(vg, name)
+
+    def test_invalid_metadata(self):
+        for text in ("vg { extent_size = }", "vg { extent_size = 8", "vg { extent_size 8 }"):
+            with self.subTest(text=text), self.assertRaises(SystemExit):
+                self.read(text)
+
+
+if __name__ == "__main__":
+    unittest.main()
@@ -0,0 +1,117 @@
+#!/usr/bin/env python3
+# -*- coding: utf-8 -*-
+"""Tests of the undo journal and the rollback of ``undo_journal.py``. ::
+
+    $ python3 -m unittest test_undo_journal
+"""
+
+import io, tempfile, undo_journal, unittest
+from argparse import Namespace
+from contextlib import redirect_stderr
+from pathlib import Path
+
+
+BLOCK_SIZE = 4096
+ORIGIN_SIZE = 4 * BLOCK_SIZE
+
+
+def block(value):
+    return bytes([value]) * BLOCK_SIZE
+
+
+class UndoJournalTest(unittest.TestCase):
+
+    def setUp(self):
+        directory = tempfile.TemporaryDirectory()
+        self.addCleanup(directory.cleanup)
+        self.journal = Path(directory.name) / "undo.bin"
+        self.origin = Path(directory.name) / "origin.bin"
+        self.origin.write_bytes(b"".join(block(index) for index in range(ORIGIN_SIZE // BLOCK_SIZE)))
+        self.original = self.origin.read_bytes()
+
+    def write_back(self, *writes):
+        """Writes the given blocks to the origin like the writeback, saving their contents to the journal."""
+        with redirect_stderr(io.StringIO()), \
+                undo_journal.open_journal(self.journal, str(self.origin), ORIGIN_SIZE) as journal:
+            for offset, data, bad in writes:
+                with open(self.origin, "r+b") as file:
+                    file.seek(offset)
+                    old = file.read(len(data))
+                    journal.save(offset, old, bad, data)
+                    file.seek(offset)
+                    file.write(data)
+
+    def read(self):
+        with redirect_stderr(io.StringIO()):
+            return undo_journal.read_journal(self.journal)
+
+    def rollback(self, force=False):
+        args = Namespace(journal=self.journal, origin=self.origin, lvm=None, pv_device=[], force=force, dry_run=False)
+        with redirect_stderr(io.StringIO()):
+            undo_journal.rollback(args)
+
+    def test_round_trip(self):
+        self.write_back((BLOCK_SIZE, block(0xaa), []), (3 * BLOCK_SIZE, block(0xbb), [(512, 1024)]))
+        journal = self.read()
+        self.assertEqual(journal.origin, str(self.origin))
+        self.assertEqual(journal.origin_size, ORIGIN_SIZE)
+        self.assertEqual(journal.end, journal.size)
+        self.assertEqual([(record.offset, record.length, record.bad) for record in journal.records],
+                         [(BLOCK_SIZE, BLOCK_SIZE, []), (3 * BLOCK_SIZE, BLOCK_SIZE, [(512, 1024)])])
+        with open(self.journal, "rb") as file:
+            file.seek(journal.records[1].position)
+            self.assertEqual(file.read(BLOCK_SIZE), block(3))
+
+    def test_torn_last_record(self):
+        self.write_back((0, block(0xaa), []), (BLOCK_SIZE, block(0xbb), []))
+        complete = self.read()
+        with open(self.journal, "r+b") as file:
+            file.truncate(complete.size - 100)
+        journal = self.read()
+        self.assertEqual(len(journal.records), 1)
+        self.assertLess(journal.end, journal.size)
+        # Continuing the journal drops the incomplete record
+        self.write_back((2 * BLOCK_SIZE, block(0xcc), []))
+        journal = self.read()
+        self.assertEqual([record.offset for record in journal.records], [0, 2 * BLOCK_SIZE])
+        self.assertEqual(journal.end, journal.size)
+
+    def test_rollback_of_block_written_twice(self):
+        self.write_back((BLOCK_SIZE, block(0xaa), []), (2 * BLOCK_SIZE, block(0xbb), []))
+        # A resumed writeback writes the first block again
+        self.write_back((BLOCK_SIZE, block(0xcc), []))
+        self.rollback()
+        self.assertEqual(self.origin.read_bytes(), self.original)
+
+    def test_rollback_skips_unchanged_blocks(self):
+        self.write_back((BLOCK_SIZE, block(0xaa), []), (2 * BLOCK_SIZE, block(0xbb), []))
+        with open(self.origin, "r+b") as file:
+            file.seek(2 * BLOCK_SIZE)
+            file.write(block(2))
+        with open(self.origin, "rb") as file:
+            restore, unchanged, changed = undo_journal.plan_rollback(file.fileno(), self.read().records)
+        self.assertEqual([record.offset for record in restore], [BLOCK_SIZE])
+        self.assertEqual(unchanged, 1)
+        self.assertEqual(changed, [])
+
+    def test_rollback_of_changed_block(self):
+        self.write_back((BLOCK_SIZE, block(0xaa), []))
+        with open(self.origin, "r+b") as file:
+            file.seek(BLOCK_SIZE)
+            file.write(block(0xdd))
+        with self.assertRaises(SystemExit):
+            self.rollback()
+        self.assertEqual(self.origin.read_bytes()[BLOCK_SIZE:2 * BLOCK_SIZE], block(0xdd))
+        self.rollback(force=True)
+        self.assertEqual(self.origin.read_bytes(), self.original)
+
+    def test_rollback_keeps_unreadable_sectors(self):
+        self.write_back((BLOCK_SIZE, block(0xaa), [(512, 1024)]))
+        self.rollback()
+        restored = self.origin.read_bytes()[BLOCK_SIZE:2 * BLOCK_SIZE]
+        self.assertEqual(restored[:512] + restored[1024:], block(1)[:512] + block(1)[1024:])
+        self.assertEqual(restored[512:1024], block(0xaa)[512:1024])
+
+
+if __name__ == "__main__":
+    unittest.main()
@@ -0,0 +1,299 @@
+#!/usr/bin/env python3
+# -*- coding: utf-8 -*-
+"""Rolls back a writeback of ``cache_verify.py`` using its undo journal.
+
+With ``--undo-journal``, the writeback saves the original contents of each origin block
+to the journal before overwriting it,
+together with the SHA-256 digests of the original and the written contents.
+Each record is flushed to disk before the block is written. ::
+
+    $ ./cache_verify.py --writeback --undo-journal undo.bin metadata.xml /dev/mapper/cache /dev/mapper/data
+    $ ./undo_journal.py show undo.bin
+    $ ./undo_journal.py rollback undo.bin /dev/mapper/data
+
+The journal must not be stored on the origin device.
+
+The rollback restores the blocks in reverse order,
+so a block written twice, e.g. after ``--resume``, gets back its contents from before the first write.
+A block is only restored if it still contains what the writeback wrote;
+blocks which still contain the original data are left alone.
+If a block has been changed since the writeback, nothing is restored, unless ``--force`` is given.
+With ``--dry-run``, the rollback only checks the blocks.
+Sectors of the origin device which were unreadable during the writeback are not restored.
+
+With ``--lvm``, the origin is a logical volume, located by reading the LVM metadata,
+like with ``cache_verify.py``. ::
+
+    $ ./undo_journal.py rollback --lvm /dev/sdb undo.bin vg/data
+
+The journal starts with the magic ``CVUNDO01``, the size of the origin device in bytes,
+the creation time and the name of the origin device.
+Each record consists of the magic ``UNDO``, the offset and length of the block in bytes,
+the digests of the original and the written contents, the unreadable ranges of the block,
+the original contents and a CRC-32 of the record.
+A damaged record at the end of the journal is left from an interrupted writeback,
+which did not write its block yet, and is ignored.
+"""
+
+import hashlib, lvm_metadata, os, struct, sys, time, zlib
+from argparse import ArgumentParser
+from contextlib import contextmanager, nullcontext
+from dataclasses import dataclass
+from datetime import datetime
+from pathlib import Path
+
+
+JOURNAL_MAGIC = b"CVUNDO01"
+# magic, size of the origin device, creation time, length of the name of the origin device
+HEADER_FORMAT = struct.Struct("<8sQQH")
+RECORD_MAGIC = b"UNDO"
+# magic, offset, length, digest of the original contents, digest of the written contents, number of unreadable ranges
+RECORD_FORMAT = struct.Struct("<4sQI32s32sH")
+RANGE_FORMAT = struct.Struct("<II")
+CRC_FORMAT = struct.Struct("<I")
+
+
+def main():
+    argparser = ArgumentParser()
+    subparsers = argparser.add_subparsers(required=True)
+
+    subparser = subparsers.add_parser("show")
+    subparser.add_argument("journal", type=Path)
+    subparser.set_defaults(func=show)
+
+    subparser = subparsers.add_parser("rollback")
+    subparser.add_argument("journal", type=Path)
+    subparser.add_argument("origin", type=Path)
+    subparser.add_argument("--force", action="store_true",
+            help="Also restore blocks which have been changed since the writeback")
+    subparser.add_argument("--dry-run", action="store_true",
+            help="Check the blocks without restoring them")
+    subparser.add_argument("--lvm", type=Path, metavar="SOURCE",
+            help="Physical volume or LVM backup file; origin is given as logical volume")
+    subparser.add_argument("--pv-device", type=Path, action="append", default=[],
+            help="Device of another physical volume of the volume group, used by --lvm")
+    subparser.set_defaults(func=rollback)
+
+    args = argparser.parse_args()
+    args.func(args)
+
+
+@dataclass(frozen=True, kw_only=True, slots=True)
+class Record:
+    offset: int
+    length: int
+    old_digest: bytes
+    new_digest: bytes
+    bad: list
+    # Position of the original contents in the journal
+    position: int
+
+
+@dataclass(frozen=True, kw_only=True, slots=True)
+class Journal:
+    origin: str
+    origin_size: int
+    created: int
+    records: list
+    # Position after the last complete record, and the size of the journal
+    end: int
+    size: int
+
+
+def show(args):
+    journal = read_journal(args.journal)
+    print(f"origin: {journal.origin} ({journal.origin_size} bytes)")
+    print(f"created: {datetime.fromtimestamp(journal.created).isoformat(' ', 'seconds')}")
+    for record in journal.records:
+        line = f"bytes {record.offset}-{record.offset + record.length - 1}"
+        if record.bad:
+            line += f" unreadable {','.join(f'{record.offset + first}-{record.offset + end - 1}' for first, end in record.bad)}"
+        print(line)
+    print(f"{len(journal.records)} blocks, {sum(record.length for record in journal.records)} bytes", file=sys.stderr)
+    warn_incomplete(args.journal, journal)
+
+
+def rollback(args):
+    journal = read_journal(args.journal)
+    warn_incomplete(args.journal, journal)
+    with make_origin_device(args) as origin, \
+            open(args.journal, "rb") as file:
+        fd = os.open(origin, os.O_RDONLY if args.dry_run else os.O_RDWR)
+        try:
+            size = os.lseek(fd, 0, os.SEEK_END)
+            if size != journal.origin_size:
+                sys.exit(f"The origin device has {size} bytes, but the journal was written for {journal.origin_size} bytes")
+            restore, unchanged, changed = plan_rollback(fd, journal.records, force=args.force)
+            for record in changed:
+                print(f"bytes {record.offset}-{record.offset + record.length - 1}"
+                      " have been changed since the writeback", file=sys.stderr)
+            if changed and not args.force:
+                sys.exit(f"{len(changed)} blocks have been changed since the writeback, use --force to restore them anyway")
+            if not args.dry_run:
+                for record in restore:
+                    file.seek(record.position)
+                    data = file.read(record.length)
+                    for first, end in readable_ranges(record):
+                        os.pwrite(fd, data[first:end], record.offset + first)
+                os.fsync(fd)
+        finally:
+            os.close(fd)
+    verb = "Would restore" if args.dry_run else "Restored"
+    print(f"{verb} {len(restore)} blocks ({sum(record.length for record in restore)} bytes),"
+          f" {unchanged} blocks contain their original data", file=sys.stderr)
+
+
+def plan_rollback(fd, records, *, force=False):
+    """Returns the records to restore in this order, the number of records whose block
+    still contains the original data, and the records whose block has been changed since.
+    The records of a block are checked from the last to the first,
+    following the contents the block gets by restoring them.
+    """
+    restore = []
+    unchanged = 0
+    changed = []
+    current = {}
+    for record in reversed(records):
+        key = (record.offset, record.length)
+        if key not in current:
+            try:
+                current[key] = masked_digest(os.pread(fd, record.length, record.offset), record.bad)
+            except OSError:
+                current[key] = None
+        if current[key] == record.new_digest:
+            restore.append(record)
+            current[key] = record.old_digest
+        elif current[key] == record.old_digest:
+            unchanged += 1
+        else:
+            changed.append(record)
+            if force:
+                restore.append(record)
+            current[key] = record.old_digest
+    return restore, unchanged, changed
+
+
+def readable_ranges(record):
+    """Returns the ranges of the block, relative to the block, which were readable when it was saved."""
+    ranges = []
+    first = 0
+    for bad_first, bad_end in record.bad:
+        if first < bad_first:
+            ranges.append((first, bad_first))
+        first = max(first, bad_end)
+    if first < record.length:
+        ranges.append((first, record.length))
+    return ranges
+
+
+def masked_digest(data, bad):
+    """Returns the SHA-256 digest of the data, with the unreadable ranges set to zeros."""
+    if bad:
+        data = bytearray(data)
+        for first, end in bad:
+            data[first:end] = bytes(end - first)
+    return hashlib.sha256(data).digest()
+
+
+def warn_incomplete(path, journal):
+    if journal.end < journal.size:
+        print(f"Ignoring {journal.size - journal.end} bytes at the end of {path} after the last complete record,"
+              " left from an interrupted writeback", file=sys.stderr)
+
+
+def read_journal(path):
+    try:
+        file = open(path, "rb")
+    except FileNotFoundError:
+        sys.exit(f"Undo journal {path} not found")
+    with file:
+        header = file.read(HEADER_FORMAT.size)
+        if len(header) < HEADER_FORMAT.size or header[:len(JOURNAL_MAGIC)] != JOURNAL_MAGIC:
+            sys.exit(f"{path} is not an undo journal")
+        _, origin_size, created, name_length = HEADER_FORMAT.unpack(header)
+        origin = file.read(name_length).decode(errors="replace")
+        records = []
+        end = file.tell()
+        while (record := read_record(file)) is not None:
+            records.append(record)
+            end = file.tell()
+        return Journal(origin=origin, origin_size=origin_size, created=created,
+                       records=records, end=end, size=os.fstat(file.fileno()).st_size)
+
+
+def read_record(file):
+    """Reads the next record of the journal, or returns ``None`` if it is missing or incomplete."""
+    header = file.read(RECORD_FORMAT.size)
+    if len(header) < RECORD_FORMAT.size:
+        return None
+    magic, offset, length, old_digest, new_digest, bad_count = RECORD_FORMAT.unpack(header)
+    if magic != RECORD_MAGIC:
+        return None
+    ranges = file.read(bad_count * RANGE_FORMAT.size)
+    position = file.tell()
+    data = file.read(length)
+    crc = file.read(CRC_FORMAT.size)
+    if len(ranges) < bad_count * RANGE_FORMAT.size or len(data) < length or len(crc) < CRC_FORMAT.size:
+        return None
+    if zlib.crc32(header + ranges + data) != CRC_FORMAT.unpack(crc)[0]:
+        return None
+    bad = [RANGE_FORMAT.unpack_from(ranges, index * RANGE_FORMAT.size) for index in range(bad_count)]
+    return Record(offset=offset, length=length, old_digest=old_digest, new_digest=new_digest,
+                  bad=bad, position=position)
+
+
+@contextmanager
+def open_journal(path, origin, origin_size):
+    """Yields a writer of the undo journal at the given path.
+    An existing journal of the same origin device is continued,
+    after removing an incomplete record at its end.
+    """
+    if path.exists():
+        journal = read_journal(path)
+        if journal.origin_size != origin_size:
+            sys.exit(f"Undo journal {path} was written for an origin device of {journal.origin_size} bytes")
+        warn_incomplete(path, journal)
+        file = open(path, "r+b")
+        file.truncate(journal.end)
+        file.seek(journal.end)
+    else:
+        file = open(path, "xb")
+        name = origin.encode()
+        file.write(HEADER_FORMAT.pack(JOURNAL_MAGIC, origin_size, int(time.time()), len(name)) + name)
+    with file:
+        yield JournalWriter(file)
+        file.flush()
+        os.fsync(file.fileno())
+
+
+class JournalWriter:
+    """Appends the original contents of origin blocks to the undo journal.
+    Each record is on disk once ``save`` returns.
+    """
+
+    def __init__(self, file):
+        self.__file = file
+
+    def save(self, offset, old, bad, new):
+        """Saves the original contents of the origin bytes at the given offset,
+        which are about to be overwritten with the new contents.
+        ``bad`` are the unreadable ranges of the original contents, relative to the offset.
+        """
+        record = RECORD_FORMAT.pack(RECORD_MAGIC, offset, len(old),
+                                    masked_digest(old, bad), masked_digest(new, bad), len(bad))
+        record += b"".join(RANGE_FORMAT.pack(first, end) for first, end in bad) + old
+        self.__file.write(record + CRC_FORMAT.pack(zlib.crc32(record)))
+        self.__file.flush()
+        os.fsync(self.__file.fileno())
+
+
+def make_origin_device(args):
+    if args.lvm is None:
+        return nullcontext(args.origin)
+    vg = lvm_metadata.read_volume_group(args.lvm)
+    name = lvm_metadata.cache_volume(vg, str(args.origin), "origin")
+    return lvm_metadata.make_lv_device(vg, name, [args.lvm, *args.pv_device], write=not args.dry_run)
+
+
+if __name__ == "__main__":
+    main()